@@ -0,0 +1,367 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+
+use crate::utils::{cell_total, for_each_cell, get_positions, positions_to_bit_vec, CellWriter, FileType};
+
+// fold enrichment over the kept cells a marker needs at least
+const MIN_MARKER_ENRICHMENT: f64 = 2.0;
+
+pub struct Options {
+    // barcodes with a total at or below it make the ambient profile
+    pub lower: f64,
+    pub num_markers: usize,
+    // contamination of every cell instead of the marker estimates
+    pub fixed: Option<f64>,
+    pub max_contamination: f64,
+}
+
+pub struct Profile {
+    // summed counts of the barcodes at or below lower
+    pub counts: Vec<f64>,
+    pub proportions: Vec<f64>,
+    // features most enriched in the ambient profile over the kept cells,
+    // they estimate the contamination of every cell
+    pub markers: Vec<usize>,
+}
+
+// ambient profile from the barcodes with a total at or below lower, the
+// markers are ranked by the ratio of the pseudo-counted ambient and pooled
+// cell proportions
+pub fn estimate_profile(
+    input: &str,
+    input_type: FileType,
+    num_features: usize,
+    totals: &[f64],
+    kept: &[bool],
+    options: &Options,
+) -> Result<Profile, io::Error> {
+    let (num_cells, lower) = (kept.len(), options.lower);
+    let mut counts: Vec<f64> = vec![0.0; num_features];
+    let mut cell_counts: Vec<f64> = vec![0.0; num_features];
+    let mut num_empty = 0;
+
+    for_each_cell(input, input_type, num_cells, num_features, |cell_id, bit_vec, exp| {
+        let pooled = match (totals[cell_id] <= lower, kept[cell_id]) {
+            (true, _) => {
+                num_empty += 1;
+                &mut counts
+            }
+            (false, true) => &mut cell_counts,
+            (false, false) => return Ok(()),
+        };
+
+        for (position, value) in get_positions(&bit_vec).into_iter().zip(exp) {
+            pooled[position] += value as f64;
+        }
+        Ok(())
+    })?;
+
+    let total: f64 = counts.iter().sum();
+    if total == 0.0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no counts in the barcodes at or below {}, can't build the ambient profile", lower),
+        ));
+    }
+    info!("Found {} counts in {} barcodes at or below {}", total, num_empty, lower);
+
+    let cell_total: f64 = cell_counts.iter().sum();
+    let log_ratio = |x: usize| {
+        ((counts[x] + 1.0) / (total + num_features as f64)).ln()
+            - ((cell_counts[x] + 1.0) / (cell_total + num_features as f64)).ln()
+    };
+
+    let mut markers: Vec<usize> = (0..num_features)
+        .filter(|x| counts[*x] > 0.0 && log_ratio(*x) >= MIN_MARKER_ENRICHMENT.ln())
+        .collect();
+    markers.sort_by(|a, b| log_ratio(*b).total_cmp(&log_ratio(*a)).then(a.cmp(b)));
+    markers.truncate(options.num_markers);
+    markers.sort();
+
+    match markers.len() {
+        0 => warn!("no feature is {}-fold enriched in the ambient profile, can't estimate the contamination",
+                   MIN_MARKER_ENRICHMENT),
+        n => info!("Estimating the contamination from {} ambient markers", n),
+    };
+
+    Ok(Profile {
+        proportions: counts.iter().map(|x| x / total).collect(),
+        counts,
+        markers,
+    })
+}
+
+pub struct Correction {
+    pub cell_id: usize,
+    pub total: f64,
+    pub contamination: f64,
+    // markers at background level the estimate used, 0 for the pooled
+    // estimate and None for a fixed contamination
+    pub markers: Option<usize>,
+    // the estimate was above the maximum contamination
+    pub capped: bool,
+    pub removed: f64,
+}
+
+// observed and expected marker counts of a cell at full contamination, over
+// the markers at background level: the ones the ambient counts can explain
+#[derive(Clone, Default)]
+struct MarkerCounts {
+    observed: f64,
+    expected: f64,
+    num_markers: usize,
+}
+
+fn marker_counts(positions: &[usize], exp: &[f32], total: f64, profile: &Profile) -> MarkerCounts {
+    let mut counts = MarkerCounts::default();
+    for marker in profile.markers.iter() {
+        let observed = match positions.binary_search(marker) {
+            Ok(index) => exp[index] as f64,
+            Err(_) => 0.0,
+        };
+
+        // more than the whole cell being ambient means the cell expresses it
+        let expected = total * profile.proportions[*marker];
+        if observed <= expected {
+            counts.observed += observed;
+            counts.expected += expected;
+            counts.num_markers += 1;
+        }
+    }
+
+    counts
+}
+
+// takes amount counts off the cell following the ambient proportions,
+// what can't come off an exhausted feature goes to the remaining ones.
+// Returns the counts actually removed.
+fn subtract(values: &mut [f64], positions: &[usize], proportions: &[f64], amount: f64) -> f64 {
+    let mut remaining = amount;
+    while remaining > 0.0 {
+        let weight: f64 = positions.iter()
+            .zip(values.iter())
+            .filter(|(_, value)| **value > 0.0)
+            .map(|(position, _)| proportions[*position])
+            .sum();
+        if weight == 0.0 {
+            break;
+        }
+
+        let mut exhausted = false;
+        let mut removed = 0.0;
+        for (position, value) in positions.iter().zip(values.iter_mut()) {
+            if *value <= 0.0 || proportions[*position] == 0.0 {
+                continue;
+            }
+
+            let taken = (remaining * proportions[*position] / weight).min(*value);
+            exhausted |= taken == *value;
+            *value -= taken;
+            removed += taken;
+        }
+
+        remaining = match exhausted {
+            true => remaining - removed,
+            false => 0.0,
+        };
+    }
+
+    amount - remaining
+}
+
+// writes the kept cells with the ambient counts taken off, either a fixed
+// contamination fraction or the one estimated from the markers at
+// background level in the cell. The cells without any fall back to the
+// estimate pooled over all the kept cells, every estimate is capped at
+// max_contamination.
+pub fn correct(
+    input: &str,
+    input_type: FileType,
+    num_features: usize,
+    kept: &[bool],
+    profile: &Profile,
+    options: &Options,
+    writer: &mut CellWriter,
+) -> Result<Vec<Correction>, io::Error> {
+    let (num_cells, fixed, max_contamination) = (kept.len(), options.fixed, options.max_contamination);
+    let mut estimates: Vec<MarkerCounts> = vec![MarkerCounts::default(); num_cells];
+    let mut pooled = 0.0;
+    if fixed.is_none() {
+        let mut pooled_counts = MarkerCounts::default();
+        for_each_cell(input, input_type.clone(), num_cells, num_features, |cell_id, bit_vec, exp| {
+            if !kept[cell_id] {
+                return Ok(());
+            }
+
+            let counts = marker_counts(&get_positions(&bit_vec), &exp, cell_total(&exp), profile);
+            pooled_counts.observed += counts.observed;
+            pooled_counts.expected += counts.expected;
+            estimates[cell_id] = counts;
+            Ok(())
+        })?;
+
+        if pooled_counts.expected > 0.0 {
+            pooled = pooled_counts.observed / pooled_counts.expected;
+        }
+        info!("Pooled contamination over the markers at background level: {}", pooled);
+    }
+
+    let mut corrections: Vec<Correction> = Vec::new();
+
+    for_each_cell(input, input_type, num_cells, num_features, |cell_id, bit_vec, exp| {
+        if !kept[cell_id] {
+            return Ok(());
+        }
+
+        let positions = get_positions(&bit_vec);
+        let mut values: Vec<f64> = exp.iter().map(|x| *x as f64).collect();
+        let total: f64 = values.iter().sum();
+
+        let (estimate, markers) = match fixed {
+            Some(fraction) => (fraction, None),
+            None => {
+                let counts = &estimates[cell_id];
+                match counts.expected > 0.0 {
+                    true => (counts.observed / counts.expected, Some(counts.num_markers)),
+                    false => (pooled, Some(0)),
+                }
+            }
+        };
+
+        let fraction = estimate.min(max_contamination);
+        let removed = subtract(&mut values, &positions, &profile.proportions, fraction * total);
+        corrections.push(Correction {
+            cell_id,
+            total,
+            contamination: fraction,
+            markers,
+            capped: estimate > max_contamination,
+            removed,
+        });
+
+        let (positions, values): (Vec<usize>, Vec<f32>) = positions.into_iter()
+            .zip(values)
+            .filter(|x| x.1 > 0.0)
+            .map(|(position, value)| (position, value as f32))
+            .unzip();
+        writer.write_cell(positions_to_bit_vec(&positions, num_features), values)
+    })?;
+
+    let num_pooled = corrections.iter().filter(|x| x.markers == Some(0)).count();
+    if num_pooled > 0 {
+        info!("{} cells have no marker at background level, using the pooled contamination", num_pooled);
+    }
+
+    let num_capped = corrections.iter().filter(|x| x.capped).count();
+    if num_capped > 0 {
+        warn!("{} cells have a contamination above {}, capped, see the report",
+              num_capped, max_contamination);
+    }
+
+    Ok(corrections)
+}
+
+pub fn write_report(
+    path: &str,
+    corrections: &[Correction],
+    barcodes: Option<&Vec<String>>,
+) -> Result<(), io::Error> {
+    info!("Writing contamination estimates into file path: {}", path);
+    let mut file = BufWriter::new(File::create(path)?);
+
+    writeln!(file, "cell\ttotal\tcontamination\tmarkers\tcapped\tremoved")?;
+    for correction in corrections {
+        let name = match barcodes {
+            Some(barcodes) => barcodes[correction.cell_id].clone(),
+            None => (correction.cell_id + 1).to_string(),
+        };
+        writeln!(file, "{}\t{}\t{}\t{}\t{}\t{}",
+                 name, correction.total, correction.contamination,
+                 correction.markers.map_or("NA".to_string(), |x| x.to_string()),
+                 correction.capped as u8, correction.removed)?;
+    }
+
+    file.flush()
+}
+
+pub fn write_profile(
+    path: &str,
+    profile: &Profile,
+    names: Option<&Vec<String>>,
+) -> Result<(), io::Error> {
+    info!("Writing ambient profile into file path: {}", path);
+    let mut file = BufWriter::new(File::create(path)?);
+
+    writeln!(file, "feature\tcount\tproportion\tmarker")?;
+    for (feature_id, count) in profile.counts.iter().enumerate() {
+        let name = match names {
+            Some(names) => names[feature_id].clone(),
+            None => (feature_id + 1).to_string(),
+        };
+        writeln!(file, "{}\t{}\t{}\t{}",
+                 name, count, profile.proportions[feature_id],
+                 profile.markers.binary_search(&feature_id).is_ok() as u8)?;
+    }
+
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_values(actual: &[f64], expected: &[f64]) {
+        for (actual, expected) in actual.iter().zip(expected.iter()) {
+            assert!((actual - expected).abs() < 1e-12, "expected {:?}, found {:?}", expected, actual);
+        }
+    }
+
+    #[test]
+    fn subtract_follows_the_proportions() {
+        let proportions = [0.5, 0.0, 0.25, 0.25];
+        let mut values = [10.0, 10.0, 10.0];
+        let removed = subtract(&mut values, &[0, 2, 3], &proportions, 8.0);
+
+        assert_eq!(removed, 8.0);
+        assert_values(&values, &[6.0, 8.0, 8.0]);
+    }
+
+    #[test]
+    fn subtract_redistributes_once_a_feature_is_exhausted() {
+        // the first round takes 6, 3 and 3 but only 1 is left in feature 2,
+        // the 2 missing come off features 0 and 3 by their proportions
+        let proportions = [0.5, 0.0, 0.25, 0.25];
+        let mut values = [10.0, 1.0, 10.0, 10.0];
+        let removed = subtract(&mut values, &[0, 2, 3, 1], &proportions, 12.0);
+
+        assert!((removed - 12.0).abs() < 1e-12);
+        assert_values(&values, &[10.0 - 6.0 - 4.0 / 3.0, 0.0, 10.0 - 3.0 - 2.0 / 3.0, 10.0]);
+    }
+
+    #[test]
+    fn subtract_stops_when_the_ambient_features_run_out() {
+        let proportions = [0.5, 0.5, 0.0];
+        let mut values = [1.0, 2.0, 5.0];
+        let removed = subtract(&mut values, &[0, 1, 2], &proportions, 10.0);
+
+        assert_eq!(removed, 3.0);
+        assert_values(&values, &[0.0, 0.0, 5.0]);
+    }
+
+    #[test]
+    fn marker_counts_skip_expressed_markers() {
+        let profile = Profile {
+            counts: vec![10.0, 20.0, 30.0, 40.0],
+            proportions: vec![0.1, 0.2, 0.3, 0.4],
+            markers: vec![0, 1, 3],
+        };
+
+        // at a total of 100 feature 1 can take up to 20 ambient counts,
+        // 30 means the cell expresses it
+        let counts = marker_counts(&[0, 1, 2], &[5.0, 30.0, 65.0], 100.0, &profile);
+        assert_eq!(counts.num_markers, 2);
+        assert_eq!(counts.observed, 5.0);
+        assert_eq!(counts.expected, 50.0);
+    }
+}
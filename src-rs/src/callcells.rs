@@ -0,0 +1,452 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+
+use rand::Rng;
+
+use crate::de::adjust_bh;
+use crate::utils::{cell_total, for_each_cell, get_positions, FileType};
+
+pub struct RankCurve {
+    pub knee: f64,
+    pub inflection: f64,
+    // cell ids by decreasing total, ties keep the input order
+    pub order: Vec<usize>,
+}
+
+// knee and inflection of the log-log barcode rank curve over the totals
+// above lower. Every distinct total is one point at the middle of its
+// ranks, the inflection is the steepest point of the smoothed curve and
+// the knee the point of highest curvature before it.
+pub fn rank_curve(totals: &[f64], lower: f64) -> Result<RankCurve, io::Error> {
+    if let Some(cell_id) = totals.iter().position(|x| !x.is_finite()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("cell {} has a total of {}, can't rank the barcodes", cell_id + 1, totals[cell_id]),
+        ));
+    }
+
+    let mut order: Vec<usize> = (0..totals.len()).collect();
+    order.sort_by(|a, b| totals[*b].total_cmp(&totals[*a]).then(a.cmp(b)));
+
+    let (mut x, mut y, mut points): (Vec<f64>, Vec<f64>, Vec<f64>) = (Vec::new(), Vec::new(), Vec::new());
+    let mut start = 0;
+    while start < order.len() && totals[order[start]] > lower {
+        let mut end = start;
+        while end < order.len() && totals[order[end]] == totals[order[start]] {
+            end += 1;
+        }
+
+        x.push(((start + 1 + end) as f64 / 2.0).log10());
+        y.push(totals[order[start]].log10());
+        points.push(totals[order[start]]);
+        start = end;
+    }
+
+    if x.len() < 3 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("found {} distinct totals above {}, too few for a rank curve", x.len(), lower),
+        ));
+    }
+
+    // running mean over a window of about 2% of the points
+    let half_window = x.len() / 100;
+    let smooth: Vec<f64> = (0..y.len())
+        .map(|i| {
+            let (from, to) = (i.saturating_sub(half_window), (i + half_window + 1).min(y.len()));
+            y[from..to].iter().sum::<f64>() / (to - from) as f64
+        })
+        .collect();
+
+    let derivative = |values: &[f64]| -> Vec<f64> {
+        (0..values.len())
+            .map(|i| {
+                let (from, to) = (i.saturating_sub(1), (i + 1).min(values.len() - 1));
+                (values[to] - values[from]) / (x[to] - x[from])
+            })
+            .collect()
+    };
+    let d1 = derivative(&smooth);
+    let d2 = derivative(&d1);
+
+    let mut inflection = 0;
+    for i in 0..x.len() {
+        if d1[i] < d1[inflection] {
+            inflection = i;
+        }
+    }
+
+    // strongest downward bend before the inflection
+    let curvature = |i: usize| d2[i] / (1.0 + d1[i] * d1[i]).powf(1.5);
+    let mut knee = 0;
+    for i in 0..inflection {
+        if curvature(i) < curvature(knee) {
+            knee = i;
+        }
+    }
+
+    Ok(RankCurve {
+        knee: points[knee],
+        inflection: points[inflection],
+        order,
+    })
+}
+
+// walker's alias table for drawing features from the ambient profile
+struct AliasTable {
+    probabilities: Vec<f64>,
+    aliases: Vec<usize>,
+}
+
+impl AliasTable {
+    fn new(weights: &[f64]) -> AliasTable {
+        let n = weights.len();
+        let total: f64 = weights.iter().sum();
+        let mut scaled: Vec<f64> = weights.iter().map(|x| x * n as f64 / total).collect();
+
+        let (mut small, mut large): (Vec<usize>, Vec<usize>) = (0..n).partition(|x| scaled[*x] < 1.0);
+        let mut probabilities: Vec<f64> = vec![1.0; n];
+        let mut aliases: Vec<usize> = (0..n).collect();
+        while let (Some(s), Some(l)) = (small.pop(), large.pop()) {
+            probabilities[s] = scaled[s];
+            aliases[s] = l;
+            scaled[l] -= 1.0 - scaled[s];
+            match scaled[l] < 1.0 {
+                true => small.push(l),
+                false => large.push(l),
+            };
+        }
+
+        AliasTable { probabilities, aliases }
+    }
+
+    fn draw<R: Rng>(&self, rng: &mut R) -> usize {
+        let index = rng.gen_range(0, self.probabilities.len());
+        match rng.gen::<f64>() < self.probabilities[index] {
+            true => index,
+            false => self.aliases[index],
+        }
+    }
+}
+
+// ambient proportions with a simple good-turing estimate: the unseen
+// features share the mass of the singletons
+fn ambient_profile(counts: &[f64]) -> Vec<f64> {
+    let total: f64 = counts.iter().sum();
+    let num_unseen = counts.iter().filter(|x| **x == 0.0).count();
+    let num_singletons = counts.iter().filter(|x| **x == 1.0).count();
+
+    let unseen_mass = match num_unseen {
+        0 => 0.0,
+        _ => (num_singletons.max(1) as f64 / total).min(0.5),
+    };
+
+    counts.iter()
+        .map(|count| match *count == 0.0 {
+            true => unseen_mass / num_unseen as f64,
+            false => (1.0 - unseen_mass) * count / total,
+        })
+        .collect()
+}
+
+pub struct EmptyDrops {
+    pub lower: f64,
+    pub retain: f64,
+    pub iterations: usize,
+}
+
+// cell id, rounded total and rounded non-zero counts of a tested barcode
+type TestedCell = (usize, usize, Vec<(usize, u64)>);
+
+pub struct TestResult {
+    pub log_likelihoods: HashMap<usize, f64>,
+    pub p_values: HashMap<usize, f64>,
+    pub fdr: HashMap<usize, f64>,
+}
+
+// multinomial test of every cell with a total between lower and retain
+// against the ambient profile of the cells at or below lower. The p-value
+// is the fraction of ambient draws of the same total that are at most as
+// likely, every draw covers all totals by adding one molecule at a time.
+// Non-integer counts are rounded, the cells rounding to no counts are not
+// tested.
+pub fn empty_drops<R: Rng>(
+    input: &str,
+    input_type: FileType,
+    num_cells: usize,
+    num_features: usize,
+    totals: &[f64],
+    params: &EmptyDrops,
+    rng: &mut R,
+) -> Result<TestResult, io::Error> {
+    let mut ambient_counts: Vec<f64> = vec![0.0; num_features];
+    let mut tested: Vec<TestedCell> = Vec::new();
+
+    for_each_cell(input, input_type, num_cells, num_features, |cell_id, bit_vec, exp| {
+        let total = totals[cell_id];
+        let is_ambient = total <= params.lower;
+        if !is_ambient && total >= params.retain {
+            return Ok(());
+        }
+
+        let counts: Vec<(usize, u64)> = get_positions(&bit_vec).into_iter()
+            .zip(exp)
+            .map(|(position, value)| (position, value.round().max(0.0) as u64))
+            .filter(|x| x.1 > 0)
+            .collect();
+
+        match is_ambient {
+            true => counts.iter().for_each(|(position, count)| ambient_counts[*position] += *count as f64),
+            false => {
+                // nothing left to test once every count rounds to 0
+                let total = counts.iter().map(|x| x.1).sum::<u64>() as usize;
+                if total > 0 {
+                    tested.push((cell_id, total, counts));
+                }
+            }
+        };
+        Ok(())
+    })?;
+
+    if ambient_counts.iter().sum::<f64>() == 0.0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no counts in the cells at or below {}, can't build the ambient profile", params.lower),
+        ));
+    }
+
+    let profile = ambient_profile(&ambient_counts);
+    let log_profile: Vec<f64> = profile.iter().map(|x| x.ln()).collect();
+    let max_total = tested.iter().map(|x| x.1).max().unwrap_or(0);
+    info!("Testing {} cells against the ambient profile, {} draws up to {} molecules",
+          tested.len(), params.iterations, max_total);
+
+    let mut log_factorials: Vec<f64> = vec![0.0; max_total + 2];
+    for k in 1..log_factorials.len() {
+        log_factorials[k] = log_factorials[k - 1] + (k as f64).ln();
+    }
+
+    // the tested cells grouped by total, sorted by likelihood
+    let mut by_total: Vec<Vec<(f64, usize)>> = vec![Vec::new(); max_total + 1];
+    let mut log_likelihoods: HashMap<usize, f64> = HashMap::new();
+    for (cell_id, total, counts) in tested.iter() {
+        let log_likelihood = log_factorials[*total] + counts.iter()
+            .map(|(position, count)| *count as f64 * log_profile[*position] - log_factorials[*count as usize])
+            .sum::<f64>();
+
+        log_likelihoods.insert(*cell_id, log_likelihood);
+        by_total[*total].push((log_likelihood, *cell_id));
+    }
+    for cells in by_total.iter_mut() {
+        cells.sort_by(|a, b| a.0.total_cmp(&b.0));
+    }
+
+    // per total, number of draws at most as likely as each cell, kept as a
+    // difference array over the sorted cells
+    let mut hits: Vec<Vec<usize>> = by_total.iter()
+        .map(|cells| vec![0; cells.len() + 1])
+        .collect();
+
+    let table = AliasTable::new(&profile);
+    let mut drawn: Vec<u64> = vec![0; num_features];
+    let mut touched: Vec<usize> = Vec::new();
+    for _ in 0..params.iterations {
+        let mut log_likelihood = 0.0;
+        for total in 1..max_total + 1 {
+            let feature_id = table.draw(rng);
+            if drawn[feature_id] == 0 {
+                touched.push(feature_id);
+            }
+            drawn[feature_id] += 1;
+            log_likelihood += (total as f64).ln() - (drawn[feature_id] as f64).ln()
+                + log_profile[feature_id];
+
+            let cells = &by_total[total];
+            if !cells.is_empty() {
+                let first = cells.partition_point(|x| x.0 < log_likelihood);
+                hits[total][first] += 1;
+            }
+        }
+
+        for feature_id in touched.drain(..) {
+            drawn[feature_id] = 0;
+        }
+    }
+
+    let mut p_values: HashMap<usize, f64> = HashMap::new();
+    for (total, cells) in by_total.iter().enumerate() {
+        let mut count = 0;
+        for (index, (_, cell_id)) in cells.iter().enumerate() {
+            count += hits[total][index];
+            p_values.insert(*cell_id, (count + 1) as f64 / (params.iterations + 1) as f64);
+        }
+    }
+
+    let cell_ids: Vec<usize> = tested.iter().map(|x| x.0).collect();
+    let raw: Vec<f64> = cell_ids.iter().map(|x| p_values[x]).collect();
+    let fdr: HashMap<usize, f64> = cell_ids.into_iter().zip(adjust_bh(&raw)).collect();
+
+    Ok(TestResult {
+        log_likelihoods,
+        p_values,
+        fdr,
+    })
+}
+
+pub fn cell_totals(
+    input: &str,
+    input_type: FileType,
+    num_cells: usize,
+    num_features: usize,
+) -> Result<Vec<f64>, io::Error> {
+    let mut totals: Vec<f64> = Vec::with_capacity(num_cells);
+    for_each_cell(input, input_type, num_cells, num_features, |_, _, exp| {
+        totals.push(cell_total(&exp));
+        Ok(())
+    })?;
+
+    Ok(totals)
+}
+
+pub fn write_report(
+    path: &str,
+    totals: &[f64],
+    curve: &RankCurve,
+    test: Option<&TestResult>,
+    called: &[bool],
+    barcodes: Option<&Vec<String>>,
+) -> Result<(), io::Error> {
+    info!("Writing cell calls into file path: {}", path);
+    let mut file = BufWriter::new(File::create(path)?);
+
+    let mut ranks: Vec<usize> = vec![0; totals.len()];
+    for (rank, cell_id) in curve.order.iter().enumerate() {
+        ranks[*cell_id] = rank + 1;
+    }
+
+    let field = |values: Option<&HashMap<usize, f64>>, cell_id: usize| {
+        values.and_then(|x| x.get(&cell_id)).map_or("NA".to_string(), |x| x.to_string())
+    };
+
+    writeln!(file, "cell\ttotal\trank\tlog_likelihood\tp_value\tfdr\tcalled")?;
+    for (cell_id, total) in totals.iter().enumerate() {
+        let name = match barcodes {
+            Some(barcodes) => barcodes[cell_id].clone(),
+            None => (cell_id + 1).to_string(),
+        };
+
+        writeln!(file, "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                 name, total, ranks[cell_id],
+                 field(test.map(|x| &x.log_likelihoods), cell_id),
+                 field(test.map(|x| &x.p_values), cell_id),
+                 field(test.map(|x| &x.fdr), cell_id),
+                 called[cell_id] as u8)?;
+    }
+
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{get_rng, write_test_cells};
+
+    #[test]
+    fn rank_curve_finds_the_knee_of_a_cliff() {
+        // 200 cells with totals 5000 down to 4005, then 2000 empty droplets
+        // with totals 100 down to 51
+        let mut totals: Vec<f64> = (0..2000).map(|i| (100 - i / 40) as f64).collect();
+        totals.extend((0..200).map(|i| (5000 - 5 * i) as f64));
+
+        let curve = rank_curve(&totals, 10.0).unwrap();
+        assert!(curve.knee >= 4005.0 && curve.knee <= 4050.0, "knee at {}", curve.knee);
+        assert!(curve.inflection >= 4005.0 && curve.inflection <= curve.knee,
+                "inflection at {}", curve.inflection);
+
+        // the cells come first, by decreasing total
+        assert_eq!(curve.order[0], 2000);
+        assert_eq!(curve.order[199], 2199);
+        assert_eq!(curve.order[200], 0);
+    }
+
+    #[test]
+    fn rank_curve_rejects_bad_totals() {
+        assert!(rank_curve(&[10.0, f64::NAN, 30.0, 40.0], 0.0).is_err());
+        assert!(rank_curve(&[10.0, 20.0, 20.0, 5.0], 6.0).is_err());
+    }
+
+    #[test]
+    fn alias_table_draws_the_weights() {
+        let weights = [0.1, 0.0, 0.6, 0.3];
+        let table = AliasTable::new(&weights);
+        let mut rng = get_rng(Some(11));
+
+        let num_draws = 200000;
+        let mut counts = [0usize; 4];
+        for _ in 0..num_draws {
+            counts[table.draw(&mut rng)] += 1;
+        }
+
+        assert_eq!(counts[1], 0);
+        for (count, weight) in counts.iter().zip(weights.iter()) {
+            let frequency = *count as f64 / num_draws as f64;
+            assert!((frequency - weight).abs() < 0.005, "drew {} for {}", frequency, weight);
+        }
+    }
+
+    #[test]
+    fn ambient_profile_gives_the_singletons_mass_to_unseen_features() {
+        // 2 singletons out of 10 counts spread over the 2 unseen features
+        let profile = ambient_profile(&[0.0, 1.0, 1.0, 8.0, 0.0]);
+        let expected = [0.1, 0.08, 0.08, 0.64, 0.1];
+        for (actual, expected) in profile.iter().zip(expected.iter()) {
+            assert!((actual - expected).abs() < 1e-12, "expected {}, found {}", expected, actual);
+        }
+
+        assert_eq!(ambient_profile(&[2.0, 6.0]), vec![0.25, 0.75]);
+    }
+
+    #[test]
+    fn empty_drops_ranks_the_cells_against_the_ambient() {
+        // 10 ambient barcodes spread over the first 4 features
+        let mut cells: Vec<(Vec<usize>, Vec<f32>)> = (0..10)
+            .map(|_| (vec![0, 1, 2, 3], vec![1.0; 4]))
+            .collect();
+        cells.extend(vec![
+            // the most likely ambient draw
+            (vec![0, 1, 2, 3], vec![5.0; 4]),
+            // skewed but still on the ambient features
+            (vec![0, 1, 2], vec![8.0, 8.0, 4.0]),
+            // nothing like the ambient
+            (vec![7], vec![20.0]),
+            // above lower, but every count rounds to 0
+            ((0..10).collect(), vec![0.45; 10]),
+            // above retain, called without testing
+            (vec![0], vec![1000.0]),
+        ]);
+        let path = write_test_cells("callcells-emptydrops", FileType::EDS, 10, &cells);
+
+        let totals = cell_totals(&path, FileType::EDS, 15, 10).unwrap();
+        let params = EmptyDrops { lower: 4.0, retain: 1000.0, iterations: 1000 };
+        let result = empty_drops(&path, FileType::EDS, 15, 10, &totals, &params, &mut get_rng(Some(5)));
+        std::fs::remove_file(&path).unwrap();
+        let result = result.unwrap();
+
+        let mut tested: Vec<usize> = result.p_values.keys().cloned().collect();
+        tested.sort();
+        assert_eq!(tested, vec![10, 11, 12]);
+
+        let (ambient, skewed, foreign) = (result.p_values[&10], result.p_values[&11], result.p_values[&12]);
+        assert_eq!(foreign, 1.0 / 1001.0);
+        assert!(foreign <= skewed && skewed < ambient, "p-values {} {} {}", foreign, skewed, ambient);
+        assert!(ambient > 0.5, "p-value {}", ambient);
+        assert!(result.log_likelihoods[&12] < result.log_likelihoods[&11]);
+        assert!(result.log_likelihoods[&11] < result.log_likelihoods[&10]);
+
+        // BH keeps the largest p-value and scales the smallest by 3
+        assert_eq!(result.fdr[&10], ambient);
+        assert_eq!(result.fdr[&12], (3.0 * foreign).min(result.fdr[&11]));
+    }
+}
@@ -1,57 +1,143 @@
 use flate2::write::GzEncoder;
+use flate2::read::GzDecoder;
 use flate2::Compression;
 use std::fs::File;
 use std::io;
-use std::io::Write;
+use std::io::{Write, BufReader, BufRead};
 
-pub fn writer(
-    path_str: String,
-    expressions: Vec<Vec<f32>>,
-    bit_vecs: Vec<Vec<u8>>,
-    _num_cells: usize,
-    num_features: usize,
-) -> Result<bool, io::Error> {
-    let file_handle = File::create(path_str)?;
-    let mut file = GzEncoder::new(file_handle, Compression::default());
+use crate::utils::{get_positions, positions_to_bit_vec, Cell};
 
-    let mut header = "\"\"".to_string();
-    for gid in 1..num_features + 1 {
-        header.push_str(&format!(",gene{}", gid));
+// streams the rows of a csv file as cells, num_cells of them
+pub struct Reader {
+    lines: io::Lines<BufReader<GzDecoder<File>>>,
+    num_cells: usize,
+    num_genes: usize,
+    cells_read: usize,
+}
+
+impl Reader {
+    pub fn new(input: &str, num_cells: usize, num_genes: usize) -> Result<Reader, io::Error> {
+        let file_handle = File::open(input)?;
+        let mut lines = BufReader::new( GzDecoder::new(file_handle) ).lines();
+
+        // first line is the header with the feature names
+        if let Some(header) = lines.next() {
+            header?;
+        }
+
+        Ok(Reader {
+            lines,
+            num_cells,
+            num_genes,
+            cells_read: 0,
+        })
     }
-    header.push_str(&format!("\n"));
-    file.write_all(header.as_bytes())?;
 
-    let mut mtx_data: String;
-    assert!(
-        bit_vecs.len() == expressions.len(),
-        "length of bit vec and expression is not same"
-    );
-    for (cell_id, exp) in expressions.into_iter().enumerate() {
-        let bit_vec = &bit_vecs[cell_id];
-        let mut fids: Vec<usize> = Vec::new();
-
-        for (feature_id, flag) in bit_vec.into_iter().enumerate() {
-            if *flag != 0 {
-                for (offset, j) in format!("{:8b}", flag).chars().enumerate() {
-                    match j {
-                        '1' => fids.push((8 * feature_id) + offset),
-                        _ => (),
-                    };
+    pub fn next_cell(&mut self) -> Result<Option<Cell>, io::Error> {
+        let record = loop {
+            match self.lines.next() {
+                Some(line) => {
+                    let record = line?;
+                    if !record.is_empty() {
+                        break record;
+                    }
                 }
+                None => {
+                    assert!(
+                        self.cells_read == self.num_cells,
+                        "found {} rows for {} cells", self.cells_read, self.num_cells
+                    );
+                    return Ok(None);
+                }
+            };
+        };
+
+        assert!(self.cells_read < self.num_cells,
+                "found more than {} rows", self.num_cells);
+
+        // first column is the cell name
+        let vals: Vec<&str> = record.split(",").skip(1).collect();
+        assert!(vals.len() == self.num_genes,
+                "found {} columns for {} features", vals.len(), self.num_genes);
+
+        let mut positions: Vec<usize> = Vec::new();
+        let mut values: Vec<f32> = Vec::new();
+        for (gid, val) in vals.into_iter().enumerate() {
+            let value = val.trim().parse::<f32>()
+                .expect("can't convert value");
+
+            if value != 0.0 {
+                positions.push(gid);
+                values.push(value);
             }
         }
 
+        self.cells_read += 1;
+        Ok(Some((positions_to_bit_vec(&positions, self.num_genes), values)))
+    }
+}
+
+pub fn reader(
+    input: &str,
+    num_cells: usize,
+    num_genes: usize,
+    expr: &mut Vec<Vec<f32>>,
+    bit_vecs: &mut Vec<Vec<u8>>,
+) -> Result<bool, io::Error> {
+    info!("Using {} as input CSV file\n", input);
+    info!(
+        "Using {} Rows (cells) and {} Columns (features)",
+        num_cells, num_genes
+    );
+
+    let mut file = Reader::new(input, num_cells, num_genes)?;
+    while let Some((bit_vec, exp)) = file.next_cell()? {
+        bit_vecs.push(bit_vec);
+        expr.push(exp);
+    }
+
+    Ok(true)
+}
+
+pub struct Writer {
+    file: GzEncoder<File>,
+    num_features: usize,
+    cell_id: usize,
+}
+
+impl Writer {
+    pub fn new(path_str: &str, num_features: usize) -> Result<Writer, io::Error> {
+        let file_handle = File::create(path_str)?;
+        let mut file = GzEncoder::new(file_handle, Compression::default());
+
+        let mut header = "\"\"".to_string();
+        for gid in 1..num_features + 1 {
+            header.push_str(&format!(",gene{}", gid));
+        }
+        header.push('\n');
+        file.write_all(header.as_bytes())?;
+
+        Ok(Writer {
+            file,
+            num_features,
+            cell_id: 0,
+        })
+    }
+
+    pub fn write_cell(&mut self, bit_vec: &[u8], exp: &[f32]) -> Result<(), io::Error> {
+        let fids = get_positions(bit_vec);
         assert!(
             fids.len() == exp.len(),
-            format!("#positions {} doesn't match with #expressed features {}",
-                    fids.len(), exp.len())
+            "#positions {} doesn't match with #expressed features {}",
+            fids.len(), exp.len()
         );
-        mtx_data = format!("cell{}", cell_id + 1);
+
+        let mut mtx_data = format!("cell{}", self.cell_id + 1);
         let mut zero_counter = 0;
-        for (index, count) in exp.into_iter().enumerate() {
+        for (index, count) in exp.iter().enumerate() {
             assert!(
-                fids[index] < num_features,
-                format!("{} position > {}", fids[index], num_features)
+                fids[index] < self.num_features,
+                "{} position > {}", fids[index], self.num_features
             );
 
             while zero_counter != fids[index] {
@@ -63,14 +149,39 @@ pub fn writer(
             mtx_data.push_str(&format!(",{}", count));
         }
 
-        while zero_counter < num_features {
+        while zero_counter < self.num_features {
             zero_counter += 1;
             mtx_data.push_str(&format!(",0"));
         }
 
         mtx_data.push_str(&format!("\n"));
-        file.write_all(mtx_data.as_bytes())?;
+        self.cell_id += 1;
+        self.file.write_all(mtx_data.as_bytes())
+    }
+
+    pub fn finish(self) -> Result<(), io::Error> {
+        self.file.finish()?;
+        Ok(())
+    }
+}
+
+pub fn writer(
+    path_str: String,
+    expressions: Vec<Vec<f32>>,
+    bit_vecs: Vec<Vec<u8>>,
+    _num_cells: usize,
+    num_features: usize,
+) -> Result<bool, io::Error> {
+    let mut file = Writer::new(&path_str, num_features)?;
+
+    assert!(
+        bit_vecs.len() == expressions.len(),
+        "length of bit vec and expression is not same"
+    );
+    for (cell_id, exp) in expressions.into_iter().enumerate() {
+        file.write_cell(&bit_vecs[cell_id], &exp)?;
     }
 
+    file.finish()?;
     Ok(true)
 }
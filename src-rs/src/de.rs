@@ -0,0 +1,441 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+
+use crate::stats::FeatureStats;
+use crate::transpose::plan_chunks;
+use crate::utils::{for_each_cell, get_positions, FileType};
+
+// complementary error function with fractional error below 1.2e-7
+// everywhere, including the far tail (Numerical Recipes erfcc)
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let r = t * (-z * z - 1.26551223 + t * (1.00002368 + t * (0.37409196 + t * (0.09678418
+        + t * (-0.18628806 + t * (0.27886807 + t * (-1.13520398 + t * (1.48851587
+        + t * (-0.82215223 + t * 0.17087277))))))))).exp();
+
+    match x >= 0.0 {
+        true => r,
+        false => 2.0 - r,
+    }
+}
+
+fn ln_gamma(x: f64) -> f64 {
+    // lanczos approximation, g = 7
+    // the published coefficients, kept digit for digit
+    #[allow(clippy::excessive_precision)]
+    const COEFFICIENTS: [f64; 9] = [
+        0.99999999999980993, 676.5203681218851, -1259.1392167224028,
+        771.32342877765313, -176.61502916214059, 12.507343278686905,
+        -0.13857109526572012, 9.9843695780195716e-6, 1.5056327351493116e-7,
+    ];
+
+    if x < 0.5 {
+        let pi = std::f64::consts::PI;
+        return (pi / (pi * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+
+    let x = x - 1.0;
+    let mut sum = COEFFICIENTS[0];
+    for (i, coefficient) in COEFFICIENTS.iter().enumerate().skip(1) {
+        sum += coefficient / (x + i as f64);
+    }
+    let t = x + 7.5;
+    0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + sum.ln()
+}
+
+// continued fraction of the incomplete beta function (modified Lentz)
+fn beta_fraction(a: f64, b: f64, x: f64) -> f64 {
+    const TINY: f64 = 1e-300;
+    let clamp = |x: f64| if x.abs() < TINY { TINY } else { x };
+
+    let mut c = 1.0;
+    let mut d = 1.0 / clamp(1.0 - (a + b) * x / (a + 1.0));
+    let mut h = d;
+
+    for m in 1..300 {
+        let m = m as f64;
+
+        // even step
+        let numerator = m * (b - m) * x / ((a + 2.0 * m - 1.0) * (a + 2.0 * m));
+        d = 1.0 / clamp(1.0 + numerator * d);
+        c = clamp(1.0 + numerator / c);
+        h *= d * c;
+
+        // odd step
+        let numerator = -(a + m) * (a + b + m) * x / ((a + 2.0 * m) * (a + 2.0 * m + 1.0));
+        d = 1.0 / clamp(1.0 + numerator * d);
+        c = clamp(1.0 + numerator / c);
+        h *= d * c;
+
+        if (d * c - 1.0).abs() < 1e-15 {
+            break;
+        }
+    }
+
+    h
+}
+
+// regularized incomplete beta function I_x(a, b)
+fn incomplete_beta(a: f64, b: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    if x >= 1.0 {
+        return 1.0;
+    }
+
+    let front = (ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b)
+                 + a * x.ln() + b * (1.0 - x).ln()).exp();
+    match x < (a + 1.0) / (a + b + 2.0) {
+        true => front * beta_fraction(a, b, x) / a,
+        false => 1.0 - front * beta_fraction(b, a, 1.0 - x) / b,
+    }
+}
+
+pub struct TestResult {
+    pub statistic: f64,
+    pub p_value: f64,
+}
+
+// mann-whitney u of group a. The zeros of both groups form one tie block,
+// so only the non-zero values have to be sorted.
+fn wilcoxon(values_a: &[f32], values_b: &[f32], num_a: usize, num_b: usize) -> TestResult {
+    let n = (num_a + num_b) as f64;
+    let (zeros_a, zeros_b) = (num_a - values_a.len(), num_b - values_b.len());
+    let num_zeros = zeros_a + zeros_b;
+
+    let mut values: Vec<(f32, bool)> = values_a.iter().map(|x| (*x, true))
+        .chain(values_b.iter().map(|x| (*x, false)))
+        .collect();
+    values.sort_by(|x, y| x.0.total_cmp(&y.0));
+
+    let mut rank_sum_a = 0.0;
+    let mut tie_sum = 0.0;
+    let mut offset = 0;
+    let mut zeros_placed = num_zeros == 0;
+
+    let place_zeros = |offset: &mut usize, rank_sum_a: &mut f64, tie_sum: &mut f64| {
+        let ties = num_zeros as f64;
+        *rank_sum_a += zeros_a as f64 * (*offset as f64 + (ties + 1.0) / 2.0);
+        *tie_sum += ties * ties * ties - ties;
+        *offset += num_zeros;
+    };
+
+    let mut start = 0;
+    while start < values.len() {
+        if !zeros_placed && values[start].0 > 0.0 {
+            place_zeros(&mut offset, &mut rank_sum_a, &mut tie_sum);
+            zeros_placed = true;
+        }
+
+        let mut end = start;
+        while end < values.len() && values[end].0 == values[start].0 {
+            end += 1;
+        }
+
+        let ties = (end - start) as f64;
+        let rank = offset as f64 + (ties + 1.0) / 2.0;
+        let in_a = values[start..end].iter().filter(|x| x.1).count();
+        rank_sum_a += in_a as f64 * rank;
+        tie_sum += ties * ties * ties - ties;
+
+        offset += end - start;
+        start = end;
+    }
+    if !zeros_placed {
+        place_zeros(&mut offset, &mut rank_sum_a, &mut tie_sum);
+    }
+
+    let (na, nb) = (num_a as f64, num_b as f64);
+    let u = rank_sum_a - na * (na + 1.0) / 2.0;
+    let mean = na * nb / 2.0;
+    let variance = na * nb / 12.0 * ((n + 1.0) - tie_sum / (n * (n - 1.0)));
+
+    // normal approximation with continuity correction
+    let p_value = match variance > 0.0 {
+        true => {
+            let z = ((u - mean).abs() - 0.5).max(0.0) / variance.sqrt();
+            erfc(z / std::f64::consts::SQRT_2).min(1.0)
+        }
+        false => 1.0,
+    };
+
+    TestResult { statistic: u, p_value }
+}
+
+// welch's t-test on the group moments
+fn welch(mean_a: f64, var_a: f64, num_a: usize, mean_b: f64, var_b: f64, num_b: usize) -> TestResult {
+    let (na, nb) = (num_a as f64, num_b as f64);
+    let (se_a, se_b) = (var_a / na, var_b / nb);
+    let se = se_a + se_b;
+
+    if se <= 0.0 {
+        let statistic = match mean_a == mean_b {
+            true => 0.0,
+            false => (mean_a - mean_b).signum() * f64::INFINITY,
+        };
+        let p_value = if mean_a == mean_b { 1.0 } else { 0.0 };
+        return TestResult { statistic, p_value };
+    }
+
+    let t = (mean_a - mean_b) / se.sqrt();
+    let df = se * se / (se_a * se_a / (na - 1.0) + se_b * se_b / (nb - 1.0));
+    let p_value = incomplete_beta(df / 2.0, 0.5, df / (df + t * t));
+
+    TestResult { statistic: t, p_value: p_value.min(1.0) }
+}
+
+// benjamini-hochberg adjusted p-values, in the input order
+pub fn adjust_bh(p_values: &[f64]) -> Vec<f64> {
+    let n = p_values.len();
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|a, b| p_values[*b].total_cmp(&p_values[*a]));
+
+    let mut adjusted: Vec<f64> = vec![1.0; n];
+    let mut running_min: f64 = 1.0;
+    for (index, position) in order.iter().enumerate() {
+        let rank = (n - index) as f64;
+        running_min = running_min.min(p_values[*position] * n as f64 / rank);
+        adjusted[*position] = running_min;
+    }
+
+    adjusted
+}
+
+pub struct FeatureResult {
+    pub mean_a: f64,
+    pub mean_b: f64,
+    pub fraction_a: f64,
+    pub fraction_b: f64,
+    pub log2_fold_change: f64,
+    pub wilcoxon: TestResult,
+    pub t_test: TestResult,
+}
+
+pub struct DeResult {
+    pub num_cells: (usize, usize),
+    pub features: Vec<FeatureResult>,
+    pub wilcoxon_adjusted: Vec<f64>,
+    pub t_test_adjusted: Vec<f64>,
+}
+
+// cell_groups holds Some(true) for group a, Some(false) for group b and
+// None for the cells left out. The first pass takes the moments, the
+// wilcoxon test then keeps the non-zero values of at most max_entries
+// entries at a time, one pass over the input per chunk of features.
+pub fn test(
+    input: &str,
+    input_type: FileType,
+    num_cells: usize,
+    num_features: usize,
+    cell_groups: &[Option<bool>],
+    pseudocount: f64,
+    max_entries: usize,
+) -> Result<DeResult, io::Error> {
+    assert!(cell_groups.len() == num_cells,
+            "found {} group labels for {} cells", cell_groups.len(), num_cells);
+
+    let mut stats_a = FeatureStats::new(num_features);
+    let mut stats_b = FeatureStats::new(num_features);
+    let mut nonzero_a: Vec<usize> = vec![0; num_features];
+    let mut nonzero_b: Vec<usize> = vec![0; num_features];
+
+    for_each_cell(input, input_type.clone(), num_cells, num_features, |cell_id, bit_vec, exp| {
+        let (stats, nonzero) = match cell_groups[cell_id] {
+            Some(true) => (&mut stats_a, &mut nonzero_a),
+            Some(false) => (&mut stats_b, &mut nonzero_b),
+            None => return Ok(()),
+        };
+
+        let positions = get_positions(&bit_vec);
+        assert!(positions.len() == exp.len(),
+                "#positions doesn't match with #expressed features");
+
+        stats.add_cell(&positions, &exp);
+        for (position, value) in positions.into_iter().zip(exp) {
+            if value != 0.0 {
+                nonzero[position] += 1;
+            }
+        }
+        Ok(())
+    })?;
+
+    let (num_a, num_b) = (stats_a.num_cells, stats_b.num_cells);
+    info!("Testing {} features between {} and {} cells", num_features, num_a, num_b);
+    if num_a < 2 || num_b < 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("both groups need at least two cells, found {} and {}", num_a, num_b),
+        ));
+    }
+
+    let nonzero: Vec<usize> = nonzero_a.iter().zip(nonzero_b.iter()).map(|(a, b)| a + b).collect();
+    let chunks = plan_chunks(&nonzero, max_entries);
+    info!("Ranking the non-zero values in {} pass(es) over the input", chunks.len());
+
+    let mut wilcoxon_results: Vec<TestResult> = Vec::with_capacity(num_features);
+    for (start, end) in chunks {
+        let mut values_a: Vec<Vec<f32>> = nonzero_a[start..end].iter().map(|x| Vec::with_capacity(*x)).collect();
+        let mut values_b: Vec<Vec<f32>> = nonzero_b[start..end].iter().map(|x| Vec::with_capacity(*x)).collect();
+
+        for_each_cell(input, input_type.clone(), num_cells, num_features, |cell_id, bit_vec, exp| {
+            let values = match cell_groups[cell_id] {
+                Some(true) => &mut values_a,
+                Some(false) => &mut values_b,
+                None => return Ok(()),
+            };
+
+            for (position, value) in get_positions(&bit_vec).into_iter().zip(exp) {
+                if position >= start && position < end && value != 0.0 {
+                    values[position - start].push(value);
+                }
+            }
+            Ok(())
+        })?;
+
+        for (values_a, values_b) in values_a.iter().zip(values_b.iter()) {
+            wilcoxon_results.push(wilcoxon(values_a, values_b, num_a, num_b));
+        }
+    }
+
+    let mut features: Vec<FeatureResult> = Vec::with_capacity(num_features);
+    for (feature_id, wilcoxon) in wilcoxon_results.into_iter().enumerate() {
+        let (mean_a, mean_b) = (stats_a.mean(feature_id), stats_b.mean(feature_id));
+        features.push(FeatureResult {
+            mean_a,
+            mean_b,
+            fraction_a: nonzero_a[feature_id] as f64 / num_a as f64,
+            fraction_b: nonzero_b[feature_id] as f64 / num_b as f64,
+            log2_fold_change: ((mean_a + pseudocount) / (mean_b + pseudocount)).log2(),
+            wilcoxon,
+            t_test: welch(mean_a, stats_a.variance(feature_id), num_a,
+                          mean_b, stats_b.variance(feature_id), num_b),
+        });
+    }
+
+    let wilcoxon_p: Vec<f64> = features.iter().map(|x| x.wilcoxon.p_value).collect();
+    let t_test_p: Vec<f64> = features.iter().map(|x| x.t_test.p_value).collect();
+
+    Ok(DeResult {
+        num_cells: (num_a, num_b),
+        wilcoxon_adjusted: adjust_bh(&wilcoxon_p),
+        t_test_adjusted: adjust_bh(&t_test_p),
+        features,
+    })
+}
+
+pub fn write_table(
+    path: &str,
+    result: &DeResult,
+    names: Option<&Vec<String>>,
+) -> Result<(), io::Error> {
+    info!("Writing test results into file path: {}", path);
+    let mut file = BufWriter::new(File::create(path)?);
+
+    writeln!(file, "feature\tmean_a\tmean_b\tfraction_a\tfraction_b\tlog2_fold_change\t\
+                    wilcoxon_u\twilcoxon_p\twilcoxon_p_adj\tt\tt_p\tt_p_adj")?;
+    for (feature_id, feature) in result.features.iter().enumerate() {
+        let name = match names {
+            Some(names) => names[feature_id].clone(),
+            None => (feature_id + 1).to_string(),
+        };
+
+        writeln!(file, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                 name, feature.mean_a, feature.mean_b,
+                 feature.fraction_a, feature.fraction_b, feature.log2_fold_change,
+                 feature.wilcoxon.statistic, feature.wilcoxon.p_value,
+                 result.wilcoxon_adjusted[feature_id],
+                 feature.t_test.statistic, feature.t_test.p_value,
+                 result.t_test_adjusted[feature_id])?;
+    }
+
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!((actual - expected).abs() <= tolerance * expected.abs().max(1e-300),
+                "expected {}, found {}", expected, actual);
+    }
+
+    #[test]
+    fn erfc_matches_known_values() {
+        assert_close(erfc(0.0), 1.0, 1e-7);
+        assert_close(erfc(0.5), 0.479500122186953, 1.2e-7);
+        assert_close(erfc(-1.2), 1.910313978229635, 1.2e-7);
+        assert_close(erfc(3.0), 2.209049699858544e-5, 1.2e-7);
+    }
+
+    #[test]
+    fn incomplete_beta_matches_closed_forms() {
+        for x in [0.1, 0.35, 0.8].iter() {
+            assert_close(incomplete_beta(1.0, 1.0, *x), *x, 1e-12);
+            assert_close(incomplete_beta(3.0, 1.0, *x), x.powi(3), 1e-12);
+            assert_close(incomplete_beta(1.0, 2.5, *x), 1.0 - (1.0 - x).powf(2.5), 1e-12);
+        }
+        assert_close(incomplete_beta(4.5, 4.5, 0.5), 0.5, 1e-12);
+        assert_close(incomplete_beta(3.0, 4.0, 0.6), 0.8208, 1e-12);
+        assert_close(incomplete_beta(2.5, 0.5, 0.3), 0.018927124071945654, 1e-10);
+    }
+
+    #[test]
+    fn incomplete_beta_gives_t_distribution_tails() {
+        // two sided p-values of student's t with 1 and 2 degrees of freedom
+        for t in [0.3f64, 1.0, 2.5, 12.0].iter() {
+            let p_one = incomplete_beta(0.5, 0.5, 1.0 / (1.0 + t * t));
+            assert_close(p_one, 1.0 - 2.0 / std::f64::consts::PI * t.atan(), 1e-10);
+
+            let p_two = incomplete_beta(1.0, 0.5, 2.0 / (2.0 + t * t));
+            assert_close(p_two, 1.0 - t / (2.0 + t * t).sqrt(), 1e-10);
+        }
+    }
+
+    #[test]
+    fn wilcoxon_matches_normal_approximation() {
+        // wilcox.test(1:5, 6:10, exact = FALSE) in R
+        let result = wilcoxon(&[1.0, 2.0, 3.0, 4.0, 5.0], &[6.0, 7.0, 8.0, 9.0, 10.0], 5, 5);
+        assert_eq!(result.statistic, 0.0);
+        assert_close(result.p_value, 0.0121857803553448, 1e-6);
+    }
+
+    #[test]
+    fn wilcoxon_ranks_zeros_as_one_tie_block() {
+        // wilcox.test(c(0, 0, 1, 3, 3), c(0, 0, 2, 3), exact = FALSE) in R
+        let result = wilcoxon(&[3.0, 1.0, 3.0], &[2.0, 3.0], 5, 4);
+        assert_eq!(result.statistic, 11.0);
+        assert_close(result.p_value, 0.896319845235947, 1e-6);
+
+        let result = wilcoxon(&[], &[], 3, 3);
+        assert_eq!(result.statistic, 4.5);
+        assert_eq!(result.p_value, 1.0);
+    }
+
+    #[test]
+    fn welch_matches_t_test() {
+        // t.test(1:5, c(2, 4, 6, 8, 10, 12)) in R
+        let result = welch(3.0, 2.5, 5, 7.0, 14.0, 6);
+        assert_close(result.statistic, -2.376354103144018, 1e-12);
+        assert_close(result.p_value, 0.0492843382067305, 1e-9);
+
+        let result = welch(1.0, 0.0, 3, 1.0, 0.0, 3);
+        assert_eq!(result.p_value, 1.0);
+    }
+
+    #[test]
+    fn adjust_bh_matches_p_adjust() {
+        // p.adjust(c(0.01, 0.04, 0.03, 0.2), "BH") in R
+        let adjusted = adjust_bh(&[0.01, 0.04, 0.03, 0.2]);
+        let expected = [0.04, 0.04 * 4.0 / 3.0, 0.04 * 4.0 / 3.0, 0.2];
+        for (actual, expected) in adjusted.iter().zip(expected.iter()) {
+            assert_close(*actual, *expected, 1e-12);
+        }
+
+        assert_eq!(adjust_bh(&[0.5, 0.9]), vec![0.9, 0.9]);
+        assert!(adjust_bh(&[]).is_empty());
+    }
+}
@@ -0,0 +1,335 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+
+use crate::utils::{get_positions, CellReader, FileType};
+
+pub struct Tolerance {
+    pub absolute: f64,
+    pub relative: f64,
+}
+
+impl Tolerance {
+    // same rule as numpy's isclose, relative to the larger of the two values
+    fn is_close(&self, a: f64, b: f64) -> bool {
+        (a - b).abs() <= self.absolute + self.relative * a.abs().max(b.abs())
+    }
+}
+
+pub struct CellDiff {
+    pub num_entries: (usize, usize),
+    pub sparsity_changes: usize,
+    pub value_changes: usize,
+    pub max_abs_diff: f64,
+    // None if one of the cells is constant and they aren't equal
+    pub correlation: Option<f64>,
+}
+
+pub struct Diff {
+    pub num_cells: (usize, usize),
+    pub num_features: (usize, usize),
+    pub cells: Vec<CellDiff>,
+    // per feature number of cells with changed sparsity and changed values
+    pub feature_sparsity_changes: Vec<usize>,
+    pub feature_value_changes: Vec<usize>,
+    // largest differences with the (cell, feature) they were found at
+    pub max_abs_diff: (f64, usize, usize),
+    pub max_rel_diff: (f64, usize, usize),
+}
+
+impl Diff {
+    pub fn dimensions_match(&self) -> bool {
+        self.num_cells.0 == self.num_cells.1 && self.num_features.0 == self.num_features.1
+    }
+
+    pub fn num_cells_with(&self, changed: fn(&CellDiff) -> bool) -> usize {
+        self.cells.iter().filter(|cell| changed(cell)).count()
+    }
+
+    pub fn is_match(&self) -> bool {
+        self.dimensions_match() &&
+            self.cells.iter().all(|cell| cell.sparsity_changes == 0 && cell.value_changes == 0)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+
+        let dimensions = |(a, b): (usize, usize)| match a == b {
+            true => format!("{}", a),
+            false => format!("{} vs {} (DIFFERENT)", a, b),
+        };
+        text += &format!("cells:\t{}\n", dimensions(self.num_cells));
+        text += &format!("features:\t{}\n", dimensions(self.num_features));
+        text += &format!("compared_cells:\t{}\n", self.cells.len());
+
+        let num_sparsity_cells = self.num_cells_with(|cell| cell.sparsity_changes > 0);
+        let num_sparsity_features = self.feature_sparsity_changes.iter().filter(|x| **x > 0).count();
+        let num_value_cells = self.num_cells_with(|cell| cell.value_changes > 0);
+        let num_value_features = self.feature_value_changes.iter().filter(|x| **x > 0).count();
+        text += &format!("cells_with_changed_sparsity:\t{}\n", num_sparsity_cells);
+        text += &format!("features_with_changed_sparsity:\t{}\n", num_sparsity_features);
+        text += &format!("cells_with_changed_values:\t{}\n", num_value_cells);
+        text += &format!("features_with_changed_values:\t{}\n", num_value_features);
+
+        let (max_abs, abs_cell, abs_feature) = self.max_abs_diff;
+        let (max_rel, rel_cell, rel_feature) = self.max_rel_diff;
+        text += &format!("max_abs_diff:\t{} (cell {}, feature {})\n",
+                         max_abs, abs_cell + 1, abs_feature + 1);
+        text += &format!("max_rel_diff:\t{} (cell {}, feature {})\n",
+                         max_rel, rel_cell + 1, rel_feature + 1);
+
+        let correlations: Vec<f64> = self.cells.iter().filter_map(|cell| cell.correlation).collect();
+        match correlations.len() {
+            0 => text += "min_correlation:\tNA\nmean_correlation:\tNA\n",
+            n => {
+                let min = correlations.iter().cloned().fold(f64::INFINITY, f64::min);
+                let mean = correlations.iter().sum::<f64>() / n as f64;
+                text += &format!("min_correlation:\t{:.6}\n", min);
+                text += &format!("mean_correlation:\t{:.6}\n", mean);
+            }
+        };
+        text += &format!("undefined_correlations:\t{}\n", self.cells.len() - correlations.len());
+
+        text
+    }
+}
+
+// running sums for the pearson correlation of a cell, the zeros only
+// contribute through the number of features
+struct Moments {
+    n: f64,
+    sum_a: f64,
+    sum_b: f64,
+    sum_aa: f64,
+    sum_bb: f64,
+    sum_ab: f64,
+}
+
+impl Moments {
+    fn new(num_features: usize) -> Moments {
+        Moments { n: num_features as f64, sum_a: 0.0, sum_b: 0.0,
+                  sum_aa: 0.0, sum_bb: 0.0, sum_ab: 0.0 }
+    }
+
+    fn add(&mut self, a: f64, b: f64) {
+        self.sum_a += a;
+        self.sum_b += b;
+        self.sum_aa += a * a;
+        self.sum_bb += b * b;
+        self.sum_ab += a * b;
+    }
+
+    fn correlation(&self, is_equal: bool) -> Option<f64> {
+        let var_a = self.sum_aa - self.sum_a * self.sum_a / self.n;
+        let var_b = self.sum_bb - self.sum_b * self.sum_b / self.n;
+        let cov = self.sum_ab - self.sum_a * self.sum_b / self.n;
+
+        match (var_a > 0.0 && var_b > 0.0, is_equal) {
+            (true, _) => Some((cov / (var_a * var_b).sqrt()).clamp(-1.0, 1.0)),
+            // two identical constant cells, e.g. both empty
+            (false, true) => Some(1.0),
+            (false, false) => None,
+        }
+    }
+}
+
+// walks both files cell by cell, the overlapping cells and features are
+// compared if the dimensions differ
+pub fn diff(
+    input: (&str, FileType, usize, usize),
+    other: (&str, FileType, usize, usize),
+    tolerance: &Tolerance,
+) -> Result<Diff, io::Error> {
+    let (input_path, input_type, input_cells, input_features) = input;
+    let (other_path, other_type, other_cells, other_features) = other;
+    info!("Comparing {} against {}", input_path, other_path);
+
+    let num_features = input_features.min(other_features);
+    let mut result = Diff {
+        num_cells: (input_cells, other_cells),
+        num_features: (input_features, other_features),
+        cells: Vec::new(),
+        feature_sparsity_changes: vec![0; num_features],
+        feature_value_changes: vec![0; num_features],
+        max_abs_diff: (0.0, 0, 0),
+        max_rel_diff: (0.0, 0, 0),
+    };
+
+    let mut input_file = CellReader::new(input_path, input_type, input_cells, input_features)?;
+    let mut other_file = CellReader::new(other_path, other_type, other_cells, other_features)?;
+
+    for cell_id in 0..input_cells.min(other_cells) {
+        let (bit_vec_a, exp_a) = input_file.next_cell()?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof,
+                           format!("{} ends before cell {}", input_path, cell_id + 1))
+        })?;
+        let (bit_vec_b, exp_b) = other_file.next_cell()?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof,
+                           format!("{} ends before cell {}", other_path, cell_id + 1))
+        })?;
+
+        let entries_a: Vec<(usize, f32)> = get_positions(&bit_vec_a).into_iter().zip(exp_a).collect();
+        let entries_b: Vec<(usize, f32)> = get_positions(&bit_vec_b).into_iter().zip(exp_b).collect();
+
+        let mut cell = CellDiff {
+            num_entries: (entries_a.len(), entries_b.len()),
+            sparsity_changes: 0,
+            value_changes: 0,
+            max_abs_diff: 0.0,
+            correlation: None,
+        };
+        let mut moments = Moments::new(num_features);
+
+        // merge both sorted entry lists, missing entries are zeros
+        let (mut i, mut j) = (0, 0);
+        while i < entries_a.len() || j < entries_b.len() {
+            let position_a = entries_a.get(i).map_or(usize::MAX, |x| x.0);
+            let position_b = entries_b.get(j).map_or(usize::MAX, |x| x.0);
+            let position = position_a.min(position_b);
+            if position >= num_features {
+                break;
+            }
+
+            let (mut a, mut b) = (None, None);
+            if position_a == position {
+                a = Some(entries_a[i].1 as f64);
+                i += 1;
+            }
+            if position_b == position {
+                b = Some(entries_b[j].1 as f64);
+                j += 1;
+            }
+
+            if a.is_some() != b.is_some() {
+                cell.sparsity_changes += 1;
+                result.feature_sparsity_changes[position] += 1;
+            }
+
+            let (a, b) = (a.unwrap_or(0.0), b.unwrap_or(0.0));
+            moments.add(a, b);
+
+            let abs_diff = (a - b).abs();
+            let rel_diff = match abs_diff > 0.0 {
+                true => abs_diff / a.abs().max(b.abs()),
+                false => 0.0,
+            };
+            if abs_diff > result.max_abs_diff.0 {
+                result.max_abs_diff = (abs_diff, cell_id, position);
+            }
+            if rel_diff > result.max_rel_diff.0 {
+                result.max_rel_diff = (rel_diff, cell_id, position);
+            }
+            cell.max_abs_diff = cell.max_abs_diff.max(abs_diff);
+
+            if !tolerance.is_close(a, b) {
+                cell.value_changes += 1;
+                result.feature_value_changes[position] += 1;
+            }
+        }
+
+        cell.correlation = moments.correlation(cell.max_abs_diff == 0.0);
+        result.cells.push(cell);
+    }
+
+    Ok(result)
+}
+
+pub fn write_report(
+    cell_path: &str,
+    feature_path: &str,
+    result: &Diff,
+) -> Result<(), io::Error> {
+    info!("Writing per-cell differences into file path: {}", cell_path);
+    let mut file = BufWriter::new(File::create(cell_path)?);
+    writeln!(file, "cell\tentries_a\tentries_b\tsparsity_changes\tvalue_changes\tmax_abs_diff\tcorrelation")?;
+    for (cell_id, cell) in result.cells.iter().enumerate() {
+        let correlation = cell.correlation.map_or("NA".to_string(), |x| x.to_string());
+        writeln!(file, "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                 cell_id + 1, cell.num_entries.0, cell.num_entries.1,
+                 cell.sparsity_changes, cell.value_changes, cell.max_abs_diff, correlation)?;
+    }
+    file.flush()?;
+
+    info!("Writing per-feature differences into file path: {}", feature_path);
+    let mut file = BufWriter::new(File::create(feature_path)?);
+    writeln!(file, "feature\tsparsity_changes\tvalue_changes")?;
+    for (feature_id, count) in result.feature_sparsity_changes.iter().enumerate() {
+        writeln!(file, "{}\t{}\t{}",
+                 feature_id + 1, count, result.feature_value_changes[feature_id])?;
+    }
+    file.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::utils::write_test_cells;
+
+    #[test]
+    fn is_close_with_both_tolerances() {
+        let tolerance = Tolerance { absolute: 0.5, relative: 0.0 };
+        assert!(tolerance.is_close(1.0, 1.5));
+        assert!(!tolerance.is_close(1.0, 1.75));
+        assert!(!tolerance.is_close(100.0, 101.0));
+
+        // relative to the larger value, 0.25 * 4.0 allows the 1.0
+        let tolerance = Tolerance { absolute: 0.0, relative: 0.25 };
+        assert!(tolerance.is_close(3.0, 4.0));
+        assert!(tolerance.is_close(4.0, 3.0));
+        assert!(!tolerance.is_close(2.0, 3.0));
+        assert!(tolerance.is_close(0.0, 0.0));
+
+        let tolerance = Tolerance { absolute: 0.5, relative: 0.25 };
+        assert!(tolerance.is_close(2.0, 3.0));
+        assert!(!tolerance.is_close(1.0, 2.5));
+    }
+
+    #[test]
+    fn merge_counts_sparsity_changes() {
+        let cells_a = vec![
+            (vec![0, 2, 5], vec![1.0, 0.0, 3.0]),
+            (vec![1, 3], vec![2.0, 4.0]),
+            (vec![], vec![]),
+        ];
+        // the explicit zero is gone, and the entries of the second cell
+        // moved without changing the values
+        let cells_b = vec![
+            (vec![0, 5], vec![1.0, 3.0]),
+            (vec![1, 4], vec![2.0, 4.0]),
+            (vec![], vec![]),
+        ];
+        let path_a = write_test_cells("diff-a", FileType::EDS, 6, &cells_a);
+        let path_b = write_test_cells("diff-b", FileType::EDS, 6, &cells_b);
+
+        let tolerance = Tolerance { absolute: 0.0, relative: 0.0 };
+        let result = diff((&path_a, FileType::EDS, 3, 6), (&path_b, FileType::EDS, 3, 6), &tolerance);
+        fs::remove_file(&path_a).unwrap();
+        fs::remove_file(&path_b).unwrap();
+        let result = result.unwrap();
+
+        assert!(result.dimensions_match());
+        assert!(!result.is_match());
+
+        let first = &result.cells[0];
+        assert_eq!(first.num_entries, (3, 2));
+        assert_eq!((first.sparsity_changes, first.value_changes), (1, 0));
+        assert_eq!(first.max_abs_diff, 0.0);
+        assert_eq!(first.correlation, Some(1.0));
+
+        let second = &result.cells[1];
+        assert_eq!((second.sparsity_changes, second.value_changes), (2, 2));
+        assert_eq!(second.max_abs_diff, 4.0);
+
+        let third = &result.cells[2];
+        assert_eq!((third.sparsity_changes, third.value_changes), (0, 0));
+        assert_eq!(third.correlation, Some(1.0));
+
+        assert_eq!(result.feature_sparsity_changes, vec![0, 0, 1, 1, 1, 0]);
+        assert_eq!(result.feature_value_changes, vec![0, 0, 0, 1, 1, 0]);
+        assert_eq!(result.max_abs_diff, (4.0, 1, 3));
+    }
+}
@@ -0,0 +1,133 @@
+use std::io;
+
+use rand::Rng;
+
+use crate::sampling::binomial;
+use crate::utils::{for_each_cell, get_positions, positions_to_bit_vec, CellWriter, FileType};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Depth {
+    // keep every molecule with the given probability
+    Fraction(f64),
+    // draw this many molecules without replacement, smaller cells are kept
+    Total(u64),
+}
+
+fn to_counts(exp: &[f32]) -> Result<Vec<u64>, io::Error> {
+    exp.iter()
+        .map(|value| match value.fract() == 0.0 && *value >= 0.0 {
+            true => Ok(*value as u64),
+            false => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("can't downsample non integer count {}", value),
+            )),
+        })
+        .collect()
+}
+
+// independent binomial thinning of every entry
+fn thin<R: Rng>(counts: &mut [u64], fraction: f64, rng: &mut R) {
+    for count in counts.iter_mut() {
+        *count = binomial(*count, fraction, rng);
+    }
+}
+
+// multivariate hypergeometric draw through selection sampling (Knuth's
+// algorithm S) over the molecules of the cell
+fn draw<R: Rng>(counts: &mut [u64], target: u64, rng: &mut R) {
+    let mut remaining: u64 = counts.iter().sum();
+    let mut needed = target.min(remaining);
+
+    for count in counts.iter_mut() {
+        let mut kept = 0;
+        for _ in 0..*count {
+            if needed > 0 && rng.gen_range(0, remaining) < needed {
+                kept += 1;
+                needed -= 1;
+            }
+            remaining -= 1;
+        }
+        *count = kept;
+    }
+}
+
+pub fn downsample<R: Rng>(
+    input: &str,
+    input_type: FileType,
+    num_cells: usize,
+    num_features: usize,
+    depth: &Depth,
+    rng: &mut R,
+    writer: &mut CellWriter,
+) -> Result<(), io::Error> {
+    info!("Downsampling {} cells to {:?}", num_cells, depth);
+
+    let mut total_before: u64 = 0;
+    let mut total_after: u64 = 0;
+    let mut dropped_entries = 0;
+
+    for_each_cell(input, input_type, num_cells, num_features, |_, bit_vec, exp| {
+        let positions = get_positions(&bit_vec);
+        let mut counts = to_counts(&exp)?;
+        total_before += counts.iter().sum::<u64>();
+
+        match depth {
+            Depth::Fraction(fraction) => thin(&mut counts, *fraction, rng),
+            Depth::Total(target) => draw(&mut counts, *target, rng),
+        };
+        total_after += counts.iter().sum::<u64>();
+
+        // entries thinned out to zero leave the bit vector
+        let mut kept_positions: Vec<usize> = Vec::with_capacity(positions.len());
+        let mut kept_values: Vec<f32> = Vec::with_capacity(positions.len());
+        for (position, count) in positions.into_iter().zip(counts) {
+            match count {
+                0 => dropped_entries += 1,
+                _ => {
+                    kept_positions.push(position);
+                    kept_values.push(count as f32);
+                }
+            };
+        }
+
+        writer.write_cell(positions_to_bit_vec(&kept_positions, num_features), kept_values)
+    })?;
+
+    info!("Kept {} out of {} molecules", total_after, total_before);
+    info!("Removed {} entries which dropped to zero", dropped_entries);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    #[test]
+    fn draw_is_hypergeometric() {
+        let mut rng = ChaCha8Rng::seed_from_u64(7);
+        let num_draws = 20_000;
+
+        let mut firsts: Vec<f64> = Vec::with_capacity(num_draws);
+        for _ in 0..num_draws {
+            let mut counts = vec![5, 10, 15];
+            draw(&mut counts, 12, &mut rng);
+
+            assert_eq!(counts.iter().sum::<u64>(), 12);
+            assert!(counts[0] <= 5 && counts[1] <= 10 && counts[2] <= 15);
+            firsts.push(counts[0] as f64);
+        }
+
+        // 12 draws out of 30 molecules, 5 of them in the first entry
+        let mean = firsts.iter().sum::<f64>() / num_draws as f64;
+        let variance = firsts.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / num_draws as f64;
+        let expected_variance = 12.0 * (5.0 / 30.0) * (25.0 / 30.0) * (18.0 / 29.0);
+        assert!((mean - 2.0).abs() < 0.03, "mean {}", mean);
+        assert!((variance / expected_variance - 1.0).abs() < 0.05, "variance {}", variance);
+
+        let mut counts = vec![3, 4];
+        draw(&mut counts, 10, &mut rng);
+        assert_eq!(counts, vec![3, 4]);
+    }
+}
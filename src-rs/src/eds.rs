@@ -9,6 +9,75 @@ use math::round;
 use flate2::write::GzEncoder;
 use flate2::Compression;
 
+use crate::utils::{get_positions, Cell};
+
+pub struct Reader {
+    file: GzDecoder<File>,
+    num_bit_vecs: usize,
+    num_cells: usize,
+    cells_read: usize,
+    offset: u64,
+}
+
+impl Reader {
+    pub fn new(input: &str, num_cells: usize, num_genes: usize) -> Result<Reader, io::Error> {
+        let file_handle = File::open(input)?;
+
+        Ok(Reader {
+            file: GzDecoder::new(file_handle),
+            num_bit_vecs: round::ceil(num_genes as f64 / 8.0, 0) as usize,
+            num_cells,
+            cells_read: 0,
+            offset: 0,
+        })
+    }
+
+    // offset in the uncompressed stream
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn read_bit_vec(&mut self) -> Result<Vec<u8>, io::Error> {
+        let mut bit_vec = vec![0; self.num_bit_vecs];
+        self.file.read_exact(&mut bit_vec[..])?;
+        self.offset += bit_vec.len() as u64;
+
+        Ok(bit_vec)
+    }
+
+    pub fn read_values(&mut self, num_values: usize) -> Result<Vec<f32>, io::Error> {
+        let mut expression: Vec<u8> = vec![0; 4 * num_values];
+        let mut float_buffer: Vec<f32> = vec![0.0_f32; num_values];
+        self.file.read_exact(&mut expression[..])?;
+        self.offset += expression.len() as u64;
+        LittleEndian::read_f32_into(&expression, &mut float_buffer);
+
+        Ok(float_buffer)
+    }
+
+    // returns the next (bit vector, expression) pair, or None after num_cells
+    pub fn next_cell(&mut self) -> Result<Option<Cell>, io::Error> {
+        if self.cells_read == self.num_cells {
+            return Ok(None);
+        }
+
+        let bit_vec = self.read_bit_vec()?;
+        let mut num_ones = 0;
+        for bits in bit_vec.iter() {
+            num_ones += bits.count_ones();
+        }
+
+        let float_buffer = self.read_values(num_ones as usize)?;
+        self.cells_read += 1;
+        Ok(Some((bit_vec, float_buffer)))
+    }
+
+    // consumes the rest of the stream, returns the number of bytes left
+    pub fn trailing_bytes(&mut self) -> Result<u64, io::Error> {
+        io::copy(&mut self.file, &mut io::sink())
+    }
+}
+
 pub fn reader(
     input: &str,
     num_cells: usize,
@@ -22,28 +91,16 @@ pub fn reader(
         num_cells, num_genes
     );
 
-    let num_bit_vecs: usize = round::ceil(num_genes as f64 / 8.0, 0) as usize;
     let mut total_molecules = 0.0;
     let mut total_exp_values = 0;
 
     {
         let mut count = 0;
-        let file_handle = File::open(input)?;
-        let mut file = GzDecoder::new(file_handle);
-
-        for _ in 0..num_cells {
-            let mut bit_vec = vec![0; num_bit_vecs];
-            file.read_exact(&mut bit_vec[..])?;
-            let mut num_ones = 0;
-            for bits in bit_vec.iter() {
-                num_ones += bits.count_ones();
-            }
-            bit_vecs.push(bit_vec);
+        let mut file = Reader::new(input, num_cells, num_genes)?;
 
-            let mut expression: Vec<u8> = vec![0; 4 * (num_ones as usize)];
-            let mut float_buffer: Vec<f32> = vec![0.0_f32; num_ones as usize];
-            file.read_exact(&mut expression[..])?;
-            LittleEndian::read_f32_into(&expression, &mut float_buffer);
+        while let Some((bit_vec, float_buffer)) = file.next_cell()? {
+            let num_ones = float_buffer.len();
+            bit_vecs.push(bit_vec);
 
             let cell_count: f32 = float_buffer.iter().sum();
             total_molecules += cell_count;
@@ -56,6 +113,11 @@ pub fn reader(
                 io::stdout().flush()?;
             }
         }
+
+        let trailing_bytes = file.trailing_bytes()?;
+        if trailing_bytes > 0 {
+            warn!("Found {} bytes after the last cell, is #cells right?", trailing_bytes);
+        }
     }
 
     println!("\n");
@@ -80,17 +142,169 @@ pub fn writer(
     _num_cells: usize,
     _num_features: usize,
 ) -> Result<bool, io::Error> {
-    let file_handle = File::create(path_str)?;
-    let mut file = GzEncoder::new(file_handle, Compression::default());
+    let mut file = Writer::new(&path_str)?;
 
     assert!(expressions.len() == bit_vecs.len());
     for (exp, bvec) in expressions.into_iter().zip(bit_vecs.into_iter()) {
-        file.write_all(&bvec)?;
+        file.write_cell(&bvec, &exp)?;
+    }
+
+    file.finish()?;
+    Ok(true)
+}
+
+pub struct Writer<W: Write = File> {
+    file: GzEncoder<W>,
+}
+
+impl Writer<File> {
+    pub fn new(path_str: &str) -> Result<Writer<File>, io::Error> {
+        let file_handle = File::create(path_str)?;
+        Ok(Writer::from_sink(file_handle))
+    }
+}
+
+impl<W: Write> Writer<W> {
+    pub fn from_sink(sink: W) -> Writer<W> {
+        Writer {
+            file: GzEncoder::new(sink, Compression::default()),
+        }
+    }
+
+    pub fn write_cell(&mut self, bit_vec: &[u8], exp: &[f32]) -> Result<(), io::Error> {
+        self.file.write_all(bit_vec)?;
 
         let mut bin_exp: Vec<u8> = vec![0_u8; exp.len() * 4];
-        LittleEndian::write_f32_into(&exp, &mut bin_exp);
-        file.write_all(&bin_exp)?;
+        LittleEndian::write_f32_into(exp, &mut bin_exp);
+        self.file.write_all(&bin_exp)
     }
 
-    Ok(true)
+    pub fn finish(self) -> Result<W, io::Error> {
+        self.file.finish()
+    }
+}
+
+// counts the bytes written to it, sizes a compressed stream without
+// writing it anywhere
+#[derive(Default)]
+pub struct CountingSink {
+    pub bytes: u64,
+}
+
+impl Write for CountingSink {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.bytes += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CellOrder {
+    Gray,
+    MinHash,
+}
+
+// rank of a bit vector when read as a binary reflected gray code, adjacent
+// ranks differ in a single bit
+fn gray_rank(bit_vec: &[u8]) -> Vec<u8> {
+    let mut rank: Vec<u8> = Vec::with_capacity(bit_vec.len());
+    let mut carry: u8 = 0;
+    for byte in bit_vec {
+        let mut binary: u8 = 0;
+        for offset in 0..8 {
+            let bit = (byte >> (7 - offset)) & 1;
+            carry ^= bit;
+            binary |= carry << (7 - offset);
+        }
+        rank.push(binary);
+    }
+
+    rank
+}
+
+const NUM_MIN_HASHES: usize = 4;
+
+fn min_hashes(bit_vec: &[u8]) -> [u64; NUM_MIN_HASHES] {
+    // odd multipliers of a multiply-shift hash family
+    const SEEDS: [u64; NUM_MIN_HASHES] = [
+        0x9e37_79b9_7f4a_7c15, 0xbf58_476d_1ce4_e5b9, 0x94d0_49bb_1331_11eb, 0xd6e8_feb8_6659_fd93,
+    ];
+
+    let mut signature = [u64::MAX; NUM_MIN_HASHES];
+    for position in get_positions(bit_vec) {
+        for (index, seed) in SEEDS.iter().enumerate() {
+            let hash = (position as u64 + 1).wrapping_mul(*seed);
+            signature[index] = signature[index].min(hash ^ (hash >> 29));
+        }
+    }
+
+    signature
+}
+
+// order putting cells with similar sparsity patterns next to each other,
+// so that gzip finds their bit vectors within its window
+pub fn reorder_cells(bit_vecs: &[Vec<u8>], method: &CellOrder) -> Vec<usize> {
+    info!("Ordering {} cells by {:?}", bit_vecs.len(), method);
+    let mut order: Vec<usize> = (0..bit_vecs.len()).collect();
+
+    match method {
+        CellOrder::Gray => {
+            let ranks: Vec<Vec<u8>> = bit_vecs.iter().map(|x| gray_rank(x)).collect();
+            order.sort_by(|a, b| ranks[*a].cmp(&ranks[*b]).then(a.cmp(b)));
+        }
+        CellOrder::MinHash => {
+            let signatures: Vec<[u64; NUM_MIN_HASHES]> = bit_vecs.iter()
+                .map(|x| min_hashes(x))
+                .collect();
+            order.sort_by(|a, b| signatures[*a].cmp(&signatures[*b]).then(a.cmp(b)));
+        }
+    };
+
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counting_sink_matches_file_size() {
+        let bit_vecs = [vec![0b1010_0000_u8], vec![0b0000_0001_u8], vec![0_u8]];
+        let alphas = [vec![1.0, 2.5], vec![7.0], vec![]];
+
+        let mut counted = Writer::from_sink(CountingSink::default());
+        let mut written = Writer::from_sink(Vec::new());
+        for (bit_vec, exp) in bit_vecs.iter().zip(alphas.iter()) {
+            counted.write_cell(bit_vec, exp).unwrap();
+            written.write_cell(bit_vec, exp).unwrap();
+        }
+
+        let written = written.finish().unwrap();
+        assert!(!written.is_empty());
+        assert_eq!(counted.finish().unwrap().bytes, written.len() as u64);
+    }
+
+    #[test]
+    fn gray_rank_inverts_the_gray_code() {
+        // two bytes, so the carry crosses the byte boundary
+        for binary in 0..(1u32 << 16) {
+            let gray = binary ^ (binary >> 1);
+            let rank = gray_rank(&[(gray >> 8) as u8, gray as u8]);
+            assert_eq!(rank, vec![(binary >> 8) as u8, binary as u8], "gray code {:#06x}", gray);
+        }
+    }
+
+    #[test]
+    fn gray_order_changes_one_bit_at_a_time() {
+        let bit_vecs: Vec<Vec<u8>> = (0..=255u8).rev().map(|x| vec![x]).collect();
+        let order = reorder_cells(&bit_vecs, &CellOrder::Gray);
+
+        for pair in order.windows(2) {
+            assert_eq!((bit_vecs[pair[0]][0] ^ bit_vecs[pair[1]][0]).count_ones(), 1);
+        }
+    }
 }
@@ -0,0 +1,265 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+
+use crate::utils::{for_each_cell, get_positions, positions_to_bit_vec, CellWriter, Cells, FileType};
+
+pub fn align(
+    bit_vecs: Vec<Vec<u8>>,
+    alphas: Vec<Vec<f32>>,
+    names: &[String],
+    reference: &[String],
+) -> Result<Cells, io::Error> {
+    info!("Aligning {} features to {} reference features",
+          names.len(), reference.len());
+    assert!(bit_vecs.len() == alphas.len());
+
+    let mut ref_index: HashMap<&str, usize> = HashMap::new();
+    for (index, name) in reference.iter().enumerate() {
+        if ref_index.insert(name.as_str(), index).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("duplicate feature {} in reference list", name),
+            ));
+        }
+    }
+
+    // maps old feature position to the new one, if kept. Duplicated names
+    // share a position, so remap sums their counts
+    let mut seen: HashSet<&str> = HashSet::new();
+    let mut new_index: Vec<Option<usize>> = Vec::with_capacity(names.len());
+    let mut num_merged = 0;
+    for name in names.iter() {
+        let index = ref_index.get(name.as_str()).cloned();
+        if !seen.insert(name.as_str()) && index.is_some() {
+            num_merged += 1;
+        }
+
+        new_index.push(index);
+    }
+
+    let num_dropped = new_index.iter().filter(|x| x.is_none()).count();
+    let num_missing = reference.iter()
+        .filter(|name| !seen.contains(name.as_str()))
+        .count();
+
+    if num_merged > 0 {
+        warn!("Merging {} duplicated features into their first occurrence", num_merged);
+    }
+    if num_dropped > 0 {
+        warn!("Dropping {} features not in the reference", num_dropped);
+    }
+    if num_missing > 0 {
+        warn!("Inserting {} empty features missing from the input", num_missing);
+    }
+
+    let mut aligned_bvecs: Vec<Vec<u8>> = Vec::with_capacity(bit_vecs.len());
+    let mut aligned_alphas: Vec<Vec<f32>> = Vec::with_capacity(alphas.len());
+    for (bit_vec, exp) in bit_vecs.into_iter().zip(alphas) {
+        let (bit_vec, exp) = remap(&bit_vec, exp, &new_index, reference.len());
+        aligned_bvecs.push(bit_vec);
+        aligned_alphas.push(exp);
+    }
+
+    Ok((aligned_bvecs, aligned_alphas))
+}
+
+// moves every entry to its new feature position, dropping the unmapped ones
+// and summing the ones mapped to the same position
+pub fn remap(
+    bit_vec: &[u8],
+    exp: Vec<f32>,
+    new_index: &[Option<usize>],
+    num_features: usize,
+) -> (Vec<u8>, Vec<f32>) {
+    let positions = get_positions(bit_vec);
+    assert!(positions.len() == exp.len(),
+            "#positions doesn't match with #expressed features");
+
+    let mut entries: Vec<(usize, f32)> = Vec::with_capacity(exp.len());
+    for (position, value) in positions.into_iter().zip(exp) {
+        assert!(position < new_index.len(),
+                "{} position > {}", position, new_index.len());
+
+        if let Some(index) = new_index[position] {
+            entries.push((index, value));
+        }
+    }
+    entries.sort_by_key(|x| x.0);
+    entries.dedup_by(|next, kept| match next.0 == kept.0 {
+        true => {
+            kept.1 += next.1;
+            true
+        }
+        false => false,
+    });
+
+    let positions: Vec<usize> = entries.iter().map(|x| x.0).collect();
+    (positions_to_bit_vec(&positions, num_features),
+     entries.into_iter().map(|x| x.1).collect())
+}
+
+// feature<TAB>target table, e.g. transcript to gene
+pub fn read_map(file_path: &str) -> Result<HashMap<String, String>, io::Error> {
+    let file = BufReader::new(File::open(file_path)?);
+
+    let mut map: HashMap<String, String> = HashMap::new();
+    for (line_id, line) in file.lines().enumerate() {
+        let record = line?;
+        if record.trim().is_empty() || record.starts_with('#') {
+            continue;
+        }
+
+        let vals: Vec<&str> = record.split('\t').collect();
+        if vals.len() < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {} of {} is not feature<TAB>target", line_id + 1, file_path),
+            ));
+        }
+
+        let (feature, target) = (vals[0].trim(), vals[1].trim());
+        if let Some(old_target) = map.insert(feature.to_string(), target.to_string()) {
+            if old_target != target {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("feature {} mapped to both {} and {}", feature, old_target, target),
+                ));
+            }
+        }
+    }
+
+    info!("Found {} mapped features in {}", map.len(), file_path);
+    Ok(map)
+}
+
+// returns the new position of every feature and the new feature names, in
+// the order they are first seen. Features sharing a target, or a name if
+// they aren't mapped, end up in the same position.
+pub fn aggregation_index(
+    names: &[String],
+    map: Option<&HashMap<String, String>>,
+    keep_unmapped: bool,
+) -> (Vec<Option<usize>>, Vec<String>) {
+    let mut target_index: HashMap<&str, usize> = HashMap::new();
+    let mut targets: Vec<String> = Vec::new();
+    let mut new_index: Vec<Option<usize>> = Vec::with_capacity(names.len());
+
+    let mut num_unmapped = 0;
+    for name in names.iter() {
+        let target = match map.map(|map| map.get(name)) {
+            Some(Some(target)) => target,
+            Some(None) if !keep_unmapped => {
+                num_unmapped += 1;
+                new_index.push(None);
+                continue;
+            }
+            Some(None) => {
+                num_unmapped += 1;
+                name
+            }
+            None => name,
+        };
+
+        let index = *target_index.entry(target.as_str()).or_insert_with(|| {
+            targets.push(target.clone());
+            targets.len() - 1
+        });
+        new_index.push(Some(index));
+    }
+
+    if num_unmapped > 0 {
+        match keep_unmapped {
+            true => warn!("{} features aren't in the map, keeping them as they are", num_unmapped),
+            false => warn!("{} features aren't in the map, dropping them", num_unmapped),
+        };
+    }
+    info!("Aggregating {} features into {}", names.len(), targets.len());
+
+    (new_index, targets)
+}
+
+pub fn aggregate(
+    input: &str,
+    input_type: FileType,
+    num_cells: usize,
+    num_features: usize,
+    new_index: &[Option<usize>],
+    num_targets: usize,
+    writer: &mut CellWriter,
+) -> Result<(), io::Error> {
+    assert!(new_index.len() == num_features,
+            "found {} mapped features for {} features", new_index.len(), num_features);
+
+    for_each_cell(input, input_type, num_cells, num_features, |_, bit_vec, exp| {
+        let (bit_vec, exp) = remap(&bit_vec, exp, new_index, num_targets);
+        writer.write_cell(bit_vec, exp)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{read_file, write_test_cells};
+
+    fn to_strings(names: &[&str]) -> Vec<String> {
+        names.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn align_sums_duplicates() {
+        let names = to_strings(&["b", "x", "a", "b"]);
+        let reference = to_strings(&["a", "b", "c"]);
+
+        let bit_vecs = vec![positions_to_bit_vec(&[0, 1, 2, 3], 4)];
+        let alphas = vec![vec![1.0, 2.0, 3.0, 4.0]];
+        let (bit_vecs, alphas) = align(bit_vecs, alphas, &names, &reference).unwrap();
+
+        assert_eq!(get_positions(&bit_vecs[0]), vec![0, 1]);
+        assert_eq!(alphas[0], vec![3.0, 5.0]);
+    }
+
+    #[test]
+    fn aggregate_sums_mapped_features() {
+        let cells = vec![
+            (vec![0, 1, 2, 3], vec![1.0, 2.0, 3.0, 4.0]),
+            (vec![2, 3], vec![5.0, 6.0]),
+        ];
+        let input = write_test_cells("features-aggregate", FileType::EDS, 4, &cells);
+
+        let names = to_strings(&["g1", "g2", "g3", "g4"]);
+        let map: HashMap<String, String> = vec![("g1", "A"), ("g2", "B"), ("g3", "A")]
+            .into_iter().map(|(x, y)| (x.to_string(), y.to_string())).collect();
+
+        let mut results = Vec::new();
+        for keep_unmapped in [true, false].iter() {
+            let (new_index, targets) = aggregation_index(&names, Some(&map), *keep_unmapped);
+
+            let output = input.replace(".eds.gz", &format!(".{}.eds.gz", keep_unmapped));
+            let mut writer = CellWriter::new(output.clone(), FileType::EDS, targets.len(), None)
+                .unwrap();
+            aggregate(&input, FileType::EDS, 2, 4, &new_index, targets.len(), &mut writer).unwrap();
+            writer.finish().unwrap();
+
+            let (bit_vecs, values) = read_file(&output, FileType::EDS, 2, targets.len()).unwrap();
+            let positions: Vec<Vec<usize>> = bit_vecs.iter().map(|x| get_positions(x)).collect();
+            results.push((new_index, targets, positions, values));
+            std::fs::remove_file(&output).unwrap();
+        }
+        std::fs::remove_file(&input).unwrap();
+
+        let (new_index, targets, positions, values) = &results[0];
+        assert_eq!(new_index, &vec![Some(0), Some(1), Some(0), Some(2)]);
+        assert_eq!(targets, &to_strings(&["A", "B", "g4"]));
+        assert_eq!(positions, &vec![vec![0, 1, 2], vec![0, 2]]);
+        assert_eq!(values, &vec![vec![4.0, 2.0, 4.0], vec![5.0, 6.0]]);
+
+        // --drop-unmapped leaves g4 out
+        let (new_index, targets, positions, values) = &results[1];
+        assert_eq!(new_index, &vec![Some(0), Some(1), Some(0), None]);
+        assert_eq!(targets, &to_strings(&["A", "B"]));
+        assert_eq!(positions, &vec![vec![0, 1], vec![0]]);
+        assert_eq!(values, &vec![vec![4.0, 2.0], vec![5.0]]);
+    }
+}
@@ -0,0 +1,365 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+
+use regex::Regex;
+
+use crate::features::remap;
+use crate::stats::FeatureStats;
+use crate::utils::{cell_total, for_each_cell, get_positions, CellWriter, FileType};
+
+pub struct CellFilter {
+    pub min_counts: Option<f64>,
+    pub max_counts: Option<f64>,
+    pub min_features: Option<usize>,
+    pub max_features: Option<usize>,
+    // per cell, whether its barcode is in the whitelist
+    pub whitelisted: Option<Vec<bool>>,
+    // features counted as mitochondrial and the maximum fraction allowed
+    pub mito: Option<(Vec<bool>, f64)>,
+}
+
+// names of the rules, in the order of the counts in a filter report
+const CELL_RULES: [&str; 6] = [
+    "min_counts", "max_counts", "min_features", "max_features", "whitelist", "max_mito_fraction",
+];
+
+pub fn mito_features(names: &[String], prefix: Option<&str>, pattern: Option<&str>)
+                     -> Result<Vec<bool>, io::Error> {
+    let regex = match pattern {
+        Some(pattern) => Some(Regex::new(pattern).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("bad mito regex: {}", e))
+        })?),
+        None => None,
+    };
+
+    let mask: Vec<bool> = names.iter()
+        .map(|name| {
+            prefix.is_some_and(|prefix| name.starts_with(prefix)) ||
+                regex.as_ref().is_some_and(|regex| regex.is_match(name))
+        })
+        .collect();
+
+    info!("Found {} mitochondrial features", mask.iter().filter(|x| **x).count());
+    Ok(mask)
+}
+
+impl CellFilter {
+    // returns, per rule, whether the cell fails it
+    fn failed_rules(&self, cell_id: usize, positions: &[usize], exp: &[f32]) -> [bool; 6] {
+        let total: f64 = cell_total(exp);
+        let detected = exp.len();
+
+        let mut failed = [false; 6];
+        failed[0] = self.min_counts.is_some_and(|min| total < min);
+        failed[1] = self.max_counts.is_some_and(|max| total > max);
+        failed[2] = self.min_features.is_some_and(|min| detected < min);
+        failed[3] = self.max_features.is_some_and(|max| detected > max);
+        failed[4] = self.whitelisted.as_ref().is_some_and(|whitelisted| !whitelisted[cell_id]);
+        failed[5] = match &self.mito {
+            Some((mask, max_fraction)) if total > 0.0 => {
+                let mito_total: f64 = positions.iter()
+                    .zip(exp)
+                    .filter(|(position, _)| mask[**position])
+                    .map(|(_, value)| *value as f64)
+                    .sum();
+
+                mito_total / total > *max_fraction
+            }
+            _ => false,
+        };
+
+        failed
+    }
+}
+
+pub struct FeatureFilter {
+    pub min_cells: Option<usize>,
+    pub min_counts: Option<f64>,
+}
+
+const FEATURE_RULES: [&str; 2] = ["min_cells", "min_feature_counts"];
+
+impl FeatureFilter {
+    pub fn is_active(&self) -> bool {
+        self.min_cells.is_some() || self.min_counts.is_some()
+    }
+
+    // returns the new position of every feature and, per rule, the number
+    // of features failing it
+    fn new_index(&self, stats: &FeatureStats) -> (Vec<Option<usize>>, Vec<usize>) {
+        let mut rule_counts: Vec<usize> = vec![0; FEATURE_RULES.len()];
+        let mut new_index: Vec<Option<usize>> = Vec::with_capacity(stats.num_features());
+
+        let mut num_kept = 0;
+        for feature_id in 0..stats.num_features() {
+            let failed = [
+                self.min_cells.is_some_and(|min| stats.detected[feature_id] < min),
+                self.min_counts.is_some_and(|min| stats.sums[feature_id] < min),
+            ];
+
+            for (rule_id, is_failed) in failed.iter().enumerate() {
+                if *is_failed {
+                    rule_counts[rule_id] += 1;
+                }
+            }
+
+            match failed.iter().any(|x| *x) {
+                true => new_index.push(None),
+                false => {
+                    new_index.push(Some(num_kept));
+                    num_kept += 1;
+                }
+            };
+        }
+
+        (new_index, rule_counts)
+    }
+}
+
+// the cell rules, then the feature rules over the kept cells
+pub struct Rules {
+    pub cells: CellFilter,
+    pub features: FeatureFilter,
+}
+
+pub struct FilterResult {
+    pub kept_cells: Vec<usize>,
+    pub kept_features: Vec<usize>,
+    pub cell_rule_counts: Vec<usize>,
+    pub feature_rule_counts: Vec<usize>,
+}
+
+pub fn filter(
+    input: &str,
+    input_type: FileType,
+    num_cells: usize,
+    num_features: usize,
+    rules: &Rules,
+    output: String,
+    output_type: FileType,
+) -> Result<FilterResult, io::Error> {
+    // mtx and h5 write their size up front, they need the first pass even
+    // without feature rules
+    let is_sized = output_type == FileType::MTX || output_type == FileType::H5;
+    let (cell_filter, feature_filter) = (&rules.cells, &rules.features);
+    let two_pass = feature_filter.is_active() || is_sized;
+
+    // first pass: apply the cell rules, count the features over the kept cells
+    let mut is_kept: Vec<bool> = Vec::with_capacity(num_cells);
+    let mut cell_rule_counts: Vec<usize> = vec![0; CELL_RULES.len()];
+    let mut feature_stats = FeatureStats::new(num_features);
+
+    let mut first_pass = |cell_id: usize, bit_vec: &[u8], exp: &[f32]| -> bool {
+        let positions = get_positions(bit_vec);
+        let failed = cell_filter.failed_rules(cell_id, &positions, exp);
+        for (rule_id, is_failed) in failed.iter().enumerate() {
+            if *is_failed {
+                cell_rule_counts[rule_id] += 1;
+            }
+        }
+
+        let keep = !failed.iter().any(|x| *x);
+        if keep && two_pass {
+            feature_stats.add_cell(&positions, exp);
+        }
+
+        is_kept.push(keep);
+        keep
+    };
+
+    let mut kept_cells: Vec<usize> = Vec::new();
+    let mut kept_features: Vec<usize> = (0..num_features).collect();
+    let mut feature_rule_counts: Vec<usize> = vec![0; FEATURE_RULES.len()];
+
+    if !two_pass {
+        // nothing to count, write in the same pass
+        let mut writer = CellWriter::new(output, output_type, num_features, None)?;
+        for_each_cell(input, input_type, num_cells, num_features, |cell_id, bit_vec, exp| {
+            if !first_pass(cell_id, &bit_vec, &exp) {
+                return Ok(());
+            }
+
+            kept_cells.push(cell_id);
+            writer.write_cell(bit_vec, exp)
+        })?;
+        writer.finish()?;
+    } else {
+        for_each_cell(input, input_type.clone(), num_cells, num_features, |cell_id, bit_vec, exp| {
+            first_pass(cell_id, &bit_vec, &exp);
+            Ok(())
+        })?;
+
+        let (new_index, rule_counts) = feature_filter.new_index(&feature_stats);
+        feature_rule_counts = rule_counts;
+        kept_features = (0..num_features).filter(|x| new_index[*x].is_some()).collect();
+
+        // second pass: rewrite the bit vectors of the kept cells
+        let num_kept_cells = is_kept.iter().filter(|x| **x).count();
+        let num_entries: usize = kept_features.iter().map(|x| feature_stats.detected[*x]).sum();
+        let mut writer = CellWriter::new(output, output_type, kept_features.len(),
+                                         Some((num_kept_cells, num_entries)))?;
+        for_each_cell(input, input_type, num_cells, num_features, |cell_id, bit_vec, exp| {
+            if !is_kept[cell_id] {
+                return Ok(());
+            }
+
+            kept_cells.push(cell_id);
+            let (bit_vec, exp) = remap(&bit_vec, exp, &new_index, kept_features.len());
+            writer.write_cell(bit_vec, exp)
+        })?;
+        writer.finish()?;
+    }
+
+    info!("Kept {} out of {} cells", kept_cells.len(), num_cells);
+    for (rule, count) in CELL_RULES.iter().zip(cell_rule_counts.iter()) {
+        info!("{} removed {} cells", rule, count);
+    }
+
+    info!("Kept {} out of {} features", kept_features.len(), num_features);
+    for (rule, count) in FEATURE_RULES.iter().zip(feature_rule_counts.iter()) {
+        info!("{} removed {} features", rule, count);
+    }
+
+    Ok(FilterResult {
+        kept_cells,
+        kept_features,
+        cell_rule_counts,
+        feature_rule_counts,
+    })
+}
+
+pub fn write_report(
+    path: &str,
+    num_cells: usize,
+    num_features: usize,
+    result: &FilterResult,
+) -> Result<(), io::Error> {
+    info!("Writing filter report into file path: {}", path);
+    let mut file = BufWriter::new(File::create(path)?);
+
+    // an entry failing several rules is counted under each of them
+    writeln!(file, "rule\tremoved")?;
+    for (rule, count) in CELL_RULES.iter().zip(result.cell_rule_counts.iter()) {
+        writeln!(file, "{}\t{}", rule, count)?;
+    }
+    writeln!(file, "removed_cells\t{}", num_cells - result.kept_cells.len())?;
+    writeln!(file, "kept_cells\t{}", result.kept_cells.len())?;
+
+    for (rule, count) in FEATURE_RULES.iter().zip(result.feature_rule_counts.iter()) {
+        writeln!(file, "{}\t{}", rule, count)?;
+    }
+    writeln!(file, "removed_features\t{}", num_features - result.kept_features.len())?;
+    writeln!(file, "kept_features\t{}", result.kept_features.len())?;
+
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_rules() -> CellFilter {
+        CellFilter {
+            min_counts: None,
+            max_counts: None,
+            min_features: None,
+            max_features: None,
+            whitelisted: None,
+            mito: None,
+        }
+    }
+
+    fn to_strings(names: &[&str]) -> Vec<String> {
+        names.iter().map(|x| x.to_string()).collect()
+    }
+
+    // the rules failed by a cell with the given values at positions 0, 1, ...
+    fn failed_by(cell_filter: &CellFilter, cell_id: usize, exp: &[f32]) -> Vec<&'static str> {
+        let positions: Vec<usize> = (0..exp.len()).collect();
+        let failed = cell_filter.failed_rules(cell_id, &positions, exp);
+        CELL_RULES.iter()
+            .zip(failed.iter())
+            .filter(|x| *x.1)
+            .map(|x| *x.0)
+            .collect()
+    }
+
+    #[test]
+    fn count_rules() {
+        let cell_filter = CellFilter { min_counts: Some(3.0), max_counts: Some(10.0), ..no_rules() };
+        assert_eq!(failed_by(&cell_filter, 0, &[1.0, 1.0]), vec!["min_counts"]);
+        assert!(failed_by(&cell_filter, 0, &[1.0, 2.0]).is_empty());
+        assert!(failed_by(&cell_filter, 0, &[4.0, 6.0]).is_empty());
+        assert_eq!(failed_by(&cell_filter, 0, &[4.0, 6.5]), vec!["max_counts"]);
+    }
+
+    #[test]
+    fn feature_rules() {
+        let cell_filter = CellFilter { min_features: Some(2), max_features: Some(3), ..no_rules() };
+        assert_eq!(failed_by(&cell_filter, 0, &[5.0]), vec!["min_features"]);
+        assert!(failed_by(&cell_filter, 0, &[1.0, 1.0, 1.0]).is_empty());
+        assert_eq!(failed_by(&cell_filter, 0, &[1.0; 4]), vec!["max_features"]);
+
+        // one cell can fail several rules
+        let cell_filter = CellFilter { min_counts: Some(10.0), min_features: Some(2), ..no_rules() };
+        assert_eq!(failed_by(&cell_filter, 0, &[1.0]), vec!["min_counts", "min_features"]);
+    }
+
+    #[test]
+    fn whitelist_rule() {
+        let cell_filter = CellFilter { whitelisted: Some(vec![true, false]), ..no_rules() };
+        assert!(failed_by(&cell_filter, 0, &[1.0]).is_empty());
+        assert_eq!(failed_by(&cell_filter, 1, &[1.0]), vec!["whitelist"]);
+    }
+
+    #[test]
+    fn mito_rule() {
+        let cell_filter = CellFilter {
+            mito: Some((vec![true, false, false], 0.25)),
+            ..no_rules()
+        };
+        assert!(failed_by(&cell_filter, 0, &[1.0, 2.0, 1.0]).is_empty());
+        assert_eq!(failed_by(&cell_filter, 0, &[2.0, 2.0, 1.0]), vec!["max_mito_fraction"]);
+        // a cell without counts has no fraction to fail
+        assert!(failed_by(&cell_filter, 0, &[]).is_empty());
+    }
+
+    #[test]
+    fn mito_prefix_and_regex() {
+        let names = to_strings(&["MT-CO1", "mt-Nd1", "ACTB", "MTOR"]);
+
+        let mask = mito_features(&names, Some("MT-"), None).unwrap();
+        assert_eq!(mask, vec![true, false, false, false]);
+
+        let mask = mito_features(&names, None, Some("^(?i)mt-")).unwrap();
+        assert_eq!(mask, vec![true, true, false, false]);
+
+        // a feature matching either of them is counted
+        let mask = mito_features(&names, Some("ACT"), Some("^mt-")).unwrap();
+        assert_eq!(mask, vec![false, true, true, false]);
+
+        assert!(mito_features(&names, None, Some("(")).is_err());
+    }
+
+    #[test]
+    fn feature_rules_over_kept_cells() {
+        // 3 cells x 4 features
+        let mut stats = FeatureStats::new(4);
+        stats.add_cell(&[0, 1, 2], &[1.0, 5.0, 1.0]);
+        stats.add_cell(&[0, 2], &[1.0, 1.0]);
+        stats.add_cell(&[0, 1], &[1.0, 1.0]);
+
+        let feature_filter = FeatureFilter { min_cells: Some(2), min_counts: Some(3.0) };
+        let (new_index, rule_counts) = feature_filter.new_index(&stats);
+        assert_eq!(new_index, vec![Some(0), Some(1), None, None]);
+        // the last feature fails both rules
+        assert_eq!(rule_counts, vec![1, 2]);
+
+        let feature_filter = FeatureFilter { min_cells: None, min_counts: None };
+        assert!(!feature_filter.is_active());
+        let (new_index, rule_counts) = feature_filter.new_index(&stats);
+        assert_eq!(new_index, vec![Some(0), Some(1), Some(2), Some(3)]);
+        assert_eq!(rule_counts, vec![0, 0]);
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+use crate::utils::{
+    for_each_cell, get_extension, get_positions, positions_to_bit_vec, write_names, CellWriter,
+    FileType,
+};
+
+pub fn read_groups(file_path: &str) -> Result<HashMap<String, String>, io::Error> {
+    let file = BufReader::new(File::open(file_path)?);
+
+    let mut groups: HashMap<String, String> = HashMap::new();
+    for (line_id, line) in file.lines().enumerate() {
+        let record = line?;
+        if record.trim().is_empty() || record.starts_with('#') {
+            continue;
+        }
+
+        let vals: Vec<&str> = record.split('\t').collect();
+        if vals.len() < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {} of {} is not barcode<TAB>group", line_id + 1, file_path),
+            ));
+        }
+
+        let (barcode, group) = (vals[0].trim(), vals[1].trim());
+        if let Some(old_group) = groups.insert(barcode.to_string(), group.to_string()) {
+            if old_group != group {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("barcode {} assigned to both {} and {}", barcode, old_group, group),
+                ));
+            }
+        }
+    }
+
+    info!("Found {} barcodes with a group in {}", groups.len(), file_path);
+    Ok(groups)
+}
+
+// returns the group index of every cell and the sorted group names
+pub fn assign(
+    barcodes: &[String],
+    groups: &HashMap<String, String>,
+) -> (Vec<Option<usize>>, Vec<String>) {
+    let mut names: Vec<String> = groups.values().cloned().collect();
+    names.sort();
+    names.dedup();
+
+    let name_index: HashMap<&str, usize> = names.iter()
+        .enumerate()
+        .map(|(index, name)| (name.as_str(), index))
+        .collect();
+
+    let cell_groups: Vec<Option<usize>> = barcodes.iter()
+        .map(|barcode| groups.get(barcode).map(|group| name_index[group.as_str()]))
+        .collect();
+
+    let num_missing = cell_groups.iter().filter(|x| x.is_none()).count();
+    if num_missing > 0 {
+        warn!("{} cells don't have a group", num_missing);
+    }
+
+    (cell_groups, names)
+}
+
+// group names end up in file names, two groups can't share one
+pub fn file_names(names: &[String]) -> Result<Vec<String>, io::Error> {
+    let mut seen: HashMap<String, &str> = HashMap::new();
+    let mut safe_names: Vec<String> = Vec::with_capacity(names.len());
+    for name in names.iter() {
+        let safe_name: String = name.chars()
+            .map(|c| match c.is_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                true => c,
+                false => '_',
+            })
+            .collect();
+
+        if let Some(other) = seen.insert(safe_name.clone(), name.as_str()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("groups {} and {} would both be written as {}", other, name, safe_name),
+            ));
+        }
+        safe_names.push(safe_name);
+    }
+
+    Ok(safe_names)
+}
+
+struct GroupOutput {
+    barcodes_path: String,
+    barcodes: Vec<String>,
+    writer: CellWriter,
+}
+
+// splits the cells by group in one pass over the input, the number of
+// cells is the number of barcodes. Every group is buffered in its writer
+// until finish for the formats which need their size up front
+pub fn split(
+    input: &str,
+    input_type: FileType,
+    num_features: usize,
+    barcodes: &[String],
+    groups: &HashMap<String, String>,
+    output_prefix: &str,
+    output_type: FileType,
+) -> Result<(), io::Error> {
+    let num_cells = barcodes.len();
+    let (cell_groups, names) = assign(barcodes, groups);
+    info!("Splitting {} cells into {} groups", num_cells, names.len());
+
+    let safe_names = file_names(&names)?;
+
+    let mut outputs: Vec<GroupOutput> = Vec::with_capacity(names.len());
+    for safe_name in safe_names.iter() {
+        let path = format!("{}.{}{}", output_prefix, safe_name, get_extension(&output_type));
+        outputs.push(GroupOutput {
+            barcodes_path: format!("{}.{}.barcodes.txt", output_prefix, safe_name),
+            barcodes: Vec::new(),
+            writer: CellWriter::new(path, output_type.clone(), num_features, None)?,
+        });
+    }
+
+    for_each_cell(input, input_type, num_cells, num_features, |cell_id, bit_vec, exp| {
+        let output = match cell_groups[cell_id] {
+            Some(group_id) => &mut outputs[group_id],
+            None => return Ok(()),
+        };
+
+        output.barcodes.push(barcodes[cell_id].clone());
+        output.writer.write_cell(bit_vec, exp)
+    })?;
+
+    for (name, output) in names.iter().zip(outputs) {
+        info!("Group {} has {} cells", name, output.barcodes.len());
+        output.writer.finish()?;
+        write_names(&output.barcodes_path, &output.barcodes)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Aggregation {
+    Sum,
+    Mean,
+}
+
+// sums the cells of every group into one sparse row, the rows follow the
+// sorted group names which are returned with the number of cells in them
+pub fn pseudobulk(
+    input: &str,
+    input_type: FileType,
+    num_features: usize,
+    barcodes: &[String],
+    groups: &HashMap<String, String>,
+    aggregation: &Aggregation,
+    writer: &mut CellWriter,
+) -> Result<Vec<(String, usize)>, io::Error> {
+    let num_cells = barcodes.len();
+    let (cell_groups, names) = assign(barcodes, groups);
+    info!("Aggregating {} cells into {} groups", num_cells, names.len());
+
+    let mut totals: Vec<HashMap<usize, f64>> = vec![HashMap::new(); names.len()];
+    let mut sizes: Vec<usize> = vec![0; names.len()];
+    for_each_cell(input, input_type, num_cells, num_features, |cell_id, bit_vec, exp| {
+        let group_id = match cell_groups[cell_id] {
+            Some(group_id) => group_id,
+            None => return Ok(()),
+        };
+
+        let positions = get_positions(&bit_vec);
+        assert!(positions.len() == exp.len(),
+                "#positions doesn't match with #expressed features");
+
+        for (position, value) in positions.into_iter().zip(exp) {
+            *totals[group_id].entry(position).or_insert(0.0) += value as f64;
+        }
+        sizes[group_id] += 1;
+        Ok(())
+    })?;
+
+    for (group_total, size) in totals.into_iter().zip(sizes.iter()) {
+        let mut entries: Vec<(usize, f64)> = group_total.into_iter().collect();
+        entries.sort_by_key(|x| x.0);
+
+        let scale = match (aggregation, size) {
+            (Aggregation::Mean, size) if *size > 0 => 1.0 / *size as f64,
+            _ => 1.0,
+        };
+
+        let positions: Vec<usize> = entries.iter().map(|x| x.0).collect();
+        let values: Vec<f32> = entries.iter().map(|x| (x.1 * scale) as f32).collect();
+        writer.write_cell(positions_to_bit_vec(&positions, num_features), values)?;
+    }
+
+    Ok(names.into_iter().zip(sizes).collect())
+}
+
+pub fn write_sizes(groups_path: &str, sizes: &[(String, usize)]) -> Result<(), io::Error> {
+    info!("Writing group sizes into file path: {}", groups_path);
+    let mut file = BufWriter::new(File::create(groups_path)?);
+    writeln!(file, "group\tnum_cells")?;
+    for (name, size) in sizes.iter() {
+        writeln!(file, "{}\t{}", name, size)?;
+    }
+
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{read_file, read_names, write_test_cells};
+
+    #[test]
+    fn file_names_collide() {
+        let names: Vec<String> = vec!["a b".to_string(), "a-b".to_string()];
+        assert_eq!(file_names(&names).unwrap(), vec!["a_b", "a-b"]);
+
+        let names: Vec<String> = vec!["a b".to_string(), "a/b".to_string()];
+        assert!(file_names(&names).is_err());
+    }
+
+    #[test]
+    fn split_keeps_the_cell_order() {
+        let cells: Vec<(Vec<usize>, Vec<f32>)> = vec![
+            (vec![0], vec![1.0]),
+            (vec![1, 2], vec![2.0, 3.0]),
+            (vec![2], vec![4.0]),
+            (vec![], vec![]),
+            (vec![0, 2], vec![5.0, 6.0]),
+        ];
+        let input = write_test_cells("groups-split", FileType::EDS, 3, &cells);
+        let prefix = input.trim_end_matches(".eds.gz");
+
+        // c3 has no group and is left out
+        let barcodes: Vec<String> = vec!["c1", "c2", "c3", "c4", "c5"]
+            .into_iter().map(|x| x.to_string()).collect();
+        let groups: HashMap<String, String> = vec![("c1", "b"), ("c2", "a"), ("c4", "b"), ("c5", "a")]
+            .into_iter().map(|(x, y)| (x.to_string(), y.to_string())).collect();
+
+        split(&input, FileType::EDS, 3, &barcodes, &groups, prefix, FileType::EDS).unwrap();
+        std::fs::remove_file(&input).unwrap();
+
+        let expected = vec![
+            ("a", vec!["c2", "c5"], vec![vec![1, 2], vec![0, 2]], vec![vec![2.0, 3.0], vec![5.0, 6.0]]),
+            ("b", vec!["c1", "c4"], vec![vec![0], vec![]], vec![vec![1.0], vec![]]),
+        ];
+        for (name, group_barcodes, positions, values) in expected.into_iter() {
+            let output = format!("{}.{}.eds.gz", prefix, name);
+            let barcodes_path = format!("{}.{}.barcodes.txt", prefix, name);
+
+            let (bit_vecs, exp) = read_file(&output, FileType::EDS, 2, 3).unwrap();
+            let names = read_names(&barcodes_path).unwrap();
+            std::fs::remove_file(&output).unwrap();
+            std::fs::remove_file(&barcodes_path).unwrap();
+
+            let cell_positions: Vec<Vec<usize>> = bit_vecs.iter().map(|x| get_positions(x)).collect();
+            assert_eq!(cell_positions, positions);
+            assert_eq!(exp, values);
+            assert_eq!(names, group_barcodes);
+        }
+    }
+
+    #[test]
+    fn pseudobulk_sums_and_averages() {
+        let cells: Vec<(Vec<usize>, Vec<f32>)> = vec![
+            (vec![0, 2], vec![1.0, 2.0]),
+            (vec![1], vec![5.0]),
+            (vec![0, 1], vec![3.0, 4.0]),
+            (vec![3], vec![7.0]),
+        ];
+        let input = write_test_cells("groups-pseudobulk", FileType::EDS, 4, &cells);
+
+        // the last cell has no group and is left out
+        let barcodes: Vec<String> = vec!["c1", "c2", "c3", "c4"]
+            .into_iter().map(|x| x.to_string()).collect();
+        let groups: HashMap<String, String> = vec![("c1", "b"), ("c2", "a"), ("c3", "b")]
+            .into_iter().map(|(x, y)| (x.to_string(), y.to_string())).collect();
+
+        let mut results = Vec::new();
+        for aggregation in [Aggregation::Sum, Aggregation::Mean].iter() {
+            let output = input.replace(".eds.gz", &format!(".{:?}.eds.gz", aggregation));
+            let mut writer = CellWriter::new(output.clone(), FileType::EDS, 4, None).unwrap();
+            let sizes = pseudobulk(&input, FileType::EDS, 4, &barcodes, &groups,
+                                   aggregation, &mut writer).unwrap();
+            writer.finish().unwrap();
+
+            assert_eq!(sizes, vec![("a".to_string(), 1), ("b".to_string(), 2)]);
+            let (bit_vecs, values) = read_file(&output, FileType::EDS, 2, 4).unwrap();
+            let positions: Vec<Vec<usize>> = bit_vecs.iter().map(|x| get_positions(x)).collect();
+            results.push((positions, values));
+            std::fs::remove_file(&output).unwrap();
+        }
+        std::fs::remove_file(&input).unwrap();
+
+        let positions = vec![vec![1], vec![0, 1, 2]];
+        assert_eq!(results[0], (positions.clone(), vec![vec![5.0], vec![4.0, 4.0, 2.0]]));
+        assert_eq!(results[1], (positions, vec![vec![5.0], vec![2.0, 2.0, 1.0]]));
+    }
+}
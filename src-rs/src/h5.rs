@@ -1,6 +1,74 @@
 use hdf5;
+use ndarray::{s, ArrayView1};
 use std::io;
 
+use crate::utils::{get_positions, positions_to_bit_vec};
+
+// entries buffered before a slice of data and indices is written
+const BLOCK_ENTRIES: usize = 1 << 20;
+
+pub fn reader(
+    input: &str,
+    num_cells: usize,
+    num_genes: usize,
+    expr: &mut Vec<Vec<f32>>,
+    bit_vecs: &mut Vec<Vec<u8>>,
+) -> Result<bool, io::Error> {
+    info!("Using {} as input H5 file\n", input);
+    info!(
+        "Using {} Rows (cells) and {} Columns (features)",
+        num_cells, num_genes
+    );
+
+    let file = hdf5::File::open(input, "r").map_err(h5_error)?;
+    let group = file.group("matrix").map_err(h5_error)?;
+
+    let shape: Vec<u64> = group.dataset("shape")
+        .and_then(|x| x.read_raw::<u64>())
+        .map_err(h5_error)?;
+    if shape.len() != 2 || shape[0] as usize != num_genes || shape[1] as usize != num_cells {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("h5 shape {:?} doesn't match {} features x {} cells", shape, num_genes, num_cells),
+        ));
+    }
+
+    let indptr: Vec<u32> = group.dataset("indptr")
+        .and_then(|x| x.read_raw::<u32>())
+        .map_err(h5_error)?;
+    let indices: Vec<u32> = group.dataset("indices")
+        .and_then(|x| x.read_raw::<u32>())
+        .map_err(h5_error)?;
+    let data: Vec<f32> = group.dataset("data")
+        .and_then(|x| x.read_raw::<f32>())
+        .map_err(h5_error)?;
+
+    if indptr.len() != num_cells + 1 || indices.len() != data.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("h5 has {} indptr entries for {} cells, {} indices for {} values",
+                    indptr.len(), num_cells, indices.len(), data.len()),
+        ));
+    }
+
+    // every column of the csc matrix is a cell
+    for cell_id in 0..num_cells {
+        let (start, end) = (indptr[cell_id] as usize, indptr[cell_id + 1] as usize);
+
+        let mut entries: Vec<(usize, f32)> = indices[start..end].iter()
+            .zip(data[start..end].iter())
+            .map(|(index, value)| (*index as usize, *value))
+            .collect();
+        entries.sort_by_key(|x| x.0);
+
+        let positions: Vec<usize> = entries.iter().map(|x| x.0).collect();
+        bit_vecs.push(positions_to_bit_vec(&positions, num_genes));
+        expr.push(entries.into_iter().map(|x| x.1).collect());
+    }
+
+    Ok(true)
+}
+
 pub fn writer(
     path_str: String,
     expressions: Vec<Vec<f32>>,
@@ -105,3 +173,108 @@ pub fn writer(
 
     Ok(true)
 }
+
+fn h5_error(error: hdf5::Error) -> io::Error {
+    io::Error::other(error.to_string())
+}
+
+// streams the cells into data and indices datasets of a size known up
+// front, only indptr (one entry per cell) is kept in memory
+pub struct Writer {
+    // keeps the file open until finish
+    _file: hdf5::File,
+    group: hdf5::Group,
+    data: hdf5::Dataset,
+    indices: hdf5::Dataset,
+    data_block: Vec<f32>,
+    indices_block: Vec<u32>,
+    indptr: Vec<u32>,
+    num_written: usize,
+    num_cells: usize,
+    num_entries: usize,
+}
+
+impl Writer {
+    pub fn new(
+        path_str: &str,
+        num_cells: usize,
+        num_features: usize,
+        num_entries: usize,
+    ) -> Result<Writer, io::Error> {
+        let file = hdf5::File::open(path_str, "w").map_err(h5_error)?;
+        let group = file.create_group("matrix").map_err(h5_error)?;
+
+        group.new_dataset::<u64>()
+            .gzip(6)
+            .create("shape", 2)
+            .and_then(|shape| shape.write(&[num_features, num_cells]))
+            .map_err(h5_error)?;
+
+        let data = group.new_dataset::<f32>()
+            .gzip(6)
+            .create("data", num_entries)
+            .map_err(h5_error)?;
+        let indices = group.new_dataset::<u32>()
+            .gzip(6)
+            .create("indices", num_entries)
+            .map_err(h5_error)?;
+
+        Ok(Writer {
+            _file: file,
+            group,
+            data,
+            indices,
+            data_block: Vec::new(),
+            indices_block: Vec::new(),
+            indptr: vec![0],
+            num_written: 0,
+            num_cells,
+            num_entries,
+        })
+    }
+
+    fn flush_block(&mut self) -> Result<(), io::Error> {
+        let (start, end) = (self.num_written, self.num_written + self.data_block.len());
+        assert!(end <= self.num_entries, "more than {} entries", self.num_entries);
+
+        self.data.write_slice(ArrayView1::from(&self.data_block[..]), s![start..end])
+            .and_then(|_| self.indices.write_slice(ArrayView1::from(&self.indices_block[..]),
+                                                   s![start..end]))
+            .map_err(h5_error)?;
+
+        self.num_written = end;
+        self.data_block.clear();
+        self.indices_block.clear();
+        Ok(())
+    }
+
+    pub fn write_cell(&mut self, bit_vec: &[u8], exp: &[f32]) -> Result<(), io::Error> {
+        let positions = get_positions(bit_vec);
+        assert!(
+            positions.len() == exp.len(),
+            "#positions doesn't match with #expressed features"
+        );
+
+        self.indices_block.extend(positions.into_iter().map(|x| x as u32));
+        self.data_block.extend_from_slice(exp);
+        let last = *self.indptr.last().unwrap();
+        self.indptr.push(last + exp.len() as u32);
+
+        match self.data_block.len() >= BLOCK_ENTRIES {
+            true => self.flush_block(),
+            false => Ok(()),
+        }
+    }
+
+    pub fn finish(mut self) -> Result<(), io::Error> {
+        self.flush_block()?;
+        assert!(self.indptr.len() == self.num_cells + 1, "num cells doesn't match");
+        assert!(self.num_written == self.num_entries, "different number of entries");
+
+        self.group.new_dataset::<u32>()
+            .gzip(6)
+            .create("indptr", self.indptr.len())
+            .and_then(|indptr| indptr.write_raw(&self.indptr))
+            .map_err(h5_error)
+    }
+}
@@ -0,0 +1,150 @@
+use std::io;
+
+use sha2::{Digest, Sha256};
+
+use crate::utils::{for_each_cell, get_positions, FileType};
+
+// bumped whenever the canonical byte stream below changes
+const HASH_VERSION: &[u8] = b"eds-content-hash-v1\0";
+
+const CANONICAL_NAN: u32 = 0x7fc0_0000;
+
+fn update_names(hasher: &mut Sha256, tag: u8, names: Option<&Vec<String>>) {
+    match names {
+        Some(names) => {
+            hasher.update([tag]);
+            hasher.update((names.len() as u64).to_le_bytes());
+            for name in names {
+                hasher.update((name.len() as u64).to_le_bytes());
+                hasher.update(name.as_bytes());
+            }
+        }
+        None => hasher.update([0u8]),
+    };
+}
+
+// -0.0 hashes as 0.0 and every NaN as the same quiet NaN
+fn canonical_bits(value: f32) -> u32 {
+    if value.is_nan() {
+        CANONICAL_NAN
+    } else if value == 0.0 {
+        0
+    } else {
+        value.to_bits()
+    }
+}
+
+// hashes the non-zero entries of one cell, returns how many there are
+fn update_cell(hasher: &mut Sha256, positions: Vec<usize>, exp: Vec<f32>) -> usize {
+    assert!(positions.len() == exp.len(),
+            "#positions doesn't match with #expressed features");
+
+    let entries: Vec<(usize, f32)> = positions.into_iter()
+        .zip(exp)
+        .filter(|(_, value)| *value != 0.0)
+        .collect();
+
+    hasher.update((entries.len() as u64).to_le_bytes());
+    for (position, value) in entries.iter() {
+        hasher.update((*position as u64).to_le_bytes());
+        hasher.update(canonical_bits(*value).to_le_bytes());
+    }
+
+    entries.len()
+}
+
+// sha256 over the logical content of the matrix: the dimensions, then per
+// cell the number of non-zero entries followed by their positions and
+// values, then the names if given. Explicit zeros are skipped, -0.0 is
+// treated as 0.0 and all NaNs as one, so neither the container nor the
+// compression changes the digest.
+pub fn content_hash(
+    input: &str,
+    input_type: FileType,
+    num_cells: usize,
+    num_features: usize,
+    barcodes: Option<&Vec<String>>,
+    features: Option<&Vec<String>>,
+) -> Result<String, io::Error> {
+    let mut hasher = Sha256::new();
+    hasher.update(HASH_VERSION);
+    hasher.update((num_cells as u64).to_le_bytes());
+    hasher.update((num_features as u64).to_le_bytes());
+
+    let mut num_cells_read = 0;
+    let mut num_entries = 0;
+    for_each_cell(input, input_type, num_cells, num_features, |_, bit_vec, exp| {
+        num_entries += update_cell(&mut hasher, get_positions(&bit_vec), exp);
+        num_cells_read += 1;
+        Ok(())
+    })?;
+
+    if num_cells_read != num_cells {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("found {} cells, expected {}", num_cells_read, num_cells),
+        ));
+    }
+    info!("Hashed {} cells with {} non-zero entries", num_cells_read, num_entries);
+
+    update_names(&mut hasher, 1, barcodes);
+    update_names(&mut hasher, 2, features);
+
+    let digest = hasher.finalize();
+    Ok(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::write_test_cells;
+
+    fn cell_digest(positions: Vec<usize>, exp: Vec<f32>) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        update_cell(&mut hasher, positions, exp);
+        hasher.finalize().to_vec()
+    }
+
+    #[test]
+    fn canonical_values() {
+        assert_eq!(canonical_bits(-0.0), canonical_bits(0.0));
+        assert_eq!(canonical_bits(f32::from_bits(0x7fc0_0001)), canonical_bits(f32::NAN));
+        assert_eq!(canonical_bits(f32::from_bits(0xffc0_0000)), CANONICAL_NAN);
+        assert_eq!(canonical_bits(1.5), 1.5_f32.to_bits());
+
+        // different nan payloads and signs hash the same
+        assert_eq!(cell_digest(vec![0, 3], vec![1.0, f32::from_bits(0xff80_0001)]),
+                   cell_digest(vec![0, 3], vec![1.0, f32::NAN]));
+
+        // explicit zeros of either sign are skipped
+        assert_eq!(cell_digest(vec![0, 3], vec![-0.0, 2.0]),
+                   cell_digest(vec![3], vec![2.0]));
+        assert_ne!(cell_digest(vec![3], vec![2.0]),
+                   cell_digest(vec![3], vec![-2.0]));
+    }
+
+    #[test]
+    fn same_content_in_every_format() {
+        // the explicit zero only survives in EDS and MTX
+        let cells: Vec<(Vec<usize>, Vec<f32>)> = vec![
+            (vec![0, 3], vec![1.0, 2.5]),
+            (vec![], vec![]),
+            (vec![1, 2, 4], vec![0.0, 7.0, -3.0]),
+        ];
+
+        let mut digests = Vec::new();
+        for file_type in [FileType::EDS, FileType::MTX, FileType::CSV].iter() {
+            let path = write_test_cells("hash", file_type.clone(), 5, &cells);
+            digests.push(content_hash(&path, file_type.clone(), 3, 5, None, None).unwrap());
+            if *file_type == FileType::EDS {
+                let barcodes: Vec<String> = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+                let with_barcodes = content_hash(&path, FileType::EDS, 3, 5, Some(&barcodes), None);
+                assert_ne!(with_barcodes.unwrap(), digests[0]);
+            }
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        assert_eq!(digests[0], digests[1]);
+        assert_eq!(digests[0], digests[2]);
+    }
+}
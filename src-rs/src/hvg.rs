@@ -0,0 +1,421 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+
+use crate::features::remap;
+use crate::stats::FeatureStats;
+use crate::utils::{for_each_cell, get_positions, CellWriter, FileType};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HvgMethod {
+    // log dispersion z-scored within bins of similar mean (Seurat v1)
+    Dispersion,
+    // variance of the values standardized by a loess fit of the
+    // mean-variance trend (Seurat v3)
+    Vst,
+}
+
+pub fn parse_method(value: &str) -> Result<HvgMethod, io::Error> {
+    match value {
+        "dispersion" => Ok(HvgMethod::Dispersion),
+        "vst" => Ok(HvgMethod::Vst),
+        x => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown hvg method {}, expected dispersion or vst", x),
+        )),
+    }
+}
+
+const NUM_MEAN_BINS: usize = 20;
+const LOESS_SPAN: f64 = 0.3;
+
+pub struct FeatureScores {
+    pub means: Vec<f64>,
+    pub variances: Vec<f64>,
+    // None for features that can't be scored, e.g. never expressed
+    pub scores: Vec<Option<f64>>,
+}
+
+impl FeatureScores {
+    // the n best scoring features, ties keep the input order
+    pub fn top(&self, n: usize) -> Vec<usize> {
+        let mut ranked: Vec<usize> = (0..self.scores.len())
+            .filter(|x| self.scores[*x].is_some())
+            .collect();
+        ranked.sort_by(|a, b| {
+            let (sa, sb) = (self.scores[*a].unwrap(), self.scores[*b].unwrap());
+            sb.total_cmp(&sa).then(a.cmp(b))
+        });
+        ranked.truncate(n);
+        ranked
+    }
+}
+
+fn dispersion_scores(means: &[f64], variances: &[f64]) -> Vec<Option<f64>> {
+    let log_dispersions: Vec<Option<f64>> = (0..means.len())
+        .map(|x| match means[x] > 0.0 && variances[x] > 0.0 {
+            true => Some((variances[x] / means[x]).ln()),
+            false => None,
+        })
+        .collect();
+
+    // equal width bins over the log mean of the scored features
+    let log_means: Vec<f64> = means.iter().map(|x| x.ln_1p()).collect();
+    let scored: Vec<usize> = (0..means.len()).filter(|x| log_dispersions[*x].is_some()).collect();
+    if scored.is_empty() {
+        return log_dispersions;
+    }
+
+    let min = scored.iter().map(|x| log_means[*x]).fold(f64::INFINITY, f64::min);
+    let max = scored.iter().map(|x| log_means[*x]).fold(f64::NEG_INFINITY, f64::max);
+    let width = (max - min) / NUM_MEAN_BINS as f64;
+    let bin_of = |x: usize| match width > 0.0 {
+        true => (((log_means[x] - min) / width) as usize).min(NUM_MEAN_BINS - 1),
+        false => 0,
+    };
+
+    let mut bins: Vec<Vec<f64>> = vec![Vec::new(); NUM_MEAN_BINS];
+    for feature_id in scored.iter() {
+        bins[bin_of(*feature_id)].push(log_dispersions[*feature_id].unwrap());
+    }
+
+    let bin_moments: Vec<(f64, f64)> = bins.iter()
+        .map(|values| {
+            let n = values.len() as f64;
+            let mean = values.iter().sum::<f64>() / n;
+            let sd = match values.len() {
+                0 | 1 => 0.0,
+                _ => (values.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / (n - 1.0)).sqrt(),
+            };
+            (mean, sd)
+        })
+        .collect();
+
+    // a feature alone in its bin, or in a bin without spread, scores zero
+    (0..means.len())
+        .map(|x| log_dispersions[x].map(|dispersion| {
+            let (mean, sd) = bin_moments[bin_of(x)];
+            match sd > 0.0 {
+                true => (dispersion - mean) / sd,
+                false => 0.0,
+            }
+        }))
+        .collect()
+}
+
+// local quadratic regression with tricube weights, x has to be sorted
+fn loess(x: &[f64], y: &[f64], span: f64) -> Vec<f64> {
+    let n = x.len();
+    let k = ((span * n as f64).ceil() as usize).max(3).min(n);
+
+    let mut fitted: Vec<f64> = Vec::with_capacity(n);
+    let mut lo = 0;
+    for i in 0..n {
+        // the k nearest neighbours of a sorted x are a contiguous window
+        while lo + k < n && x[lo + k] - x[i] < x[i] - x[lo] {
+            lo += 1;
+        }
+        let window = lo..lo + k;
+        let max_dist = (x[i] - x[lo]).max(x[lo + k - 1] - x[i]);
+
+        // weighted normal equations of y = a + b*dx + c*dx^2
+        let mut moments = [0.0f64; 5];
+        let mut rhs = [0.0f64; 3];
+        for j in window {
+            let dx = x[j] - x[i];
+            let weight = match max_dist > 0.0 {
+                true => (1.0 - (dx.abs() / max_dist).powi(3)).powi(3),
+                false => 1.0,
+            };
+
+            let mut power = weight;
+            for m in 0..5 {
+                if m < 3 {
+                    rhs[m] += power * y[j];
+                }
+                moments[m] += power;
+                power *= dx;
+            }
+        }
+
+        let matrix = [
+            [moments[0], moments[1], moments[2]],
+            [moments[1], moments[2], moments[3]],
+            [moments[2], moments[3], moments[4]],
+        ];
+        let fit = match solve3(matrix, rhs) {
+            Some(coefficients) => coefficients[0],
+            None => rhs[0] / moments[0],
+        };
+        fitted.push(fit);
+    }
+
+    fitted
+}
+
+// cramer's rule, None if the system is (close to) singular
+fn solve3(a: [[f64; 3]; 3], b: [f64; 3]) -> Option<[f64; 3]> {
+    let det = |m: [[f64; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+
+    let d = det(a);
+    let scale = a[0][0] * a[1][1] * a[2][2];
+    if d.abs() <= 1e-12 * scale.abs() || d == 0.0 {
+        return None;
+    }
+
+    let mut solution = [0.0; 3];
+    for column in 0..3 {
+        let mut m = a;
+        for row in 0..3 {
+            m[row][column] = b[row];
+        }
+        solution[column] = det(m) / d;
+    }
+
+    Some(solution)
+}
+
+fn vst_scores(
+    input: &str,
+    input_type: FileType,
+    num_cells: usize,
+    num_features: usize,
+    means: &[f64],
+    variances: &[f64],
+) -> Result<Vec<Option<f64>>, io::Error> {
+    let mut fitted: Vec<usize> = (0..num_features).filter(|x| variances[*x] > 0.0).collect();
+    fitted.sort_by(|a, b| means[*a].total_cmp(&means[*b]));
+
+    let x: Vec<f64> = fitted.iter().map(|f| means[*f].log10()).collect();
+    let y: Vec<f64> = fitted.iter().map(|f| variances[*f].log10()).collect();
+    let trend = loess(&x, &y, LOESS_SPAN);
+
+    let mut expected_sd: Vec<Option<f64>> = vec![None; num_features];
+    for (feature_id, fit) in fitted.iter().zip(trend) {
+        expected_sd[*feature_id] = Some(10f64.powf(fit).sqrt());
+    }
+
+    // second pass: sum of the squared standardized values clipped at
+    // sqrt(#cells), the zeros are added in bulk at the end
+    let clip = (num_cells as f64).sqrt();
+    let mut sums: Vec<f64> = vec![0.0; num_features];
+    let mut detected: Vec<usize> = vec![0; num_features];
+    for_each_cell(input, input_type, num_cells, num_features, |_, bit_vec, exp| {
+        for (position, value) in get_positions(&bit_vec).into_iter().zip(exp) {
+            if let Some(sd) = expected_sd[position] {
+                let z = ((value as f64 - means[position]).abs() / sd).min(clip);
+                sums[position] += z * z;
+                detected[position] += 1;
+            }
+        }
+        Ok(())
+    })?;
+
+    let n = num_cells as f64;
+    let scores = (0..num_features)
+        .map(|x| expected_sd[x].map(|sd| {
+            let z = (means[x] / sd).min(clip);
+            let sum = sums[x] + (num_cells - detected[x]) as f64 * z * z;
+            sum / (n - 1.0)
+        }))
+        .collect();
+
+    Ok(scores)
+}
+
+pub fn score_features(
+    input: &str,
+    input_type: FileType,
+    num_cells: usize,
+    num_features: usize,
+    method: &HvgMethod,
+) -> Result<FeatureScores, io::Error> {
+    let mut stats = FeatureStats::new(num_features);
+    for_each_cell(input, input_type.clone(), num_cells, num_features, |_, bit_vec, exp| {
+        let positions = get_positions(&bit_vec);
+        assert!(positions.len() == exp.len(),
+                "#positions doesn't match with #expressed features");
+
+        stats.add_cell(&positions, &exp);
+        Ok(())
+    })?;
+
+    let means: Vec<f64> = (0..num_features).map(|x| stats.mean(x)).collect();
+    let variances: Vec<f64> = (0..num_features).map(|x| stats.variance(x)).collect();
+
+    info!("Scoring {} features with {:?}", num_features, method);
+    let scores = match method {
+        HvgMethod::Dispersion => dispersion_scores(&means, &variances),
+        HvgMethod::Vst => vst_scores(input, input_type, num_cells, num_features,
+                                     &means, &variances)?,
+    };
+
+    Ok(FeatureScores {
+        means,
+        variances,
+        scores,
+    })
+}
+
+pub fn write_table(
+    path: &str,
+    top: &[usize],
+    scores: &FeatureScores,
+    names: Option<&Vec<String>>,
+) -> Result<(), io::Error> {
+    info!("Writing {} variable features into file path: {}", top.len(), path);
+    let mut file = BufWriter::new(File::create(path)?);
+
+    writeln!(file, "rank\tfeature\tindex\tmean\tvariance\tscore")?;
+    for (rank, feature_id) in top.iter().enumerate() {
+        let name = match names {
+            Some(names) => names[*feature_id].clone(),
+            None => (feature_id + 1).to_string(),
+        };
+        writeln!(file, "{}\t{}\t{}\t{}\t{}\t{}",
+                 rank + 1, name, feature_id + 1,
+                 scores.means[*feature_id], scores.variances[*feature_id],
+                 scores.scores[*feature_id].unwrap())?;
+    }
+
+    file.flush()
+}
+
+// keeps only the selected features, in their input order
+pub fn write_subset(
+    input: &str,
+    input_type: FileType,
+    num_cells: usize,
+    num_features: usize,
+    selected: &[usize],
+    output: String,
+    output_type: FileType,
+) -> Result<(), io::Error> {
+    let mut new_index: Vec<Option<usize>> = vec![None; num_features];
+    let mut kept: Vec<usize> = selected.to_vec();
+    kept.sort();
+    for (index, feature_id) in kept.iter().enumerate() {
+        new_index[*feature_id] = Some(index);
+    }
+
+    let mut writer = CellWriter::new(output, output_type, kept.len(), None)?;
+    for_each_cell(input, input_type, num_cells, num_features, |_, bit_vec, exp| {
+        let (bit_vec, exp) = remap(&bit_vec, exp, &new_index, kept.len());
+        writer.write_cell(bit_vec, exp)
+    })?;
+
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{read_file, write_test_cells};
+
+    #[test]
+    fn solve3_solves_and_rejects_singular_systems() {
+        // 2x + y = 3, x + 3y + z = 5, y + 4z = 5
+        let solution = solve3([[2.0, 1.0, 0.0], [1.0, 3.0, 1.0], [0.0, 1.0, 4.0]], [3.0, 5.0, 5.0]).unwrap();
+        for (actual, expected) in solution.iter().zip([1.0, 1.0, 1.0].iter()) {
+            assert!((actual - expected).abs() < 1e-12, "expected {}, found {}", expected, actual);
+        }
+
+        assert!(solve3([[1.0, 2.0, 3.0], [2.0, 4.0, 6.0], [0.0, 1.0, 1.0]], [1.0, 2.0, 3.0]).is_none());
+    }
+
+    #[test]
+    fn loess_reproduces_a_quadratic() {
+        let x: Vec<f64> = (0..40).map(|i| (i as f64 * 0.37).powf(1.3)).collect();
+        let y: Vec<f64> = x.iter().map(|x| 2.0 - 0.5 * x + 0.25 * x * x).collect();
+
+        for (fitted, expected) in loess(&x, &y, LOESS_SPAN).iter().zip(y.iter()) {
+            assert!((fitted - expected).abs() < 1e-8 * expected.abs().max(1.0),
+                    "expected {}, found {}", expected, fitted);
+        }
+    }
+
+    #[test]
+    fn loess_smooths_around_the_trend() {
+        let x: Vec<f64> = (0..101).map(|i| i as f64).collect();
+        let y: Vec<f64> = x.iter()
+            .enumerate()
+            .map(|(i, x)| x + if i % 2 == 0 { 1.0 } else { -1.0 })
+            .collect();
+
+        // away from the edges the alternating noise averages out
+        let fitted = loess(&x, &y, 0.5);
+        for i in 20..80 {
+            assert!((fitted[i] - x[i]).abs() < 0.1, "expected about {}, found {}", x[i], fitted[i]);
+        }
+    }
+
+    #[test]
+    fn dispersion_scores_skip_unexpressed_features() {
+        let means = [0.0, 1.0, 1.0, 1.0];
+        let variances = [0.0, 1.0, 2.0, 4.0];
+        let scores = dispersion_scores(&means, &variances);
+
+        // one bin, the log dispersions 0, ln 2 and ln 4 z-score to -1, 0 and 1
+        assert!(scores[0].is_none());
+        for (score, expected) in scores[1..].iter().zip([-1.0, 0.0, 1.0].iter()) {
+            assert!((score.unwrap() - expected).abs() < 1e-12);
+        }
+
+        let feature_scores = FeatureScores {
+            means: means.to_vec(),
+            variances: variances.to_vec(),
+            scores,
+        };
+        assert_eq!(feature_scores.top(2), vec![3, 2]);
+    }
+
+    // writes 4 cells of 12 features, the first feature is 4, 0, 0, 0 and
+    // the last is 3 in every cell
+    fn write_cells(name: &str) -> String {
+        let cells: Vec<(Vec<usize>, Vec<f32>)> = vec![
+            (vec![0, 5, 11], vec![4.0, 1.0, 3.0]),
+            (vec![2, 11], vec![2.0, 3.0]),
+            (vec![11], vec![3.0]),
+            (vec![5, 11], vec![6.0, 3.0]),
+        ];
+        write_test_cells(&format!("hvg-{}", name), FileType::EDS, 12, &cells)
+    }
+
+    #[test]
+    fn vst_clips_and_adds_the_zeros() {
+        // log variance linear in log mean, the trend is the variance itself
+        let mut means: Vec<f64> = (0..11).map(|i| 1.5f64.powi(i)).collect();
+        let mut variances = means.clone();
+        means.push(3.0);
+        variances.push(0.0);
+
+        let path = write_cells("vst");
+        let scores = vst_scores(&path, FileType::EDS, 4, 12, &means, &variances);
+        std::fs::remove_file(&path).unwrap();
+        let scores = scores.unwrap();
+
+        // mean 1 and sd 1: the 4 clips at sqrt(4) = 2 for 4 and the three
+        // zeros add 1 each, over 4 - 1 cells
+        assert!((scores[0].unwrap() - 7.0 / 3.0).abs() < 1e-9, "found {:?}", scores[0]);
+        assert!(scores[11].is_none());
+    }
+
+    #[test]
+    fn subset_keeps_the_input_order() {
+        let path = write_cells("subset");
+        let output = path.replace(".eds.gz", ".subset.eds.gz");
+        write_subset(&path, FileType::EDS, 4, 12, &[11, 0, 5], output.clone(), FileType::EDS).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let (bit_vecs, values) = read_file(&output, FileType::EDS, 4, 3).unwrap();
+        std::fs::remove_file(&output).unwrap();
+
+        let positions: Vec<Vec<usize>> = bit_vecs.iter().map(|x| get_positions(x)).collect();
+        assert_eq!(positions, vec![vec![0, 1, 2], vec![2], vec![2], vec![1, 2]]);
+        assert_eq!(values, vec![vec![4.0, 1.0, 3.0], vec![3.0], vec![3.0], vec![6.0, 3.0]]);
+    }
+}
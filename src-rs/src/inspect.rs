@@ -0,0 +1,213 @@
+use std::fs;
+use std::io;
+
+use math::round;
+
+use crate::utils::{for_each_cell, json_number, FileType};
+
+pub struct Summary {
+    pub file_type: FileType,
+    pub num_cells: usize,
+    pub num_features: usize,
+    pub num_entries: usize,
+    pub num_empty_cells: usize,
+    pub integer_values: bool,
+    // over the whole matrix, the implicit zeros included
+    pub min_value: f32,
+    pub max_value: f32,
+    pub sum_values: f64,
+    pub file_size: u64,
+    pub payload_size: u64,
+}
+
+impl Summary {
+    pub fn density(&self) -> f64 {
+        match self.num_cells * self.num_features {
+            0 => 0.0,
+            total => self.num_entries as f64 / total as f64,
+        }
+    }
+
+    pub fn value_type(&self) -> &str {
+        match self.integer_values {
+            true => "integer (f32)",
+            false => "real (f32)",
+        }
+    }
+
+    // compression is only defined w.r.t. the EDS payload
+    pub fn compression_ratio(&self) -> Option<f64> {
+        match (&self.file_type, self.file_size) {
+            (FileType::EDS, size) if size > 0 => Some(self.payload_size as f64 / size as f64),
+            _ => None,
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        text.push_str(&format!("format\t{:?}\n", self.file_type));
+        text.push_str("version\tnone (headerless)\n");
+        text.push_str(&format!("cells\t{}\n", self.num_cells));
+        text.push_str(&format!("features\t{}\n", self.num_features));
+        text.push_str(&format!("nnz\t{}\n", self.num_entries));
+        text.push_str(&format!("density\t{:.6}\n", self.density()));
+        text.push_str(&format!("value_type\t{}\n", self.value_type()));
+        text.push_str(&format!("min\t{}\n", self.min_value));
+        text.push_str(&format!("max\t{}\n", self.max_value));
+        text.push_str(&format!("sum\t{}\n", self.sum_values));
+        text.push_str(&format!("empty_cells\t{}\n", self.num_empty_cells));
+        text.push_str(&format!("file_size\t{}\n", self.file_size));
+        if let Some(ratio) = self.compression_ratio() {
+            text.push_str(&format!("uncompressed_size\t{}\n", self.payload_size));
+            text.push_str(&format!("compression_ratio\t{:.2}\n", ratio));
+        }
+
+        text
+    }
+
+    pub fn to_json(&self) -> String {
+        let (payload, ratio) = match self.compression_ratio() {
+            Some(ratio) => (self.payload_size.to_string(), format!("{:.4}", ratio)),
+            None => ("null".to_string(), "null".to_string()),
+        };
+
+        let mut json = "{\n".to_string();
+        json.push_str(&format!("  \"format\": \"{:?}\",\n", self.file_type));
+        json.push_str("  \"version\": null,\n");
+        json.push_str(&format!("  \"cells\": {},\n", self.num_cells));
+        json.push_str(&format!("  \"features\": {},\n", self.num_features));
+        json.push_str(&format!("  \"nnz\": {},\n", self.num_entries));
+        json.push_str(&format!("  \"density\": {},\n", json_number(self.density())));
+        json.push_str(&format!("  \"value_type\": \"{}\",\n", self.value_type()));
+        json.push_str(&format!("  \"min\": {},\n", json_number(self.min_value)));
+        json.push_str(&format!("  \"max\": {},\n", json_number(self.max_value)));
+        json.push_str(&format!("  \"sum\": {},\n", json_number(self.sum_values)));
+        json.push_str(&format!("  \"empty_cells\": {},\n", self.num_empty_cells));
+        json.push_str(&format!("  \"file_size\": {},\n", self.file_size));
+        json.push_str(&format!("  \"uncompressed_size\": {},\n", payload));
+        json.push_str(&format!("  \"compression_ratio\": {}\n", ratio));
+        json.push_str("}\n");
+
+        json
+    }
+}
+
+pub fn summarize(
+    input: &str,
+    input_type: FileType,
+    num_cells: usize,
+    num_features: usize,
+) -> Result<Summary, io::Error> {
+    let num_bit_vecs: usize = round::ceil(num_features as f64 / 8.0, 0) as usize;
+    let mut summary = Summary {
+        file_type: input_type.clone(),
+        num_cells,
+        num_features,
+        num_entries: 0,
+        num_empty_cells: 0,
+        integer_values: true,
+        min_value: 0.0,
+        max_value: 0.0,
+        sum_values: 0.0,
+        file_size: fs::metadata(input)?.len(),
+        payload_size: 0,
+    };
+
+    let mut found_value = false;
+    for_each_cell(input, input_type, num_cells, num_features, |_, _, exp| {
+        if exp.is_empty() {
+            summary.num_empty_cells += 1;
+        }
+
+        for value in exp.iter() {
+            if !found_value {
+                found_value = true;
+                summary.min_value = *value;
+                summary.max_value = *value;
+            }
+
+            summary.min_value = summary.min_value.min(*value);
+            summary.max_value = summary.max_value.max(*value);
+            summary.sum_values += *value as f64;
+            summary.integer_values &= value.fract() == 0.0;
+        }
+
+        summary.num_entries += exp.len();
+        Ok(())
+    })?;
+
+    if summary.num_entries < num_cells * num_features {
+        summary.min_value = summary.min_value.min(0.0);
+        summary.max_value = summary.max_value.max(0.0);
+    }
+
+    summary.payload_size = (num_cells * num_bit_vecs + 4 * summary.num_entries) as u64;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::utils::write_test_cells;
+
+    fn summarize_cells(name: &str, file_type: FileType, num_features: usize,
+                       cells: &[(Vec<usize>, Vec<f32>)]) -> Summary {
+        let path = write_test_cells(&format!("inspect-{}", name), file_type.clone(), num_features, cells);
+        let summary = summarize(&path, file_type, cells.len(), num_features);
+        fs::remove_file(&path).unwrap();
+        summary.unwrap()
+    }
+
+    #[test]
+    fn summary_of_a_sparse_matrix() {
+        let cells = vec![
+            (vec![0, 9], vec![2.0, 5.0]),
+            (vec![], vec![]),
+            (vec![3], vec![3.0]),
+        ];
+        let summary = summarize_cells("sparse", FileType::EDS, 10, &cells);
+
+        assert_eq!(summary.num_entries, 3);
+        assert_eq!(summary.density(), 0.1);
+        assert_eq!(summary.num_empty_cells, 1);
+        assert_eq!(summary.value_type(), "integer (f32)");
+        // the implicit zeros are the smallest values
+        assert_eq!((summary.min_value, summary.max_value), (0.0, 5.0));
+        assert_eq!(summary.sum_values, 10.0);
+
+        // 2 bytes of bit vector per cell and 4 per value
+        assert_eq!(summary.payload_size, 18);
+        let ratio = summary.compression_ratio().unwrap();
+        assert_eq!(ratio, 18.0 / summary.file_size as f64);
+
+        let text = summary.to_text();
+        assert!(text.contains("nnz\t3\n"));
+        assert!(text.contains("min\t0\nmax\t5\n"));
+        assert!(text.contains(&format!("compression_ratio\t{:.2}\n", ratio)));
+
+        let json = summary.to_json();
+        assert!(json.starts_with("{\n  \"format\": \"EDS\",\n  \"version\": null,\n"));
+        assert!(json.contains("  \"nnz\": 3,\n  \"density\": 0.1,\n"));
+        assert!(json.contains("  \"min\": 0,\n  \"max\": 5,\n  \"sum\": 10,\n  \"empty_cells\": 1,\n"));
+        assert!(json.contains("  \"uncompressed_size\": 18,\n"));
+        assert!(json.ends_with(&format!("  \"compression_ratio\": {:.4}\n}}\n", ratio)));
+    }
+
+    #[test]
+    fn summary_of_a_dense_matrix() {
+        let cells = vec![(vec![0, 1], vec![1.5, 2.5])];
+        let summary = summarize_cells("dense", FileType::CSV, 2, &cells);
+
+        assert_eq!(summary.density(), 1.0);
+        assert_eq!(summary.num_empty_cells, 0);
+        assert_eq!(summary.value_type(), "real (f32)");
+        assert_eq!((summary.min_value, summary.max_value), (1.5, 2.5));
+
+        // only EDS has a compression ratio
+        assert_eq!(summary.compression_ratio(), None);
+        assert!(!summary.to_text().contains("compression_ratio"));
+        let json = summary.to_json();
+        assert!(json.contains("  \"uncompressed_size\": null,\n  \"compression_ratio\": null\n"));
+    }
+}
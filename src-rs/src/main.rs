@@ -1,6 +1,7 @@
 extern crate byteorder;
 extern crate clap;
 extern crate flate2;
+#[cfg(feature = "h5")]
 extern crate hdf5;
 extern crate math;
 extern crate pretty_env_logger;
@@ -8,43 +9,82 @@ extern crate pretty_env_logger;
 #[macro_use]
 extern crate log;
 
+mod ambient;
+mod callcells;
 mod csv;
+mod de;
+mod diff;
+mod downsample;
 mod eds;
+mod features;
+mod filter;
+mod groups;
+#[cfg(feature = "h5")]
 mod h5;
+mod hash;
+mod hvg;
+mod inspect;
 mod mtx;
+mod normalize;
+mod pca;
+mod sample;
+mod sampling;
+mod score;
+mod sort;
+mod stats;
+mod transpose;
 mod utils;
+mod validate;
 
 use clap::{App, Arg, ArgMatches, SubCommand};
+use std::collections::HashSet;
+use std::fs;
 use std::io;
+use std::process;
+use std::str::FromStr;
 use utils::FileType;
 
 fn randomize_file(sub_m: &ArgMatches) -> Result<(), io::Error> {
     let input_file_path = sub_m.value_of("input").unwrap();
-    let output_file_type = FileType::Dummy(".random".to_string());
+    let (input_file_type, _) =
+        utils::get_output_path(input_file_path, FileType::Dummy("".to_string()));
 
-    let (input_file_type, output_file_path) =
-        utils::get_output_path(input_file_path, output_file_type.clone());
-
-    let num_cells: usize = sub_m
-        .value_of("cells")
-        .expect("can't find #cells")
-        .parse()
-        .unwrap();
+    // the output keeps the input format, and its extension
+    let output_suffix = match sub_m.is_present("unshuffle") {
+        true => ".unshuffled".to_string(),
+        false => ".random".to_string(),
+    } + &utils::get_extension(&input_file_type);
+    let (_, output_file_path) =
+        utils::get_output_path(input_file_path, FileType::Dummy(output_suffix));
 
-    let num_features = sub_m
-        .value_of("features")
-        .expect("can't find #features")
-        .parse()
-        .unwrap();
+    let (num_cells, num_features) = parse_dims(sub_m)?;
 
     let (bit_vecs, alphas) = utils::read_file(input_file_path,
                                               input_file_type.clone(),
                                               num_cells,
                                               num_features)?;
 
-    let (bit_vecs, alphas) = utils::randomize( bit_vecs, alphas )?;
-    utils::write_file( output_file_path, input_file_type,
-                       bit_vecs, alphas, num_cells, num_features)?;
+    let order = match sub_m.value_of("unshuffle") {
+        Some(path) => {
+            let order = utils::read_permutation(path, num_cells)?;
+            if order.len() != num_cells {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} covers {} out of {} cells", path, order.len(), num_cells),
+                ));
+            }
+            utils::invert_permutation(&order)
+        }
+        None => {
+            let mut rng = utils::get_rng(parse_arg(sub_m, "seed")?);
+            let order = utils::randomize(num_cells, &mut rng);
+            utils::write_permutation(&(output_file_path.clone() + ".perm.txt"), &order)?;
+            order
+        }
+    };
+
+    utils::write_permuted( output_file_path, input_file_type,
+                           bit_vecs, alphas, &order, num_features)?;
 
     info!("All Done!");
     Ok(())
@@ -54,122 +94,1903 @@ fn convert_file(sub_m: &ArgMatches) -> Result<(), io::Error> {
     let input_file_path = sub_m.value_of("input").unwrap();
     let output_file_type = utils::find_output_format(sub_m);
 
+    let normalization = match sub_m.value_of("normalize") {
+        Some(value) => Some(normalize::parse_normalization(value)?),
+        None => None,
+    };
+
+    let cell_order = match sub_m.value_of("reorder") {
+        Some("gray") => Some(eds::CellOrder::Gray),
+        Some(_) => Some(eds::CellOrder::MinHash),
+        None => None,
+    };
+
+    // normalized or reordered output can have the input format, keep it apart
+    let mut output_suffix = "".to_string();
+    if normalization.is_some() {
+        output_suffix.push_str(".norm");
+    }
+    if cell_order.is_some() {
+        output_suffix.push_str(".reordered");
+    }
+
+    let (input_file_type, output_file_path) = match output_suffix.is_empty() {
+        false => {
+            output_suffix.push_str(&utils::get_extension(&output_file_type));
+            utils::get_output_path(input_file_path, FileType::Dummy(output_suffix))
+        }
+        true => utils::get_output_path(input_file_path, output_file_type.clone()),
+    };
+
+    let (num_cells, num_features) = parse_dims(sub_m)?;
+
+    let cell_by_gene = parse_cell_by_gene(sub_m, &input_file_type)?;
+
+    // normalization streams over the input, unless the cells are reordered
+    // or come from a feature-major mtx which are both read into memory
+    if let (Some(method), None, true) = (&normalization, &cell_order, cell_by_gene) {
+        let normalizer = normalize::CellNormalizer::new(
+            input_file_path, input_file_type.clone(), num_cells, num_features, method.clone())?;
+
+        // the values change but not the entries, mtx and h5 are sized from the input
+        let size = match output_file_type {
+            FileType::MTX | FileType::H5 => {
+                let mut num_entries = 0;
+                utils::for_each_cell(input_file_path, input_file_type.clone(), num_cells, num_features,
+                                     |_, _, exp| {
+                    num_entries += exp.len();
+                    Ok(())
+                })?;
+                Some((num_cells, num_entries))
+            }
+            _ => None,
+        };
+
+        let mut writer = utils::CellWriter::new(output_file_path, output_file_type, num_features, size)?;
+        utils::for_each_cell(input_file_path, input_file_type, num_cells, num_features,
+                             |_, bit_vec, mut exp| {
+            normalizer.apply(&mut exp);
+            writer.write_cell(bit_vec, exp)
+        })?;
+        writer.finish()?;
+
+        info!("All Done!");
+        return Ok(());
+    }
+
+    let (bit_vecs, mut alphas) = utils::read_file_oriented(input_file_path,
+                                                           input_file_type,
+                                                           num_cells,
+                                                           num_features,
+                                                           cell_by_gene)?;
+
+    if let Some(method) = normalization {
+        let totals: Vec<f64> = alphas.iter().map(|exp| utils::cell_total(exp)).collect();
+        let normalizer = normalize::CellNormalizer::from_totals(method, &totals);
+        for exp in alphas.iter_mut() {
+            normalizer.apply(exp);
+        }
+    }
+
+    if let Some(method) = cell_order {
+        // compressed size in the input order, what the reordering is up against
+        let mut unordered = eds::Writer::from_sink(eds::CountingSink::default());
+        for (bit_vec, exp) in bit_vecs.iter().zip(alphas.iter()) {
+            unordered.write_cell(bit_vec, exp)?;
+        }
+        let unordered_bytes = unordered.finish()?.bytes;
+
+        let order = eds::reorder_cells(&bit_vecs, &method);
+        utils::write_permutation(&(output_file_path.clone() + ".perm.txt"), &order)?;
+        utils::write_permuted(output_file_path.clone(), output_file_type,
+                              bit_vecs, alphas, &order, num_features)?;
+
+        let reordered_bytes = fs::metadata(&output_file_path)?.len();
+        info!("Wrote {} bytes with the cells reordered, {} bytes in the input order, saving {} bytes",
+              reordered_bytes, unordered_bytes, unordered_bytes as i64 - reordered_bytes as i64);
+        info!("All Done!");
+        return Ok(());
+    }
+
+    utils::write_file( output_file_path, output_file_type,
+                       bit_vecs, alphas, num_cells, num_features)?;
+
+    info!("All Done!");
+    Ok(())
+}
+
+fn align_features(sub_m: &ArgMatches) -> Result<(), io::Error> {
+    let input_file_path = sub_m.value_of("input").unwrap();
+    let output_file_type = utils::find_output_format_or(sub_m, FileType::EDS);
+    let output_suffix = ".aligned".to_string() + &utils::get_extension(&output_file_type);
+
     let (input_file_type, output_file_path) =
-        utils::get_output_path(input_file_path, output_file_type.clone());
+        utils::get_output_path(input_file_path, FileType::Dummy(output_suffix));
 
-    let num_cells: usize = sub_m
-        .value_of("cells")
-        .expect("can't find #cells")
-        .parse()
-        .unwrap();
+    let (num_cells, num_features) = parse_dims(sub_m)?;
 
-    let num_features = sub_m
-        .value_of("features")
-        .expect("can't find #features")
-        .parse()
-        .unwrap();
+    let names = read_optional_names(sub_m, "names", num_features)?.unwrap();
+    let reference = utils::read_names(sub_m.value_of("reference").unwrap())?;
 
     let (bit_vecs, alphas) = utils::read_file(input_file_path,
                                               input_file_type,
                                               num_cells,
                                               num_features)?;
 
+    let (bit_vecs, alphas) = features::align(bit_vecs, alphas, &names, &reference)?;
     utils::write_file( output_file_path, output_file_type,
-                       bit_vecs, alphas, num_cells, num_features)?;
+                       bit_vecs, alphas, num_cells, reference.len())?;
+
+    info!("All Done!");
+    Ok(())
+}
+
+fn split_file(sub_m: &ArgMatches) -> Result<(), io::Error> {
+    let input_file_path = sub_m.value_of("input").unwrap();
+    let output_file_type = utils::find_output_format_or(sub_m, FileType::EDS);
+
+    let (input_file_type, output_prefix) =
+        utils::get_output_path(input_file_path, FileType::Dummy("".to_string()));
+
+    let (num_cells, num_features) = parse_dims(sub_m)?;
+
+    let barcodes = utils::read_names(sub_m.value_of("barcodes").unwrap())?;
+    if barcodes.len() != num_cells {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("found {} barcodes for {} cells", barcodes.len(), num_cells),
+        ));
+    }
+    let groups = groups::read_groups(sub_m.value_of("groups").unwrap())?;
+
+    groups::split(input_file_path, input_file_type, num_features,
+                  &barcodes, &groups, &output_prefix, output_file_type)?;
+
+    info!("All Done!");
+    Ok(())
+}
+
+fn transpose_file(sub_m: &ArgMatches) -> Result<(), io::Error> {
+    let input_file_path = sub_m.value_of("input").unwrap();
+    let output_file_type = utils::find_output_format_or(sub_m, FileType::EDS);
+    let output_suffix = ".transposed".to_string() + &utils::get_extension(&output_file_type);
+
+    let (input_file_type, output_file_path) =
+        utils::get_output_path(input_file_path, FileType::Dummy(output_suffix));
+
+    let (num_cells, num_features) = parse_dims(sub_m)?;
+
+    let max_entries: usize = parse_required_arg(sub_m, "max-entries")?;
+
+    let source = transpose::Source {
+        path: input_file_path,
+        cell_by_gene: parse_cell_by_gene(sub_m, &input_file_type)?,
+        file_type: input_file_type,
+        num_cells,
+        num_features,
+    };
+    transpose::transpose(&source, output_file_path, output_file_type, max_entries)?;
+
+    info!("All Done!");
+    Ok(())
+}
+
+fn inspect_file(sub_m: &ArgMatches) -> Result<(), io::Error> {
+    let input_file_path = sub_m.value_of("input").unwrap();
+    let (input_file_type, _) =
+        utils::get_output_path(input_file_path, FileType::Dummy("".to_string()));
+
+    let (num_cells, num_features) = parse_dims(sub_m)?;
+
+    let summary = inspect::summarize(input_file_path, input_file_type,
+                                     num_cells, num_features)?;
+
+    match sub_m.is_present("json") {
+        true => print!("{}", summary.to_json()),
+        false => print!("{}", summary.to_text()),
+    };
+
+    Ok(())
+}
+
+fn validate_file(sub_m: &ArgMatches) -> Result<(), io::Error> {
+    let input_file_path = sub_m.value_of("input").unwrap();
+    let (input_file_type, _) =
+        utils::get_output_path(input_file_path, FileType::Dummy("".to_string()));
+    if input_file_type != FileType::EDS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("validate only supports EDS input, found {:?}", input_file_type),
+        ));
+    }
+
+    let (num_cells, num_features) = parse_dims(sub_m)?;
+
+    let max_errors: usize = parse_required_arg(sub_m, "max-errors")?;
+
+    let report = validate::validate(input_file_path, num_cells, num_features, max_errors)?;
+    for message in report.messages.iter() {
+        println!("{}", message);
+    }
+
+    if !report.is_valid() {
+        println!("INVALID: {} error(s), {} of {} cells read",
+                 report.num_errors, report.num_cells_read, num_cells);
+        process::exit(1);
+    }
+
+    println!("OK: {} cells, {} features", num_cells, num_features);
+    Ok(())
+}
+
+fn stats_file(sub_m: &ArgMatches) -> Result<(), io::Error> {
+    let input_file_path = sub_m.value_of("input").unwrap();
+    let (input_file_type, output_prefix) =
+        utils::get_output_path(input_file_path, FileType::Dummy("".to_string()));
+
+    let (num_cells, num_features) = parse_dims(sub_m)?;
+
+    let barcodes = read_optional_names(sub_m, "barcodes", num_cells)?;
+    let names = read_optional_names(sub_m, "names", num_features)?;
+
+    let (cell_stats, feature_stats) = stats::collect(input_file_path, input_file_type,
+                                                     num_cells, num_features)?;
+
+    match sub_m.value_of("format").unwrap() {
+        "json" => stats::write_json(&(output_prefix.clone() + ".cell_stats.json"),
+                                    &(output_prefix + ".feature_stats.json"),
+                                    &cell_stats, &feature_stats,
+                                    barcodes.as_ref(), names.as_ref())?,
+        _ => stats::write_tsv(&(output_prefix.clone() + ".cell_stats.tsv"),
+                              &(output_prefix + ".feature_stats.tsv"),
+                              &cell_stats, &feature_stats,
+                              barcodes.as_ref(), names.as_ref())?,
+    };
+
+    info!("All Done!");
+    Ok(())
+}
+
+fn parse_arg<T: FromStr>(sub_m: &ArgMatches, name: &str) -> Result<Option<T>, io::Error> {
+    match sub_m.value_of(name) {
+        Some(value) => value.parse().map(Some).map_err(|_| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("can't parse --{} from {}", name, value),
+        )),
+        None => Ok(None),
+    }
+}
+
+// for the arguments with a default or required by clap
+fn parse_required_arg<T: FromStr>(sub_m: &ArgMatches, name: &str) -> Result<T, io::Error> {
+    parse_arg(sub_m, name)?.ok_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("can't find --{}", name),
+    ))
+}
+
+fn read_optional_names(sub_m: &ArgMatches, name: &str, expected: usize)
+                       -> Result<Option<Vec<String>>, io::Error> {
+    let names = match sub_m.value_of(name) {
+        Some(path) => utils::read_names(path)?,
+        None => return Ok(None),
+    };
+
+    if names.len() != expected {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("found {} {} for {} entries", names.len(), name, expected),
+        ));
+    }
+    Ok(Some(names))
+}
+
+fn filter_file(sub_m: &ArgMatches) -> Result<(), io::Error> {
+    let input_file_path = sub_m.value_of("input").unwrap();
+    let output_file_type = utils::find_output_format_or(sub_m, FileType::EDS);
+    let (input_file_type, output_prefix) =
+        utils::get_output_path(input_file_path, FileType::Dummy(".filtered".to_string()));
+
+    let (num_cells, num_features) = parse_dims(sub_m)?;
+
+    let barcodes = read_optional_names(sub_m, "barcodes", num_cells)?;
+    let names = read_optional_names(sub_m, "names", num_features)?;
+
+    let whitelisted = match sub_m.value_of("whitelist") {
+        Some(path) => {
+            let barcodes = barcodes.as_ref().ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "whitelist filtering needs the cell barcodes",
+            ))?;
+            let whitelist: HashSet<String> = utils::read_names(path)?.into_iter().collect();
+            Some(barcodes.iter().map(|barcode| whitelist.contains(barcode)).collect())
+        }
+        None => None,
+    };
+
+    let mito = match sub_m.value_of("max-mito") {
+        Some(_) => {
+            let names = names.as_ref().ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "mito filtering needs the feature names",
+            ))?;
+            let regex = sub_m.value_of("mito-regex");
+            let prefix = match (sub_m.value_of("mito-prefix"), regex) {
+                (None, None) => Some("MT-"),
+                (prefix, _) => prefix,
+            };
+
+            let mask = filter::mito_features(names, prefix, regex)?;
+            Some((mask, parse_required_arg(sub_m, "max-mito")?))
+        }
+        None => None,
+    };
+
+    let cells = filter::CellFilter {
+        min_counts: parse_arg(sub_m, "min-counts")?,
+        max_counts: parse_arg(sub_m, "max-counts")?,
+        min_features: parse_arg(sub_m, "min-features")?,
+        max_features: parse_arg(sub_m, "max-features")?,
+        whitelisted,
+        mito,
+    };
+
+    let features = filter::FeatureFilter {
+        min_cells: parse_arg(sub_m, "min-cells")?,
+        min_counts: parse_arg(sub_m, "min-feature-counts")?,
+    };
+    let rules = filter::Rules { cells, features };
+
+    let output_file_path = output_prefix.clone() + &utils::get_extension(&output_file_type);
+    let result = filter::filter(
+        input_file_path, input_file_type, num_cells, num_features,
+        &rules, output_file_path, output_file_type)?;
+
+    filter::write_report(&(output_prefix.clone() + ".report.tsv"),
+                         num_cells, num_features, &result)?;
+
+    if let Some(barcodes) = barcodes {
+        let kept_barcodes: Vec<String> = result.kept_cells.iter()
+            .map(|cell_id| barcodes[*cell_id].clone())
+            .collect();
+        utils::write_names(&(output_prefix.clone() + ".barcodes.txt"), &kept_barcodes)?;
+    }
+
+    if let (Some(names), true) = (names, rules.features.is_active()) {
+        let kept_names: Vec<String> = result.kept_features.iter()
+            .map(|feature_id| names[*feature_id].clone())
+            .collect();
+        utils::write_names(&(output_prefix + ".features.txt"), &kept_names)?;
+    }
+
+    info!("All Done!");
+    Ok(())
+}
+
+fn downsample_file(sub_m: &ArgMatches) -> Result<(), io::Error> {
+    let input_file_path = sub_m.value_of("input").unwrap();
+    let output_file_type = utils::find_output_format_or(sub_m, FileType::EDS);
+    let output_suffix = ".downsampled".to_string() + &utils::get_extension(&output_file_type);
+
+    let (input_file_type, output_file_path) =
+        utils::get_output_path(input_file_path, FileType::Dummy(output_suffix));
+
+    let (num_cells, num_features) = parse_dims(sub_m)?;
+
+    let depth = match parse_arg(sub_m, "target")? {
+        Some(target) => downsample::Depth::Total(target),
+        None => {
+            let fraction: f64 = parse_required_arg(sub_m, "fraction")?;
+            if !(0.0..=1.0).contains(&fraction) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("fraction {} is not in [0, 1]", fraction),
+                ));
+            }
+            downsample::Depth::Fraction(fraction)
+        }
+    };
+
+    let mut rng = utils::get_rng(parse_arg(sub_m, "seed")?);
+    let mut writer = utils::CellWriter::new(output_file_path, output_file_type, num_features, None)?;
+    downsample::downsample(input_file_path, input_file_type, num_cells, num_features,
+                           &depth, &mut rng, &mut writer)?;
+    writer.finish()?;
+
+    info!("All Done!");
+    Ok(())
+}
+
+fn sample_file(sub_m: &ArgMatches) -> Result<(), io::Error> {
+    let input_file_path = sub_m.value_of("input").unwrap();
+    let output_file_type = utils::find_output_format_or(sub_m, FileType::EDS);
+    let (input_file_type, output_prefix) =
+        utils::get_output_path(input_file_path, FileType::Dummy("".to_string()));
+
+    let (num_cells, num_features) = parse_dims(sub_m)?;
+
+    let size = match parse_arg(sub_m, "size")? {
+        Some(count) => sample::SampleSize::Count(count),
+        None => {
+            let fraction: f64 = parse_required_arg(sub_m, "fraction")?;
+            if !(fraction > 0.0 && fraction <= 1.0) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("fraction {} is not in (0, 1]", fraction),
+                ));
+            }
+            sample::SampleSize::Fraction(fraction)
+        }
+    };
+
+    let barcodes = read_optional_names(sub_m, "barcodes", num_cells)?;
+    let strata = match sub_m.value_of("groups") {
+        Some(path) => {
+            let groups = groups::read_groups(path)?;
+            let barcodes = barcodes.as_ref().ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "stratification needs the cell barcodes",
+            ))?;
+            Some(groups::assign(barcodes, &groups).0)
+        }
+        None => None,
+    };
+
+    let mut rng = utils::get_rng(parse_arg(sub_m, "seed")?);
+    let sampled = sample::sample_cells(num_cells, &size,
+                                       strata.as_deref(), &mut rng);
+
+    let mut outputs: Vec<(String, Vec<usize>)> = Vec::new();
+    match sub_m.is_present("split") {
+        true => {
+            let mut is_sampled: Vec<bool> = vec![false; num_cells];
+            sampled.iter().for_each(|cell_id| is_sampled[*cell_id] = true);
+            let rest: Vec<usize> = (0..num_cells).filter(|x| !is_sampled[*x]).collect();
+
+            outputs.push((output_prefix.clone() + ".train", sampled));
+            outputs.push((output_prefix + ".test", rest));
+        }
+        false => outputs.push((output_prefix + ".sample", sampled)),
+    };
+
+    let mut writers: Vec<utils::CellWriter> = Vec::with_capacity(outputs.len());
+    for (prefix, _) in outputs.iter() {
+        let path = prefix.clone() + &utils::get_extension(&output_file_type);
+        writers.push(utils::CellWriter::new(path, output_file_type.clone(), num_features, None)?);
+    }
+
+    let (writer, rest_writer) = writers.split_first_mut().unwrap();
+    sample::write_sample(input_file_path, input_file_type, num_cells, num_features,
+                         &outputs[0].1, writer, rest_writer.first_mut())?;
+    for writer in writers {
+        writer.finish()?;
+    }
+
+    for (prefix, cells) in outputs.iter() {
+        utils::write_permutation(&(prefix.clone() + ".cells.txt"), cells)?;
+        if let Some(barcodes) = barcodes.as_ref() {
+            let sampled_barcodes: Vec<String> = cells.iter()
+                .map(|cell_id| barcodes[*cell_id].clone())
+                .collect();
+            utils::write_names(&(prefix.clone() + ".barcodes.txt"), &sampled_barcodes)?;
+        }
+    }
+
+    info!("All Done!");
+    Ok(())
+}
+
+fn sort_file(sub_m: &ArgMatches) -> Result<(), io::Error> {
+    let input_file_path = sub_m.value_of("input").unwrap();
+    let (input_file_type, output_prefix) =
+        utils::get_output_path(input_file_path, FileType::Dummy(".sorted".to_string()));
+    let output_file_type = utils::find_output_format_or(sub_m, input_file_type.clone());
+    let output_file_path = output_prefix + &utils::get_extension(&output_file_type);
+
+    let (num_cells, num_features) = parse_dims(sub_m)?;
+
+    let barcodes = read_optional_names(sub_m, "barcodes", num_cells)?;
+    let key = match sub_m.value_of("by").unwrap() {
+        "counts" => sort::SortKey::Counts,
+        "features" => sort::SortKey::Features,
+        "barcode" => sort::SortKey::Barcode,
+        _ => {
+            let path = sub_m.value_of("key").ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "sorting by table needs --key",
+            ))?;
+            sort::SortKey::Table(groups::read_groups(path)?)
+        }
+    };
+
+    let (bit_vecs, alphas) = utils::read_file(input_file_path,
+                                              input_file_type,
+                                              num_cells,
+                                              num_features)?;
+
+    let order = sort::sort_order(&alphas, barcodes.as_ref(), &key,
+                                 sub_m.is_present("descending"));
+    utils::write_permutation(&(output_file_path.clone() + ".perm.txt"), &order)?;
+    utils::write_permuted(output_file_path, output_file_type,
+                          bit_vecs, alphas, &order, num_features)?;
+
+    info!("All Done!");
+    Ok(())
+}
+
+fn diff_file(sub_m: &ArgMatches) -> Result<(), io::Error> {
+    let input_file_path = sub_m.value_of("input").unwrap();
+    let (input_file_type, output_prefix) =
+        utils::get_output_path(input_file_path, FileType::Dummy("".to_string()));
+
+    let other_file_path = sub_m.value_of("other").unwrap();
+    let (other_file_type, _) =
+        utils::get_output_path(other_file_path, FileType::Dummy("".to_string()));
+
+    let (num_cells, num_features) = parse_dims(sub_m)?;
+
+    // the other file has the same dimensions unless told otherwise
+    let other_cells: usize = parse_arg(sub_m, "other-cells")?.unwrap_or(num_cells);
+    let other_features: usize = parse_arg(sub_m, "other-features")?.unwrap_or(num_features);
+
+    let tolerance = diff::Tolerance {
+        absolute: parse_required_arg(sub_m, "tolerance")?,
+        relative: parse_required_arg(sub_m, "rel-tolerance")?,
+    };
+
+    let result = diff::diff(
+        (input_file_path, input_file_type, num_cells, num_features),
+        (other_file_path, other_file_type, other_cells, other_features),
+        &tolerance,
+    )?;
+
+    if sub_m.is_present("report") {
+        diff::write_report(
+            &(output_prefix.clone() + ".diff.cells.tsv"),
+            &(output_prefix + ".diff.features.tsv"),
+            &result,
+        )?;
+    }
+
+    print!("{}", result.to_text());
+    if !result.is_match() {
+        println!("MISMATCH");
+        process::exit(1);
+    }
+
+    println!("MATCH");
+    Ok(())
+}
+
+fn hash_file(sub_m: &ArgMatches) -> Result<(), io::Error> {
+    let input_file_path = sub_m.value_of("input").unwrap();
+    let (input_file_type, _) =
+        utils::get_output_path(input_file_path, FileType::Dummy("".to_string()));
+
+    let (num_cells, num_features) = parse_dims(sub_m)?;
+
+    let barcodes = read_optional_names(sub_m, "barcodes", num_cells)?;
+    let names = read_optional_names(sub_m, "names", num_features)?;
+
+    let digest = hash::content_hash(input_file_path, input_file_type,
+                                    num_cells, num_features,
+                                    barcodes.as_ref(), names.as_ref())?;
+
+    println!("{}  {}", digest, input_file_path);
+    Ok(())
+}
+
+fn hvg_file(sub_m: &ArgMatches) -> Result<(), io::Error> {
+    let input_file_path = sub_m.value_of("input").unwrap();
+    let output_file_type = utils::find_output_format_or(sub_m, FileType::EDS);
+    let (input_file_type, output_prefix) =
+        utils::get_output_path(input_file_path, FileType::Dummy(".hvg".to_string()));
+
+    let (num_cells, num_features) = parse_dims(sub_m)?;
+
+    let names = read_optional_names(sub_m, "names", num_features)?;
+    let method = hvg::parse_method(sub_m.value_of("method").unwrap())?;
+    let num_top: usize = parse_required_arg(sub_m, "top")?;
+
+    let scores = hvg::score_features(input_file_path, input_file_type.clone(),
+                                     num_cells, num_features, &method)?;
+    let top = scores.top(num_top);
+    if top.len() < num_top {
+        warn!("Only {} features could be scored, asked for {}", top.len(), num_top);
+    }
+    hvg::write_table(&(output_prefix.clone() + ".tsv"), &top, &scores, names.as_ref())?;
+
+    if sub_m.is_present("subset") {
+        let output_file_path = output_prefix.clone() + &utils::get_extension(&output_file_type);
+        hvg::write_subset(input_file_path, input_file_type, num_cells, num_features,
+                          &top, output_file_path, output_file_type)?;
+
+        if let Some(names) = names {
+            let mut kept = top.clone();
+            kept.sort();
+            let kept_names: Vec<String> = kept.iter()
+                .map(|feature_id| names[*feature_id].clone())
+                .collect();
+            utils::write_names(&(output_prefix + ".features.txt"), &kept_names)?;
+        }
+    }
+
+    info!("All Done!");
+    Ok(())
+}
+
+fn pseudobulk_file(sub_m: &ArgMatches) -> Result<(), io::Error> {
+    let input_file_path = sub_m.value_of("input").unwrap();
+    let output_file_type = utils::find_output_format_or(sub_m, FileType::MTX);
+    let (input_file_type, output_prefix) =
+        utils::get_output_path(input_file_path, FileType::Dummy(".pseudobulk".to_string()));
+
+    let (num_cells, num_features) = parse_dims(sub_m)?;
+
+    let barcodes = read_optional_names(sub_m, "barcodes", num_cells)?.unwrap();
+    let groups = groups::read_groups(sub_m.value_of("groups").unwrap())?;
+    let aggregation = match sub_m.is_present("mean") {
+        true => groups::Aggregation::Mean,
+        false => groups::Aggregation::Sum,
+    };
+
+    let output_file_path = output_prefix.clone() + &utils::get_extension(&output_file_type);
+    let mut writer = utils::CellWriter::new(output_file_path, output_file_type, num_features, None)?;
+    let sizes = groups::pseudobulk(input_file_path, input_file_type, num_features,
+                                   &barcodes, &groups, &aggregation, &mut writer)?;
+    writer.finish()?;
+
+    groups::write_sizes(&(output_prefix + ".groups.tsv"), &sizes)?;
+
+    info!("All Done!");
+    Ok(())
+}
+
+fn aggregate_features_file(sub_m: &ArgMatches) -> Result<(), io::Error> {
+    let input_file_path = sub_m.value_of("input").unwrap();
+    let output_file_type = utils::find_output_format_or(sub_m, FileType::EDS);
+    let (input_file_type, output_prefix) =
+        utils::get_output_path(input_file_path, FileType::Dummy(".aggregated".to_string()));
+
+    let (num_cells, num_features) = parse_dims(sub_m)?;
+
+    let names = read_optional_names(sub_m, "names", num_features)?.unwrap();
+    let map = match sub_m.value_of("map") {
+        Some(path) => Some(features::read_map(path)?),
+        None => None,
+    };
+
+    let (new_index, targets) = features::aggregation_index(
+        &names, map.as_ref(), !sub_m.is_present("drop-unmapped"));
+
+    let output_file_path = output_prefix.clone() + &utils::get_extension(&output_file_type);
+    let mut writer = utils::CellWriter::new(output_file_path, output_file_type, targets.len(), None)?;
+    features::aggregate(input_file_path, input_file_type, num_cells, num_features,
+                        &new_index, targets.len(), &mut writer)?;
+    writer.finish()?;
+
+    utils::write_names(&(output_prefix + ".features.txt"), &targets)?;
+
+    info!("All Done!");
+    Ok(())
+}
+
+fn pca_file(sub_m: &ArgMatches) -> Result<(), io::Error> {
+    let input_file_path = sub_m.value_of("input").unwrap();
+    let (input_file_type, output_prefix) =
+        utils::get_output_path(input_file_path, FileType::Dummy(".pca".to_string()));
+
+    let (num_cells, num_features) = parse_dims(sub_m)?;
+
+    let barcodes = read_optional_names(sub_m, "barcodes", num_cells)?;
+    let names = read_optional_names(sub_m, "names", num_features)?;
+
+    let normalizer = match sub_m.value_of("normalize").unwrap() {
+        "none" => None,
+        value => Some(normalize::CellNormalizer::new(
+            input_file_path, input_file_type.clone(), num_cells, num_features,
+            normalize::parse_normalization(value)?)?),
+    };
+
+    let format = match sub_m.value_of("format").unwrap() {
+        "npy" => pca::TableFormat::Npy,
+        _ => pca::TableFormat::Tsv,
+    };
+
+    let options = pca::Options {
+        num_components: parse_required_arg(sub_m, "components")?,
+        oversampling: parse_required_arg(sub_m, "oversampling")?,
+        iterations: parse_required_arg(sub_m, "iterations")?,
+    };
+
+    let mut rng = utils::get_rng(parse_arg(sub_m, "seed")?);
+    let result = pca::fit(input_file_path, input_file_type.clone(), num_cells, num_features,
+                          normalizer, &options, &mut rng)?;
+
+    result.write_embeddings(input_file_path, input_file_type, num_features,
+                            &(output_prefix.clone() + ".embeddings" + format.extension()),
+                            &format, barcodes.as_ref())?;
+    result.write_loadings(&(output_prefix.clone() + ".loadings" + format.extension()),
+                          &format, names.as_ref())?;
+    result.write_variance(&(output_prefix + ".variance.tsv"))?;
+
+    info!("All Done!");
+    Ok(())
+}
+
+fn de_file(sub_m: &ArgMatches) -> Result<(), io::Error> {
+    let input_file_path = sub_m.value_of("input").unwrap();
+    let (input_file_type, output_prefix) =
+        utils::get_output_path(input_file_path, FileType::Dummy(".de.tsv".to_string()));
+
+    let (num_cells, num_features) = parse_dims(sub_m)?;
+
+    let barcodes = read_optional_names(sub_m, "barcodes", num_cells)?.unwrap();
+    let names = read_optional_names(sub_m, "names", num_features)?;
+    let groups = groups::read_groups(sub_m.value_of("groups").unwrap())?;
+
+    let group_a = sub_m.value_of("group-a").unwrap();
+    let group_b = sub_m.value_of("group-b").unwrap();
+    if group_a == group_b {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("can't test group {} against itself", group_a),
+        ));
+    }
+
+    let cell_groups: Vec<Option<bool>> = barcodes.iter()
+        .map(|barcode| match groups.get(barcode).map(|x| x.as_str()) {
+            Some(group) if group == group_a => Some(true),
+            Some(group) if group == group_b => Some(false),
+            _ => None,
+        })
+        .collect();
+
+    let pseudocount: f64 = parse_required_arg(sub_m, "pseudocount")?;
+    let max_entries: usize = parse_required_arg(sub_m, "max-entries")?;
+    let result = de::test(input_file_path, input_file_type, num_cells, num_features,
+                          &cell_groups, pseudocount, max_entries)?;
+    info!("Compared {} cells of {} against {} cells of {}",
+          result.num_cells.0, group_a, result.num_cells.1, group_b);
+
+    de::write_table(&output_prefix, &result, names.as_ref())?;
 
     info!("All Done!");
     Ok(())
 }
 
+fn score_file(sub_m: &ArgMatches) -> Result<(), io::Error> {
+    let input_file_path = sub_m.value_of("input").unwrap();
+    let (input_file_type, output_prefix) =
+        utils::get_output_path(input_file_path, FileType::Dummy(".scores.tsv".to_string()));
+
+    let (num_cells, num_features) = parse_dims(sub_m)?;
+
+    let barcodes = read_optional_names(sub_m, "barcodes", num_cells)?;
+    let names = read_optional_names(sub_m, "names", num_features)?.unwrap();
+    let sets = score::read_gene_sets(sub_m.value_of("sets").unwrap(), &names)?;
+
+    let method = match sub_m.value_of("method").unwrap() {
+        "seurat" => score::ScoreMethod::seurat(parse_required_arg(sub_m, "controls")?,
+                                               parse_required_arg(sub_m, "bins")?)?,
+        "aucell" => score::ScoreMethod::AUCell {
+            max_rank: parse_required_arg(sub_m, "max-rank")?,
+        },
+        _ => score::ScoreMethod::Mean,
+    };
+
+    let normalizer = match sub_m.value_of("normalize").unwrap() {
+        "none" => None,
+        value => Some(normalize::CellNormalizer::new(
+            input_file_path, input_file_type.clone(), num_cells, num_features,
+            normalize::parse_normalization(value)?)?),
+    };
+
+    let mut rng = utils::get_rng(parse_arg(sub_m, "seed")?);
+    let options = score::Options { method, normalizer };
+    let scores = score::score(input_file_path, input_file_type, num_cells, num_features,
+                              &sets, &options, &mut rng)?;
+
+    score::write_table(&output_prefix, &sets, &scores, barcodes.as_ref())?;
+
+    info!("All Done!");
+    Ok(())
+}
+
+fn callcells_file(sub_m: &ArgMatches) -> Result<(), io::Error> {
+    let input_file_path = sub_m.value_of("input").unwrap();
+    let output_file_type = utils::find_output_format_or(sub_m, FileType::EDS);
+    let (input_file_type, output_prefix) =
+        utils::get_output_path(input_file_path, FileType::Dummy(".called".to_string()));
+
+    let (num_cells, num_features) = parse_dims(sub_m)?;
+
+    let barcodes = read_optional_names(sub_m, "barcodes", num_cells)?;
+    let lower: f64 = parse_required_arg(sub_m, "lower")?;
+
+    let totals = callcells::cell_totals(input_file_path, input_file_type.clone(),
+                                        num_cells, num_features)?;
+    let curve = callcells::rank_curve(&totals, lower)?;
+    let threshold = match sub_m.value_of("threshold").unwrap() {
+        "inflection" => curve.inflection,
+        _ => curve.knee,
+    };
+    info!("Knee at a total of {}", curve.knee);
+    info!("Inflection at a total of {}", curve.inflection);
+
+    let mut called: Vec<bool> = totals.iter().map(|total| *total >= threshold).collect();
+    let test = match sub_m.is_present("emptydrops") {
+        true => {
+            let params = callcells::EmptyDrops {
+                lower,
+                retain: threshold,
+                iterations: parse_required_arg(sub_m, "iterations")?,
+            };
+            let fdr: f64 = parse_required_arg(sub_m, "fdr")?;
+
+            let mut rng = utils::get_rng(parse_arg(sub_m, "seed")?);
+            let test = callcells::empty_drops(input_file_path, input_file_type.clone(),
+                                              num_cells, num_features, &totals,
+                                              &params, &mut rng)?;
+            for (cell_id, cell_fdr) in test.fdr.iter() {
+                called[*cell_id] |= *cell_fdr <= fdr;
+            }
+            Some(test)
+        }
+        false => None,
+    };
+
+    let called_cells: Vec<usize> = (0..num_cells).filter(|x| called[*x]).collect();
+    info!("Called {} cells", called_cells.len());
+
+    callcells::write_report(&(output_prefix.clone() + ".report.tsv"), &totals, &curve,
+                            test.as_ref(), &called, barcodes.as_ref())?;
+
+    let mut writer = utils::CellWriter::new(
+        output_prefix.clone() + &utils::get_extension(&output_file_type),
+        output_file_type, num_features, None)?;
+    sample::write_sample(input_file_path, input_file_type, num_cells, num_features,
+                         &called_cells, &mut writer, None)?;
+    writer.finish()?;
+
+    utils::write_permutation(&(output_prefix.clone() + ".cells.txt"), &called_cells)?;
+    if let Some(barcodes) = barcodes {
+        let called_barcodes: Vec<String> = called_cells.iter()
+            .map(|cell_id| barcodes[*cell_id].clone())
+            .collect();
+        utils::write_names(&(output_prefix + ".barcodes.txt"), &called_barcodes)?;
+    }
+
+    info!("All Done!");
+    Ok(())
+}
+
+fn ambient_file(sub_m: &ArgMatches) -> Result<(), io::Error> {
+    let input_file_path = sub_m.value_of("input").unwrap();
+    let output_file_type = utils::find_output_format_or(sub_m, FileType::EDS);
+    let (input_file_type, output_prefix) =
+        utils::get_output_path(input_file_path, FileType::Dummy(".decontaminated".to_string()));
+
+    let (num_cells, num_features) = parse_dims(sub_m)?;
+
+    let barcodes = read_optional_names(sub_m, "barcodes", num_cells)?;
+    let names = read_optional_names(sub_m, "names", num_features)?;
+    let lower: f64 = parse_required_arg(sub_m, "lower")?;
+    let fixed: Option<f64> = parse_arg(sub_m, "contamination")?;
+    if let Some(fraction) = fixed {
+        if !(0.0..=1.0).contains(&fraction) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("contamination {} is not in [0, 1]", fraction),
+            ));
+        }
+    }
+    let max_contamination: f64 = parse_required_arg(sub_m, "max-contamination")?;
+    if !(0.0..=1.0).contains(&max_contamination) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("max-contamination {} is not in [0, 1]", max_contamination),
+        ));
+    }
+
+    // the cells to correct, by default the barcodes above the knee
+    let totals = callcells::cell_totals(input_file_path, input_file_type.clone(),
+                                        num_cells, num_features)?;
+    let kept_cells: Vec<usize> = match sub_m.value_of("keep") {
+        Some(path) => {
+            let mut kept_cells = utils::read_permutation(path, num_cells)?;
+            kept_cells.sort();
+            kept_cells
+        }
+        None => {
+            let curve = callcells::rank_curve(&totals, lower)?;
+            info!("Keeping the barcodes with a total of at least {}", curve.knee);
+            (0..num_cells).filter(|x| totals[*x] >= curve.knee).collect()
+        }
+    };
+
+    let mut kept: Vec<bool> = vec![false; num_cells];
+    for cell_id in kept_cells.iter() {
+        kept[*cell_id] = true;
+    }
+
+    let options = ambient::Options {
+        lower,
+        num_markers: parse_required_arg(sub_m, "markers")?,
+        fixed,
+        max_contamination,
+    };
+    let profile = ambient::estimate_profile(input_file_path, input_file_type.clone(),
+                                            num_features, &totals, &kept, &options)?;
+    ambient::write_profile(&(output_prefix.clone() + ".profile.tsv"), &profile, names.as_ref())?;
+
+    let output_file_path = output_prefix.clone() + &utils::get_extension(&output_file_type);
+    let mut writer = utils::CellWriter::new(output_file_path, output_file_type, num_features, None)?;
+    let corrections = ambient::correct(input_file_path, input_file_type, num_features,
+                                       &kept, &profile, &options, &mut writer)?;
+    writer.finish()?;
+
+    let fractions: Vec<f64> = corrections.iter().map(|x| x.contamination).collect();
+    info!("Corrected {} cells, median contamination of {}",
+          corrections.len(), normalize::median(&fractions));
+    ambient::write_report(&(output_prefix.clone() + ".report.tsv"), &corrections, barcodes.as_ref())?;
+
+    utils::write_permutation(&(output_prefix.clone() + ".cells.txt"), &kept_cells)?;
+    if let Some(barcodes) = barcodes {
+        let kept_barcodes: Vec<String> = kept_cells.iter()
+            .map(|cell_id| barcodes[*cell_id].clone())
+            .collect();
+        utils::write_names(&(output_prefix + ".barcodes.txt"), &kept_barcodes)?;
+    }
+
+    info!("All Done!");
+    Ok(())
+}
+
+fn input_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("cells")
+            .long("cells")
+            .short("c")
+            .takes_value(true)
+            .help("Number of cells"),
+        Arg::with_name("features")
+            .long("features")
+            .short("f")
+            .takes_value(true)
+            .help("Number of features"),
+        Arg::with_name("input")
+            .long("input")
+            .short("i")
+            .takes_value(true)
+            .required(true)
+            .requires("cells")
+            .requires("features")
+            .help("path to input file"),
+    ]
+}
+
+// the -c and -f dimensions every input takes
+fn parse_dims(sub_m: &ArgMatches) -> Result<(usize, usize), io::Error> {
+    Ok((parse_required_arg(sub_m, "cells")?, parse_required_arg(sub_m, "features")?))
+}
+
+fn output_format_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("mtx")
+            .long("mtx")
+            .conflicts_with_all(&["eds", "csv", "h5"])
+            .help("write matrix market exchange file"),
+        Arg::with_name("h5")
+            .long("h5")
+            .conflicts_with_all(&["eds", "csv", "mtx"])
+            .help("write h5 wrapped csc file"),
+        Arg::with_name("csv")
+            .long("csv")
+            .conflicts_with_all(&["eds", "mtx", "h5"])
+            .help("write comma separated file"),
+        Arg::with_name("eds")
+            .long("eds")
+            .conflicts_with_all(&["csv", "mtx", "h5"])
+            .help("write EDS file (default)"),
+    ]
+}
+
+fn feature_major_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("feature-major")
+        .long("feature-major")
+        .help("the input mtx has the features in its first column and the cells in the second")
+}
+
+// mtx input is cell-major unless --feature-major, which the other formats
+// can't take
+fn parse_cell_by_gene(sub_m: &ArgMatches, input_file_type: &FileType) -> Result<bool, io::Error> {
+    if !sub_m.is_present("feature-major") {
+        return Ok(true);
+    }
+
+    match input_file_type {
+        FileType::MTX => Ok(false),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--feature-major only applies to mtx input",
+        )),
+    }
+}
+
 fn main() -> io::Result<()> {
     let matches = App::new("EDS")
         .version("0.1.0")
         .author("Avi Srivastava, Mike Love and Rob Patro")
         .about("Efficient scData Storage format")
         .subcommand(
-            SubCommand::with_name("randomize")
-                .about("randomize the order of cells")
+            SubCommand::with_name("randomize")
+                .about("randomize the order of cells")
+                .arg(
+                    Arg::with_name("cells")
+                        .long("cells")
+                        .short("c")
+                        .takes_value(true)
+                        .help("Number of cells"),
+                )
+                .arg(
+                    Arg::with_name("features")
+                        .long("features")
+                        .short("f")
+                        .takes_value(true)
+                        .help("Number of features"),
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .long("input")
+                        .short("i")
+                        .takes_value(true)
+                        .requires("cells")
+                        .requires("features")
+                        .help("path to input file"),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .short("s")
+                        .takes_value(true)
+                        .conflicts_with("unshuffle")
+                        .help("seed of the random number generator"),
+                )
+                .arg(
+                    Arg::with_name("unshuffle")
+                        .long("unshuffle")
+                        .short("u")
+                        .takes_value(true)
+                        .help("path to a permutation record, restores the original order"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about("comnvert from eds data format to csv or mtx format")
+                .arg(
+                    Arg::with_name("mtx")
+                        .long("mtx")
+                        .conflicts_with("eds")
+                        .conflicts_with("csv")
+                        .conflicts_with("h5")
+                        .help("convert to matrix market exchange file"),
+                )
+                .arg(
+                    Arg::with_name("h5")
+                        .long("h5")
+                        .conflicts_with("eds")
+                        .conflicts_with("csv")
+                        .conflicts_with("mtx")
+                        .help("convert to h5 wrapped csc file"),
+                )
+                .arg(
+                    Arg::with_name("csv")
+                        .long("csv")
+                        .conflicts_with("eds")
+                        .conflicts_with("mtx")
+                        .conflicts_with("h5")
+                        .help("convert to comma separated file"),
+                )
+                .arg(
+                    Arg::with_name("eds")
+                        .long("eds")
+                        .conflicts_with("csv")
+                        .conflicts_with("mtx")
+                        .conflicts_with("h5")
+                        .help("convert to EDS file"),
+                )
+                .arg(
+                    Arg::with_name("cells")
+                        .long("cells")
+                        .short("c")
+                        .takes_value(true)
+                        .help("Number of cells"),
+                )
+                .arg(
+                    Arg::with_name("features")
+                        .long("features")
+                        .short("f")
+                        .takes_value(true)
+                        .help("Number of features"),
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .long("input")
+                        .short("i")
+                        .takes_value(true)
+                        .requires("cells")
+                        .requires("features")
+                        .help("path to input file"),
+                )
+                .arg(
+                    Arg::with_name("normalize")
+                        .long("normalize")
+                        .takes_value(true)
+                        .help("write normalized values: cpm, total[:<target>], log1p or cpm+log1p"),
+                )
+                .arg(
+                    Arg::with_name("reorder")
+                        .long("reorder")
+                        .takes_value(true)
+                        .possible_values(&["gray", "minhash"])
+                        .requires("eds")
+                        .help("order cells by sparsity pattern to shrink the EDS file"),
+                )
+                .arg(feature_major_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("align-features")
+                .about("reorder features to match a reference feature list")
+                .args(&input_args())
+                .args(&output_format_args())
+                .arg(
+                    Arg::with_name("names")
+                        .long("names")
+                        .short("n")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the feature names of the input, one per line"),
+                )
+                .arg(
+                    Arg::with_name("reference")
+                        .long("reference")
+                        .short("r")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the reference feature names, one per line"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("split")
+                .about("split the cells into one file per group")
+                .args(&input_args())
+                .args(&output_format_args())
+                .arg(
+                    Arg::with_name("barcodes")
+                        .long("barcodes")
+                        .short("b")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the cell barcodes of the input, one per line"),
+                )
+                .arg(
+                    Arg::with_name("groups")
+                        .long("groups")
+                        .short("g")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the barcode<TAB>group table"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("transpose")
+                .about("swap rows and columns, cells become features and vice versa")
+                .args(&input_args())
+                .args(&output_format_args())
+                .arg(
+                    Arg::with_name("max-entries")
+                        .long("max-entries")
+                        .takes_value(true)
+                        .default_value("100000000")
+                        .help("maximum number of entries held in memory, spills to disk beyond it"),
+                )
+                .arg(feature_major_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("inspect")
+                .about("print dimensions, sparsity and value summary of a file")
+                .args(&input_args())
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("print the summary as json"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("validate")
+                .about("check the structural integrity of an EDS file")
+                .args(&input_args())
+                .arg(
+                    Arg::with_name("max-errors")
+                        .long("max-errors")
+                        .takes_value(true)
+                        .default_value("20")
+                        .help("maximum number of errors to print"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("write per-cell and per-feature summary statistics")
+                .args(&input_args())
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["tsv", "json"])
+                        .default_value("tsv")
+                        .help("output table format"),
+                )
+                .arg(
+                    Arg::with_name("barcodes")
+                        .long("barcodes")
+                        .short("b")
+                        .takes_value(true)
+                        .help("path to the cell barcodes of the input, one per line"),
+                )
+                .arg(
+                    Arg::with_name("names")
+                        .long("names")
+                        .short("n")
+                        .takes_value(true)
+                        .help("path to the feature names of the input, one per line"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("filter")
+                .about("drop cells and features failing quality control thresholds")
+                .args(&input_args())
+                .args(&output_format_args())
+                .arg(
+                    Arg::with_name("min-counts")
+                        .long("min-counts")
+                        .takes_value(true)
+                        .help("minimum total counts of a cell"),
+                )
+                .arg(
+                    Arg::with_name("max-counts")
+                        .long("max-counts")
+                        .takes_value(true)
+                        .help("maximum total counts of a cell"),
+                )
+                .arg(
+                    Arg::with_name("min-features")
+                        .long("min-features")
+                        .takes_value(true)
+                        .help("minimum number of detected features of a cell"),
+                )
+                .arg(
+                    Arg::with_name("max-features")
+                        .long("max-features")
+                        .takes_value(true)
+                        .help("maximum number of detected features of a cell"),
+                )
+                .arg(
+                    Arg::with_name("min-cells")
+                        .long("min-cells")
+                        .takes_value(true)
+                        .help("minimum number of cells expressing a feature"),
+                )
+                .arg(
+                    Arg::with_name("min-feature-counts")
+                        .long("min-feature-counts")
+                        .takes_value(true)
+                        .help("minimum total counts of a feature"),
+                )
+                .arg(
+                    Arg::with_name("whitelist")
+                        .long("whitelist")
+                        .short("w")
+                        .takes_value(true)
+                        .requires("barcodes")
+                        .help("path to the barcodes to keep, one per line"),
+                )
+                .arg(
+                    Arg::with_name("max-mito")
+                        .long("max-mito")
+                        .takes_value(true)
+                        .requires("names")
+                        .help("maximum fraction of counts from mitochondrial features"),
+                )
+                .arg(
+                    Arg::with_name("mito-prefix")
+                        .long("mito-prefix")
+                        .takes_value(true)
+                        .help("name prefix of the mitochondrial features [default: MT-]"),
+                )
+                .arg(
+                    Arg::with_name("mito-regex")
+                        .long("mito-regex")
+                        .takes_value(true)
+                        .help("regex matching the names of the mitochondrial features"),
+                )
+                .arg(
+                    Arg::with_name("barcodes")
+                        .long("barcodes")
+                        .short("b")
+                        .takes_value(true)
+                        .help("path to the cell barcodes of the input, one per line"),
+                )
+                .arg(
+                    Arg::with_name("names")
+                        .long("names")
+                        .short("n")
+                        .takes_value(true)
+                        .help("path to the feature names of the input, one per line"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("downsample")
+                .about("thin the counts of every cell to a target depth")
+                .args(&input_args())
+                .args(&output_format_args())
+                .arg(
+                    Arg::with_name("target")
+                        .long("target")
+                        .short("t")
+                        .takes_value(true)
+                        .required_unless("fraction")
+                        .conflicts_with("fraction")
+                        .help("target total counts per cell"),
+                )
+                .arg(
+                    Arg::with_name("fraction")
+                        .long("fraction")
+                        .takes_value(true)
+                        .help("fraction of the counts to keep in every cell"),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .short("s")
+                        .takes_value(true)
+                        .help("seed of the random number generator"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("sample")
+                .about("draw a random subset of the cells")
+                .args(&input_args())
+                .args(&output_format_args())
+                .arg(
+                    Arg::with_name("size")
+                        .long("size")
+                        .takes_value(true)
+                        .required_unless("fraction")
+                        .conflicts_with("fraction")
+                        .help("number of cells to draw"),
+                )
+                .arg(
+                    Arg::with_name("fraction")
+                        .long("fraction")
+                        .takes_value(true)
+                        .help("fraction of the cells to draw"),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .short("s")
+                        .takes_value(true)
+                        .help("seed of the random number generator"),
+                )
+                .arg(
+                    Arg::with_name("groups")
+                        .long("groups")
+                        .short("g")
+                        .takes_value(true)
+                        .requires("barcodes")
+                        .help("path to a barcode<TAB>label table, draws proportionally per label"),
+                )
+                .arg(
+                    Arg::with_name("barcodes")
+                        .long("barcodes")
+                        .short("b")
+                        .takes_value(true)
+                        .help("path to the cell barcodes of the input, one per line"),
+                )
+                .arg(
+                    Arg::with_name("split")
+                        .long("split")
+                        .help("write the drawn cells as train and the rest as test"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("sort")
+                .about("sort the cells by a key, the deterministic counterpart of randomize")
+                .args(&input_args())
+                .args(&output_format_args())
                 .arg(
-                    Arg::with_name("cells")
-                        .long("cells")
-                        .short("c")
+                    Arg::with_name("by")
+                        .long("by")
                         .takes_value(true)
-                        .help("Number of cells"),
+                        .possible_values(&["counts", "features", "barcode", "table"])
+                        .default_value("counts")
+                        .help("key to sort the cells by"),
                 )
                 .arg(
-                    Arg::with_name("features")
-                        .long("features")
-                        .short("f")
+                    Arg::with_name("key")
+                        .long("key")
+                        .short("k")
                         .takes_value(true)
-                        .help("Number of features"),
+                        .requires("barcodes")
+                        .required_if("by", "table")
+                        .help("path to a barcode<TAB>key table, used with --by table"),
                 )
                 .arg(
-                    Arg::with_name("input")
-                        .long("input")
-                        .short("i")
+                    Arg::with_name("descending")
+                        .long("descending")
+                        .short("d")
+                        .help("sort from the largest key down"),
+                )
+                .arg(
+                    Arg::with_name("barcodes")
+                        .long("barcodes")
+                        .short("b")
                         .takes_value(true)
-                        .requires("cells")
-                        .requires("features")
-                        .help("path to input file"),
+                        .required_ifs(&[("by", "barcode"), ("by", "table")])
+                        .help("path to the cell barcodes of the input, one per line"),
                 ),
         )
         .subcommand(
-            SubCommand::with_name("convert")
-                .about("comnvert from eds data format to csv or mtx format")
+            SubCommand::with_name("diff")
+                .about("compare two count matrices cell by cell, exits non-zero on mismatch")
+                .args(&input_args())
                 .arg(
-                    Arg::with_name("mtx")
-                        .long("mtx")
-                        .conflicts_with("eds")
-                        .conflicts_with("csv")
-                        .conflicts_with("h5")
-                        .help("convert to matrix market exchange file"),
+                    Arg::with_name("other")
+                        .long("other")
+                        .short("j")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the file compared against the input"),
                 )
                 .arg(
-                    Arg::with_name("h5")
-                        .long("h5")
-                        .conflicts_with("eds")
-                        .conflicts_with("csv")
-                        .conflicts_with("mtx")
-                        .help("convert to h5 wrapped csc file"),
+                    Arg::with_name("other-cells")
+                        .long("other-cells")
+                        .takes_value(true)
+                        .help("Number of cells in the other file, same as the input by default"),
                 )
                 .arg(
-                    Arg::with_name("csv")
-                        .long("csv")
-                        .conflicts_with("eds")
-                        .conflicts_with("mtx")
-                        .conflicts_with("h5")
-                        .help("convert to comma separated file"),
+                    Arg::with_name("other-features")
+                        .long("other-features")
+                        .takes_value(true)
+                        .help("Number of features in the other file, same as the input by default"),
                 )
                 .arg(
-                    Arg::with_name("eds")
-                        .long("eds")
-                        .conflicts_with("csv")
-                        .conflicts_with("mtx")
-                        .conflicts_with("h5")
-                        .help("convert to EDS file"),
+                    Arg::with_name("tolerance")
+                        .long("tolerance")
+                        .short("t")
+                        .takes_value(true)
+                        .default_value("0")
+                        .help("absolute difference allowed between two values"),
                 )
                 .arg(
-                    Arg::with_name("cells")
-                        .long("cells")
-                        .short("c")
+                    Arg::with_name("rel-tolerance")
+                        .long("rel-tolerance")
                         .takes_value(true)
-                        .help("Number of cells"),
+                        .default_value("0")
+                        .help("difference allowed relative to the larger of two values"),
                 )
                 .arg(
-                    Arg::with_name("features")
-                        .long("features")
-                        .short("f")
+                    Arg::with_name("report")
+                        .long("report")
+                        .short("r")
+                        .help("write per-cell and per-feature difference tables"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("hash")
+                .about("print a digest of the matrix content, independent of the file format")
+                .args(&input_args())
+                .arg(
+                    Arg::with_name("barcodes")
+                        .long("barcodes")
+                        .short("b")
                         .takes_value(true)
-                        .help("Number of features"),
+                        .help("path to the cell barcodes of the input, hashed along the values"),
                 )
                 .arg(
-                    Arg::with_name("input")
-                        .long("input")
-                        .short("i")
+                    Arg::with_name("names")
+                        .long("names")
+                        .short("n")
                         .takes_value(true)
-                        .requires("cells")
-                        .requires("features")
-                        .help("path to input file"),
+                        .help("path to the feature names of the input, hashed along the values"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("hvg")
+                .about("select the highly variable features")
+                .args(&input_args())
+                .args(&output_format_args())
+                .arg(
+                    Arg::with_name("method")
+                        .long("method")
+                        .short("m")
+                        .takes_value(true)
+                        .possible_values(&["dispersion", "vst"])
+                        .default_value("vst")
+                        .help("dispersion z-scored by mean bins or variance-stabilized variance"),
+                )
+                .arg(
+                    Arg::with_name("top")
+                        .long("top")
+                        .short("t")
+                        .takes_value(true)
+                        .default_value("2000")
+                        .help("number of features to select"),
+                )
+                .arg(
+                    Arg::with_name("subset")
+                        .long("subset")
+                        .help("write the input with only the selected features"),
+                )
+                .arg(
+                    Arg::with_name("names")
+                        .long("names")
+                        .short("n")
+                        .takes_value(true)
+                        .help("path to the feature names of the input, one per line"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("pseudobulk")
+                .about("aggregate the cells of every group into a groups x features matrix")
+                .args(&input_args())
+                .args(&output_format_args())
+                .arg(
+                    Arg::with_name("barcodes")
+                        .long("barcodes")
+                        .short("b")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the cell barcodes of the input, one per line"),
+                )
+                .arg(
+                    Arg::with_name("groups")
+                        .long("groups")
+                        .short("g")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the barcode<TAB>group table"),
+                )
+                .arg(
+                    Arg::with_name("mean")
+                        .long("mean")
+                        .help("average the counts of a group instead of summing them"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("aggregate-features")
+                .about("sum the features mapped to the same target, e.g. transcripts to genes")
+                .args(&input_args())
+                .args(&output_format_args())
+                .arg(
+                    Arg::with_name("names")
+                        .long("names")
+                        .short("n")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the feature names of the input, one per line"),
+                )
+                .arg(
+                    Arg::with_name("map")
+                        .long("map")
+                        .short("m")
+                        .takes_value(true)
+                        .help("path to the feature<TAB>target table, duplicate names are merged without it"),
+                )
+                .arg(
+                    Arg::with_name("drop-unmapped")
+                        .long("drop-unmapped")
+                        .requires("map")
+                        .help("drop the features missing from the map instead of keeping them"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("pca")
+                .about("randomized PCA of the normalized counts, streaming over the input")
+                .args(&input_args())
+                .arg(
+                    Arg::with_name("components")
+                        .long("components")
+                        .short("k")
+                        .takes_value(true)
+                        .default_value("50")
+                        .help("number of principal components"),
+                )
+                .arg(
+                    Arg::with_name("normalize")
+                        .long("normalize")
+                        .takes_value(true)
+                        .default_value("cpm+log1p")
+                        .help("normalization applied before centering: cpm, total[:<target>], log1p, cpm+log1p or none"),
+                )
+                .arg(
+                    Arg::with_name("iterations")
+                        .long("iterations")
+                        .takes_value(true)
+                        .default_value("4")
+                        .help("number of power iterations, one pass over the input each"),
+                )
+                .arg(
+                    Arg::with_name("oversampling")
+                        .long("oversampling")
+                        .takes_value(true)
+                        .default_value("10")
+                        .help("extra random directions sampled beyond the components"),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .short("s")
+                        .takes_value(true)
+                        .help("seed of the random projection"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["tsv", "npy"])
+                        .default_value("tsv")
+                        .help("format of the embeddings and loadings"),
+                )
+                .arg(
+                    Arg::with_name("barcodes")
+                        .long("barcodes")
+                        .short("b")
+                        .takes_value(true)
+                        .help("path to the cell barcodes of the input, one per line"),
+                )
+                .arg(
+                    Arg::with_name("names")
+                        .long("names")
+                        .short("n")
+                        .takes_value(true)
+                        .help("path to the feature names of the input, one per line"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("de")
+                .about("test every feature for differential expression between two groups of cells")
+                .args(&input_args())
+                .arg(
+                    Arg::with_name("barcodes")
+                        .long("barcodes")
+                        .short("b")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the cell barcodes of the input, one per line"),
+                )
+                .arg(
+                    Arg::with_name("groups")
+                        .long("groups")
+                        .short("g")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the barcode<TAB>group table"),
+                )
+                .arg(
+                    Arg::with_name("group-a")
+                        .long("a")
+                        .takes_value(true)
+                        .required(true)
+                        .help("first group, the fold changes are a over b"),
+                )
+                .arg(
+                    Arg::with_name("group-b")
+                        .long("b")
+                        .takes_value(true)
+                        .required(true)
+                        .help("second group"),
+                )
+                .arg(
+                    Arg::with_name("pseudocount")
+                        .long("pseudocount")
+                        .takes_value(true)
+                        .default_value("1")
+                        .help("added to both group means before taking the fold change"),
+                )
+                .arg(
+                    Arg::with_name("max-entries")
+                        .long("max-entries")
+                        .takes_value(true)
+                        .default_value("100000000")
+                        .help("maximum number of non-zero values ranked at once, more take more passes"),
+                )
+                .arg(
+                    Arg::with_name("names")
+                        .long("names")
+                        .short("n")
+                        .takes_value(true)
+                        .help("path to the feature names of the input, one per line"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("score")
+                .about("score every cell on one or more gene sets")
+                .args(&input_args())
+                .arg(
+                    Arg::with_name("sets")
+                        .long("sets")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the gene sets in GMT format: name, description, features"),
+                )
+                .arg(
+                    Arg::with_name("names")
+                        .long("names")
+                        .short("n")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the feature names of the input, one per line"),
+                )
+                .arg(
+                    Arg::with_name("method")
+                        .long("method")
+                        .short("m")
+                        .takes_value(true)
+                        .possible_values(&["mean", "seurat", "aucell"])
+                        .default_value("mean")
+                        .help("mean expression, seurat control-binned score or aucell rank score"),
+                )
+                .arg(
+                    Arg::with_name("normalize")
+                        .long("normalize")
+                        .takes_value(true)
+                        .default_value("cpm+log1p")
+                        .help("normalization before scoring: cpm, total[:<target>], log1p, cpm+log1p or none"),
+                )
+                .arg(
+                    Arg::with_name("controls")
+                        .long("controls")
+                        .takes_value(true)
+                        .default_value("100")
+                        .help("control features drawn per set feature by the seurat score"),
+                )
+                .arg(
+                    Arg::with_name("bins")
+                        .long("bins")
+                        .takes_value(true)
+                        .default_value("24")
+                        .help("number of expression bins of the seurat score"),
+                )
+                .arg(
+                    Arg::with_name("max-rank")
+                        .long("max-rank")
+                        .takes_value(true)
+                        .default_value("0.05")
+                        .help("fraction of the top ranked features used by the aucell score"),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .short("s")
+                        .takes_value(true)
+                        .help("seed of the control feature sampling"),
+                )
+                .arg(
+                    Arg::with_name("barcodes")
+                        .long("barcodes")
+                        .short("b")
+                        .takes_value(true)
+                        .help("path to the cell barcodes of the input, one per line"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("callcells")
+                .about("call cells from the barcode rank curve, optionally testing against the ambient profile")
+                .args(&input_args())
+                .args(&output_format_args())
+                .arg(
+                    Arg::with_name("lower")
+                        .long("lower")
+                        .takes_value(true)
+                        .default_value("100")
+                        .help("totals at or below are empty droplets, they make the ambient profile"),
+                )
+                .arg(
+                    Arg::with_name("threshold")
+                        .long("threshold")
+                        .takes_value(true)
+                        .possible_values(&["knee", "inflection"])
+                        .default_value("knee")
+                        .help("point of the rank curve above which every barcode is a cell"),
+                )
+                .arg(
+                    Arg::with_name("emptydrops")
+                        .long("emptydrops")
+                        .help("also call the barcodes between lower and the threshold that differ from the ambient profile"),
+                )
+                .arg(
+                    Arg::with_name("iterations")
+                        .long("iterations")
+                        .takes_value(true)
+                        .default_value("10000")
+                        .help("number of ambient draws of the emptydrops test"),
+                )
+                .arg(
+                    Arg::with_name("fdr")
+                        .long("fdr")
+                        .takes_value(true)
+                        .default_value("0.01")
+                        .help("maximum BH-adjusted p-value of a called barcode"),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .short("s")
+                        .takes_value(true)
+                        .help("seed of the ambient draws"),
+                )
+                .arg(
+                    Arg::with_name("barcodes")
+                        .long("barcodes")
+                        .short("b")
+                        .takes_value(true)
+                        .help("path to the cell barcodes of the input, one per line"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ambient")
+                .about("estimate the ambient profile and the contamination of every cell, then subtract it")
+                .args(&input_args())
+                .args(&output_format_args())
+                .arg(
+                    Arg::with_name("lower")
+                        .long("lower")
+                        .takes_value(true)
+                        .default_value("100")
+                        .help("totals at or below are empty droplets, they make the ambient profile"),
+                )
+                .arg(
+                    Arg::with_name("keep")
+                        .long("keep")
+                        .short("k")
+                        .takes_value(true)
+                        .help("0-based indices of the cells to correct, one per line (default: barcodes above the knee)"),
+                )
+                .arg(
+                    Arg::with_name("markers")
+                        .long("markers")
+                        .takes_value(true)
+                        .default_value("10")
+                        .help("number of ambient enriched features estimating the contamination"),
+                )
+                .arg(
+                    Arg::with_name("contamination")
+                        .long("contamination")
+                        .takes_value(true)
+                        .help("fixed contamination fraction of every cell instead of the estimates"),
+                )
+                .arg(
+                    Arg::with_name("max-contamination")
+                        .long("max-contamination")
+                        .takes_value(true)
+                        .default_value("0.5")
+                        .help("cap of the estimated contamination, the capped cells are flagged in the report"),
+                )
+                .arg(
+                    Arg::with_name("barcodes")
+                        .long("barcodes")
+                        .short("b")
+                        .takes_value(true)
+                        .help("path to the cell barcodes of the input, one per line"),
+                )
+                .arg(
+                    Arg::with_name("names")
+                        .long("names")
+                        .short("n")
+                        .takes_value(true)
+                        .help("path to the feature names of the input, one per line"),
                 ),
         )
         .get_matches();
@@ -191,5 +2012,85 @@ fn main() -> io::Result<()> {
         None => (),
     };
 
+    if let Some(sub_m) = matches.subcommand_matches("align-features") {
+        return align_features(sub_m);
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("split") {
+        return split_file(sub_m);
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("transpose") {
+        return transpose_file(sub_m);
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("inspect") {
+        return inspect_file(sub_m);
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("validate") {
+        return validate_file(sub_m);
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("stats") {
+        return stats_file(sub_m);
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("filter") {
+        return filter_file(sub_m);
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("downsample") {
+        return downsample_file(sub_m);
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("sample") {
+        return sample_file(sub_m);
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("sort") {
+        return sort_file(sub_m);
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("diff") {
+        return diff_file(sub_m);
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("hash") {
+        return hash_file(sub_m);
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("hvg") {
+        return hvg_file(sub_m);
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("pseudobulk") {
+        return pseudobulk_file(sub_m);
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("aggregate-features") {
+        return aggregate_features_file(sub_m);
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("pca") {
+        return pca_file(sub_m);
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("de") {
+        return de_file(sub_m);
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("score") {
+        return score_file(sub_m);
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("callcells") {
+        return callcells_file(sub_m);
+    }
+
+    if let Some(sub_m) = matches.subcommand_matches("ambient") {
+        return ambient_file(sub_m);
+    }
+
     Ok(())
 }
@@ -6,32 +6,27 @@ use std::io;
 use std::collections::HashMap;
 use std::io::{Write, BufReader, BufRead};
 
-use crate::utils::triplets_to_eds;
-pub fn reader(
+use crate::utils::{get_positions, triplets_to_eds};
+
+// calls back with the (cell, gene, value) triplets in file order, the
+// orientation tells which of the first two columns is the cell
+pub fn for_each_entry<F>(
     input: &str,
     num_cells: usize,
     num_genes: usize,
-    expr: &mut Vec<Vec<f32>>,
-    bit_vecs: &mut Vec<Vec<u8>>,
-) -> Result<bool, io::Error> {
-    info!("Using {} as input MTX file\n", input);
-    info!(
-        "Using {} Rows (cells) and {} Columns (features)",
-        num_cells, num_genes
-    );
-
+    cell_by_gene: bool,
+    mut callback: F,
+) -> Result<(), io::Error>
+where F: FnMut(usize, usize, f32) -> Result<(), io::Error> {
     let file_handle = File::open(input)?;
     let file = BufReader::new( GzDecoder::new(file_handle) );
 
-    let cell_by_gene = true;
     let (cell_index, gene_index) = match cell_by_gene {
         true => (0, 1),
         false => (1, 0),
     };
 
     let mut found_first = false;
-    let mut triplets: Vec<HashMap<u32, f32>> = vec![ HashMap::new(); num_cells ];
-
     for line in file.lines() {
         let record = line?;
         if record.chars().nth(0).unwrap() == '%' {
@@ -41,7 +36,7 @@ pub fn reader(
         let vals: Vec<&str> = record.split("\t")
             .collect();
 
-        let gid = vals[gene_index].parse::<u32>()
+        let gid = vals[gene_index].parse::<usize>()
             .expect("can't convert gid");
         let cid = vals[cell_index].parse::<usize>()
             .expect("can't convert cid");
@@ -52,17 +47,95 @@ pub fn reader(
             found_first = true;
 
             assert!(num_cells == cid );
-            assert!(num_genes == gid as usize);
+            assert!(num_genes == gid);
             continue;
         }
 
-        triplets[cid - 1].insert(gid - 1, value);
+        callback(cid - 1, gid - 1, value)?;
     }
 
+    Ok(())
+}
+
+pub fn reader(
+    input: &str,
+    num_cells: usize,
+    num_genes: usize,
+    cell_by_gene: bool,
+    expr: &mut Vec<Vec<f32>>,
+    bit_vecs: &mut Vec<Vec<u8>>,
+) -> Result<bool, io::Error> {
+    info!("Using {} as input MTX file\n", input);
+    info!(
+        "Using {} Rows (cells) and {} Columns (features)",
+        num_cells, num_genes
+    );
+
+    let mut triplets: Vec<HashMap<u32, f32>> = vec![ HashMap::new(); num_cells ];
+    for_each_entry(input, num_cells, num_genes, cell_by_gene, |cid, gid, value| {
+        triplets[cid].insert(gid as u32, value);
+        Ok(())
+    })?;
+
     triplets_to_eds(&triplets, expr, bit_vecs, num_genes);
     Ok(true)
 }
 
+pub struct Writer {
+    file: GzEncoder<File>,
+    cell_id: usize,
+}
+
+impl Writer {
+    pub fn new(
+        path_str: &str,
+        num_cells: usize,
+        num_features: usize,
+        num_entries: usize,
+    ) -> Result<Writer, io::Error> {
+        let file_handle = File::create(path_str)?;
+        let mut file = GzEncoder::new(file_handle, Compression::default());
+
+        let mut header = "%%MatrixMarket\tmatrix\tcoordinate\treal\tgeneral\n".to_string();
+        header.push_str(&format!(
+            "{}\t{}\t{}\n",
+            num_cells, num_features, num_entries
+        ));
+        file.write_all(header.as_bytes())?;
+
+        Ok(Writer {
+            file,
+            cell_id: 0,
+        })
+    }
+
+    pub fn write_cell(&mut self, bit_vec: &[u8], exp: &[f32]) -> Result<(), io::Error> {
+        let fids = get_positions(bit_vec);
+        assert!(
+            fids.len() == exp.len(),
+            "#positions doesn't match with #expressed features"
+        );
+
+        let mut mtx_data = "".to_string();
+        for (index, count) in exp.iter().enumerate() {
+            mtx_data.push_str(&format!(
+                "{}\t{}\t{}\n",
+                self.cell_id + 1,
+                fids[index] + 1,
+                count
+            ));
+        }
+
+        self.cell_id += 1;
+        self.file.write_all(mtx_data.as_bytes())
+    }
+
+    pub fn finish(self) -> Result<(), io::Error> {
+        self.file.finish()?;
+        Ok(())
+    }
+}
+
 pub fn writer(
     path_str: String,
     expressions: Vec<Vec<f32>>,
@@ -75,51 +148,16 @@ pub fn writer(
         .iter()
         .for_each(|x| tot_expressed_features += x.len());
 
-    let file_handle = File::create(path_str)?;
-    let mut file = GzEncoder::new(file_handle, Compression::default());
-
-    let mut header = "%%MatrixMarket\tmatrix\tcoordinate\treal\tgeneral\n".to_string();
-    header.push_str(&format!(
-        "{}\t{}\t{}\n",
-        num_cells, num_features, tot_expressed_features
-    ));
-    file.write_all(header.as_bytes())?;
+    let mut file = Writer::new(&path_str, num_cells, num_features, tot_expressed_features)?;
 
     assert!(
         bit_vecs.len() == expressions.len(),
         "length of bit vec and expression is not same"
     );
     for (cell_id, exp) in expressions.into_iter().enumerate() {
-        let bit_vec = &bit_vecs[cell_id];
-        let mut fids: Vec<usize> = Vec::new();
-
-        for (feature_id, flag) in bit_vec.into_iter().enumerate() {
-            if *flag != 0 {
-                for (offset, j) in format!("{:8b}", flag).chars().enumerate() {
-                    match j {
-                        '1' => fids.push((8 * feature_id) + offset),
-                        _ => (),
-                    };
-                }
-            }
-        }
-
-        assert!(
-            fids.len() == exp.len(),
-            "#positions doesn't match with #expressed features"
-        );
-        let mut mtx_data = "".to_string();
-        for (index, count) in exp.into_iter().enumerate() {
-            mtx_data.push_str(&format!(
-                "{}\t{}\t{}\n",
-                cell_id + 1,
-                fids[index] + 1,
-                count
-            ));
-        }
-
-        file.write_all(mtx_data.as_bytes())?;
+        file.write_cell(&bit_vecs[cell_id], &exp)?;
     }
 
+    file.finish()?;
     Ok(true)
 }
@@ -0,0 +1,186 @@
+use std::io;
+
+use crate::utils::{cell_total, for_each_cell, FileType};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Normalization {
+    Cpm,
+    // scale every cell to the target total, the median total if missing
+    Total(Option<f64>),
+    Log1p,
+    CpmLog1p,
+}
+
+pub fn parse_normalization(value: &str) -> Result<Normalization, io::Error> {
+    let method = match value {
+        "cpm" => Normalization::Cpm,
+        "log1p" => Normalization::Log1p,
+        "cpm+log1p" => Normalization::CpmLog1p,
+        "total" => Normalization::Total(None),
+        x if x.starts_with("total:") => {
+            let target: f64 = x["total:".len()..].parse().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput,
+                               format!("can't parse the target of {}", x))
+            })?;
+            Normalization::Total(Some(target))
+        }
+        x => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown normalization {}, expected cpm, total[:<target>], log1p or cpm+log1p", x),
+            ))
+        }
+    };
+
+    Ok(method)
+}
+
+pub fn median(values: &[f64]) -> f64 {
+    let mut sorted: Vec<f64> = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+
+    match sorted.len() {
+        0 => 0.0,
+        n if n % 2 == 1 => sorted[n / 2],
+        n => (sorted[n / 2 - 1] + sorted[n / 2]) / 2.0,
+    }
+}
+
+impl Normalization {
+    // target total of every cell, None if the counts aren't rescaled
+    pub fn target(&self, totals: &[f64]) -> Option<f64> {
+        match self {
+            Normalization::Cpm | Normalization::CpmLog1p => Some(1e6),
+            Normalization::Total(Some(target)) => Some(*target),
+            Normalization::Total(None) => Some(median(totals)),
+            Normalization::Log1p => None,
+        }
+    }
+
+    fn takes_log(&self) -> bool {
+        matches!(self, Normalization::Log1p | Normalization::CpmLog1p)
+    }
+
+    // zeros stay zeros, so the bit vector of the cell is unchanged
+    pub fn apply(&self, exp: &mut [f32], total: f64, target: Option<f64>) {
+        let scale = match (target, total > 0.0) {
+            (Some(target), true) => target / total,
+            _ => 1.0,
+        };
+
+        for value in exp.iter_mut() {
+            let mut norm = *value as f64 * scale;
+            if self.takes_log() {
+                norm = norm.ln_1p();
+            }
+            *value = norm as f32;
+        }
+    }
+}
+
+// per-cell normalization for the streaming commands, the target total is
+// fixed up front, which takes a pass over the input for the median
+pub struct CellNormalizer {
+    method: Normalization,
+    target: Option<f64>,
+}
+
+impl CellNormalizer {
+    pub fn new(
+        input: &str,
+        input_type: FileType,
+        num_cells: usize,
+        num_features: usize,
+        method: Normalization,
+    ) -> Result<CellNormalizer, io::Error> {
+        let mut totals: Vec<f64> = Vec::new();
+        if method == Normalization::Total(None) {
+            totals.reserve(num_cells);
+            for_each_cell(input, input_type, num_cells, num_features, |_, _, exp| {
+                totals.push(cell_total(&exp));
+                Ok(())
+            })?;
+        }
+
+        Ok(CellNormalizer::from_totals(method, &totals))
+    }
+
+    // the totals of every cell are only looked at for the median
+    pub fn from_totals(method: Normalization, totals: &[f64]) -> CellNormalizer {
+        let target = method.target(totals);
+        info!("Normalizing with {:?}, target total {:?}", method, target);
+
+        CellNormalizer { method, target }
+    }
+
+    pub fn apply(&self, exp: &mut [f32]) {
+        let total = cell_total(exp);
+        self.method.apply(exp, total, self.target);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the cells normalized one at a time, totals of 2, 6 and 0
+    fn normalized(value: &str) -> Vec<Vec<f32>> {
+        let mut alphas: Vec<Vec<f32>> = vec![vec![1.0, 1.0], vec![1.0, 2.0, 3.0], vec![]];
+        let totals: Vec<f64> = alphas.iter().map(|exp| cell_total(exp)).collect();
+
+        let normalizer = CellNormalizer::from_totals(parse_normalization(value).unwrap(), &totals);
+        for exp in alphas.iter_mut() {
+            normalizer.apply(exp);
+        }
+        alphas
+    }
+
+    fn assert_close(actual: &[Vec<f32>], expected: &[Vec<f64>]) {
+        assert_eq!(actual.len(), expected.len());
+        for (cell, expected) in actual.iter().zip(expected.iter()) {
+            assert_eq!(cell.len(), expected.len());
+            for (actual, expected) in cell.iter().zip(expected.iter()) {
+                assert!((*actual as f64 - expected).abs() < 1e-6 * expected.max(1.0),
+                        "expected {:?}, found {:?}", expected, cell);
+            }
+        }
+    }
+
+    #[test]
+    fn cpm() {
+        assert_close(&normalized("cpm"), &[vec![5e5, 5e5], vec![1e6 / 6.0, 2e6 / 6.0, 3e6 / 6.0], vec![]]);
+    }
+
+    #[test]
+    fn total_to_a_target() {
+        assert_close(&normalized("total:12"), &[vec![6.0, 6.0], vec![2.0, 4.0, 6.0], vec![]]);
+    }
+
+    #[test]
+    fn total_to_the_median() {
+        // the median of the totals 0, 2 and 6
+        assert_close(&normalized("total"), &[vec![1.0, 1.0], vec![1.0 / 3.0, 2.0 / 3.0, 1.0], vec![]]);
+    }
+
+    #[test]
+    fn log1p() {
+        let expected = vec![vec![2f64.ln(), 2f64.ln()], vec![2f64.ln(), 3f64.ln(), 4f64.ln()], vec![]];
+        assert_close(&normalized("log1p"), &expected);
+    }
+
+    #[test]
+    fn cpm_log1p() {
+        let expected = vec![
+            vec![5e5f64.ln_1p(), 5e5f64.ln_1p()],
+            vec![(1e6f64 / 6.0).ln_1p(), (2e6f64 / 6.0).ln_1p(), 5e5f64.ln_1p()],
+            vec![],
+        ];
+        assert_close(&normalized("cpm+log1p"), &expected);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(parse_normalization("total:x").is_err());
+        assert!(parse_normalization("scran").is_err());
+    }
+}
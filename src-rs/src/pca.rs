@@ -0,0 +1,562 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+
+use rand::Rng;
+
+use crate::normalize::CellNormalizer;
+use crate::sampling;
+use crate::stats::FeatureStats;
+use crate::utils::{for_each_cell, get_positions, FileType};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TableFormat {
+    Tsv,
+    Npy,
+}
+
+impl TableFormat {
+    pub fn extension(&self) -> &str {
+        match self {
+            TableFormat::Tsv => ".tsv",
+            TableFormat::Npy => ".npy",
+        }
+    }
+}
+
+pub struct Options {
+    pub num_components: usize,
+    pub oversampling: usize,
+    pub iterations: usize,
+}
+
+// the centered, log-normalized matrix is never built, every product with
+// it is one pass over the input and only feature x components matrices
+// are kept in memory
+pub struct Pca {
+    normalizer: Option<CellNormalizer>,
+    pub means: Vec<f64>,
+    pub total_variance: f64,
+    // row-major features x components
+    pub loadings: Vec<f64>,
+    pub singular_values: Vec<f64>,
+    pub num_cells: usize,
+}
+
+struct Input<'a> {
+    path: &'a str,
+    file_type: FileType,
+    num_cells: usize,
+    num_features: usize,
+}
+
+impl<'a> Input<'a> {
+    // calls back with the normalized entries of every cell
+    fn for_each_normalized<F>(&self, normalizer: &Option<CellNormalizer>,
+                              mut callback: F) -> Result<(), io::Error>
+    where F: FnMut(usize, &[usize], &[f32]) {
+        for_each_cell(self.path, self.file_type.clone(), self.num_cells, self.num_features,
+                      |cell_id, bit_vec, mut exp| {
+            let positions = get_positions(&bit_vec);
+            assert!(positions.len() == exp.len(),
+                    "#positions doesn't match with #expressed features");
+
+            if let Some(normalizer) = normalizer {
+                normalizer.apply(&mut exp);
+            }
+
+            callback(cell_id, &positions, &exp);
+            Ok(())
+        })
+    }
+}
+
+// rows of the centered cell times a features x width matrix
+fn project(positions: &[usize], values: &[f32], matrix: &[f64], width: usize,
+           mean_projection: &[f64], row: &mut [f64]) {
+    for (column, value) in row.iter_mut().enumerate() {
+        *value = -mean_projection[column];
+    }
+
+    for (position, value) in positions.iter().zip(values) {
+        let offset = position * width;
+        for column in 0..width {
+            row[column] += *value as f64 * matrix[offset + column];
+        }
+    }
+}
+
+// means^T times a features x width matrix
+fn mean_projection(means: &[f64], matrix: &[f64], width: usize) -> Vec<f64> {
+    let mut projection: Vec<f64> = vec![0.0; width];
+    for (feature_id, mean) in means.iter().enumerate() {
+        for column in 0..width {
+            projection[column] += mean * matrix[feature_id * width + column];
+        }
+    }
+    projection
+}
+
+// modified gram-schmidt on the columns, run twice for stability. Columns
+// that vanish are left as zeros.
+fn orthonormalize(matrix: &mut [f64], num_rows: usize, width: usize) {
+    for _ in 0..2 {
+        for column in 0..width {
+            for previous in 0..column {
+                let dot: f64 = (0..num_rows)
+                    .map(|row| matrix[row * width + column] * matrix[row * width + previous])
+                    .sum();
+                for row in 0..num_rows {
+                    matrix[row * width + column] -= dot * matrix[row * width + previous];
+                }
+            }
+
+            let norm: f64 = (0..num_rows)
+                .map(|row| matrix[row * width + column].powi(2))
+                .sum::<f64>()
+                .sqrt();
+            for row in 0..num_rows {
+                matrix[row * width + column] = match norm > 1e-12 {
+                    true => matrix[row * width + column] / norm,
+                    false => 0.0,
+                };
+            }
+        }
+    }
+}
+
+// cyclic jacobi rotations on a small symmetric matrix, returns the
+// eigenvalues in decreasing order and the eigenvectors as columns
+fn symmetric_eigen(mut a: Vec<f64>, n: usize) -> (Vec<f64>, Vec<f64>) {
+    let mut vectors: Vec<f64> = vec![0.0; n * n];
+    for i in 0..n {
+        vectors[i * n + i] = 1.0;
+    }
+
+    for _ in 0..100 {
+        let off: f64 = (0..n)
+            .flat_map(|i| (0..n).filter(move |j| *j != i).map(move |j| (i, j)))
+            .map(|(i, j)| a[i * n + j].powi(2))
+            .sum();
+        if off < 1e-22 {
+            break;
+        }
+
+        for p in 0..n {
+            for q in p + 1..n {
+                if a[p * n + q].abs() < 1e-300 {
+                    continue;
+                }
+
+                let theta = (a[q * n + q] - a[p * n + p]) / (2.0 * a[p * n + q]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let t = if theta == 0.0 { 1.0 } else { t };
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+
+                for k in 0..n {
+                    let (akp, akq) = (a[k * n + p], a[k * n + q]);
+                    a[k * n + p] = c * akp - s * akq;
+                    a[k * n + q] = s * akp + c * akq;
+                }
+                for k in 0..n {
+                    let (apk, aqk) = (a[p * n + k], a[q * n + k]);
+                    a[p * n + k] = c * apk - s * aqk;
+                    a[q * n + k] = s * apk + c * aqk;
+                }
+                for k in 0..n {
+                    let (vkp, vkq) = (vectors[k * n + p], vectors[k * n + q]);
+                    vectors[k * n + p] = c * vkp - s * vkq;
+                    vectors[k * n + q] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|x, y| a[y * n + y].total_cmp(&a[x * n + x]));
+
+    let values: Vec<f64> = order.iter().map(|x| a[x * n + x]).collect();
+    let mut sorted_vectors: Vec<f64> = vec![0.0; n * n];
+    for (new_column, column) in order.iter().enumerate() {
+        for row in 0..n {
+            sorted_vectors[row * n + new_column] = vectors[row * n + column];
+        }
+    }
+
+    (values, sorted_vectors)
+}
+
+pub fn fit<R: Rng>(
+    input_path: &str,
+    input_type: FileType,
+    num_cells: usize,
+    num_features: usize,
+    normalizer: Option<CellNormalizer>,
+    options: &Options,
+    rng: &mut R,
+) -> Result<Pca, io::Error> {
+    let input = Input {
+        path: input_path,
+        file_type: input_type,
+        num_cells,
+        num_features,
+    };
+
+    let max_rank = num_cells.min(num_features);
+    let num_components = options.num_components.min(max_rank);
+    let width = (num_components + options.oversampling).min(max_rank);
+    if num_components < options.num_components {
+        warn!("Only {} components can be computed, asked for {}",
+              num_components, options.num_components);
+    }
+
+    let mut stats = FeatureStats::new(num_features);
+    input.for_each_normalized(&normalizer, |_, positions, values| {
+        stats.add_cell(positions, values);
+    })?;
+    let means: Vec<f64> = (0..num_features).map(|x| stats.mean(x)).collect();
+    let total_variance: f64 = (0..num_features).map(|x| stats.variance(x)).sum();
+
+    info!("Running randomized PCA with {} components, {} power iterations",
+          num_components, options.iterations);
+
+    let mut basis: Vec<f64> = (0..num_features * width).map(|_| sampling::normal(rng)).collect();
+    orthonormalize(&mut basis, num_features, width);
+
+    // power iterations: basis <- orth(A^T A basis), one pass each
+    let mut row: Vec<f64> = vec![0.0; width];
+    for iteration in 0..options.iterations + 1 {
+        let projection = mean_projection(&means, &basis, width);
+        let mut next: Vec<f64> = vec![0.0; num_features * width];
+        let mut row_sums: Vec<f64> = vec![0.0; width];
+
+        input.for_each_normalized(&normalizer, |_, positions, values| {
+            project(positions, values, &basis, width, &projection, &mut row);
+            for (position, value) in positions.iter().zip(values) {
+                let offset = position * width;
+                for column in 0..width {
+                    next[offset + column] += *value as f64 * row[column];
+                }
+            }
+            for column in 0..width {
+                row_sums[column] += row[column];
+            }
+        })?;
+
+        for (feature_id, mean) in means.iter().enumerate() {
+            for column in 0..width {
+                next[feature_id * width + column] -= mean * row_sums[column];
+            }
+        }
+
+        orthonormalize(&mut next, num_features, width);
+        basis = next;
+        info!("Done power iteration {}", iteration + 1);
+    }
+
+    // (A basis)^T (A basis) is small, its eigenvectors rotate the basis
+    // onto the right singular vectors
+    let projection = mean_projection(&means, &basis, width);
+    let mut gram: Vec<f64> = vec![0.0; width * width];
+    input.for_each_normalized(&normalizer, |_, positions, values| {
+        project(positions, values, &basis, width, &projection, &mut row);
+        for i in 0..width {
+            for j in 0..width {
+                gram[i * width + j] += row[i] * row[j];
+            }
+        }
+    })?;
+
+    let (eigenvalues, rotation) = symmetric_eigen(gram, width);
+    let singular_values: Vec<f64> = eigenvalues.iter()
+        .take(num_components)
+        .map(|x| x.max(0.0).sqrt())
+        .collect();
+
+    let mut loadings: Vec<f64> = vec![0.0; num_features * num_components];
+    for feature_id in 0..num_features {
+        for component in 0..num_components {
+            loadings[feature_id * num_components + component] = (0..width)
+                .map(|k| basis[feature_id * width + k] * rotation[k * width + component])
+                .sum();
+        }
+    }
+
+    // the sign is arbitrary, make the largest loading of every component positive
+    for component in 0..num_components {
+        let largest = (0..num_features)
+            .map(|x| loadings[x * num_components + component])
+            .fold(0.0f64, |acc, x| if x.abs() > acc.abs() { x } else { acc });
+        if largest < 0.0 {
+            for feature_id in 0..num_features {
+                loadings[feature_id * num_components + component] *= -1.0;
+            }
+        }
+    }
+
+    Ok(Pca {
+        normalizer,
+        means,
+        total_variance,
+        loadings,
+        singular_values,
+        num_cells,
+    })
+}
+
+fn npy_header(num_rows: usize, num_columns: usize) -> Vec<u8> {
+    let mut dict = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}",
+                           num_rows, num_columns);
+
+    // magic, version and header length take 10 bytes, pad the whole
+    // header to a multiple of 64 with the newline last
+    while (10 + dict.len() + 1) % 64 != 0 {
+        dict.push(' ');
+    }
+    dict.push('\n');
+
+    let mut header: Vec<u8> = b"\x93NUMPY\x01\x00".to_vec();
+    header.extend_from_slice(&(dict.len() as u16).to_le_bytes());
+    header.extend_from_slice(dict.as_bytes());
+    header
+}
+
+struct TableWriter {
+    file: BufWriter<File>,
+    format: TableFormat,
+}
+
+impl TableWriter {
+    fn new(path: &str, format: &TableFormat, row_label: &str,
+           num_rows: usize, num_columns: usize) -> Result<TableWriter, io::Error> {
+        let mut file = BufWriter::new(File::create(path)?);
+        match format {
+            TableFormat::Tsv => {
+                let columns: Vec<String> = (1..num_columns + 1).map(|x| format!("PC{}", x)).collect();
+                writeln!(file, "{}\t{}", row_label, columns.join("\t"))?;
+            }
+            TableFormat::Npy => file.write_all(&npy_header(num_rows, num_columns))?,
+        };
+
+        Ok(TableWriter { file, format: format.clone() })
+    }
+
+    fn write_row(&mut self, name: &str, values: &[f64]) -> Result<(), io::Error> {
+        match self.format {
+            TableFormat::Tsv => {
+                let values: Vec<String> = values.iter().map(|x| x.to_string()).collect();
+                writeln!(self.file, "{}\t{}", name, values.join("\t"))
+            }
+            TableFormat::Npy => {
+                for value in values {
+                    self.file.write_all(&(*value as f32).to_le_bytes())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn name_of(names: Option<&Vec<String>>, index: usize) -> String {
+    match names {
+        Some(names) => names[index].clone(),
+        None => (index + 1).to_string(),
+    }
+}
+
+impl Pca {
+    pub fn num_components(&self) -> usize {
+        self.singular_values.len()
+    }
+
+    // the cell embeddings need one more pass, they are written as they come
+    pub fn write_embeddings(
+        &self,
+        input_path: &str,
+        input_type: FileType,
+        num_features: usize,
+        path: &str,
+        format: &TableFormat,
+        barcodes: Option<&Vec<String>>,
+    ) -> Result<(), io::Error> {
+        info!("Writing cell embeddings into file path: {}", path);
+        let width = self.num_components();
+        let input = Input {
+            path: input_path,
+            file_type: input_type,
+            num_cells: self.num_cells,
+            num_features,
+        };
+
+        let mut writer = TableWriter::new(path, format, "cell", self.num_cells, width)?;
+        let projection = mean_projection(&self.means, &self.loadings, width);
+        let mut row: Vec<f64> = vec![0.0; width];
+        let mut result: Result<(), io::Error> = Ok(());
+
+        input.for_each_normalized(&self.normalizer, |cell_id, positions, values| {
+            project(positions, values, &self.loadings, width, &projection, &mut row);
+            if result.is_ok() {
+                result = writer.write_row(&name_of(barcodes, cell_id), &row);
+            }
+        })?;
+        result?;
+
+        writer.file.flush()
+    }
+
+    pub fn write_loadings(
+        &self,
+        path: &str,
+        format: &TableFormat,
+        names: Option<&Vec<String>>,
+    ) -> Result<(), io::Error> {
+        info!("Writing feature loadings into file path: {}", path);
+        let width = self.num_components();
+        let num_features = self.means.len();
+
+        let mut writer = TableWriter::new(path, format, "feature", num_features, width)?;
+        for feature_id in 0..num_features {
+            writer.write_row(&name_of(names, feature_id),
+                             &self.loadings[feature_id * width..(feature_id + 1) * width])?;
+        }
+
+        writer.file.flush()
+    }
+
+    pub fn write_variance(&self, path: &str) -> Result<(), io::Error> {
+        info!("Writing explained variance into file path: {}", path);
+        let mut file = BufWriter::new(File::create(path)?);
+
+        writeln!(file, "component\tsingular_value\tvariance\tvariance_ratio")?;
+        for (component, singular_value) in self.singular_values.iter().enumerate() {
+            let variance = match self.num_cells {
+                0 | 1 => 0.0,
+                n => singular_value * singular_value / (n - 1) as f64,
+            };
+            let ratio = match self.total_variance > 0.0 {
+                true => variance / self.total_variance,
+                false => 0.0,
+            };
+            writeln!(file, "PC{}\t{}\t{}\t{}", component + 1, singular_value, variance, ratio)?;
+        }
+
+        file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{get_rng, temp_path, write_test_cells};
+
+    #[test]
+    fn symmetric_eigen_of_a_tridiagonal_matrix() {
+        let a = vec![2.0, 1.0, 0.0, 1.0, 2.0, 1.0, 0.0, 1.0, 2.0];
+        let (values, vectors) = symmetric_eigen(a.clone(), 3);
+
+        let sqrt2 = 2.0f64.sqrt();
+        for (actual, expected) in values.iter().zip([2.0 + sqrt2, 2.0, 2.0 - sqrt2].iter()) {
+            assert!((actual - expected).abs() < 1e-10, "expected {}, found {}", expected, actual);
+        }
+
+        // a v = lambda v for every column
+        for column in 0..3 {
+            for row in 0..3 {
+                let av: f64 = (0..3).map(|k| a[row * 3 + k] * vectors[k * 3 + column]).sum();
+                assert!((av - values[column] * vectors[row * 3 + column]).abs() < 1e-10);
+            }
+        }
+
+        // the top eigenvector is (1, sqrt 2, 1) / 2 up to the sign
+        let sign = vectors[0].signum();
+        for (row, expected) in [0.5, sqrt2 / 2.0, 0.5].iter().enumerate() {
+            assert!((sign * vectors[row * 3] - expected).abs() < 1e-10);
+        }
+    }
+
+    #[test]
+    fn orthonormalize_drops_dependent_columns() {
+        // the second column is twice the first
+        let mut matrix = vec![1.0, 2.0, 1.0, 0.0, 0.0, 2.0, 1.0, 2.0, 0.0, 0.0, 0.0, 1.0];
+        orthonormalize(&mut matrix, 4, 3);
+
+        let dot = |a: usize, b: usize| -> f64 { (0..4).map(|row| matrix[row * 3 + a] * matrix[row * 3 + b]).sum() };
+        assert!((dot(0, 0) - 1.0).abs() < 1e-12);
+        assert!(dot(1, 1).abs() < 1e-12);
+        assert!((dot(2, 2) - 1.0).abs() < 1e-12);
+        assert!(dot(0, 2).abs() < 1e-12);
+    }
+
+    #[test]
+    fn project_centers_the_cell() {
+        let matrix = vec![1.0, 0.0, 0.0, 1.0, 1.0, 1.0];
+        let means = vec![1.0, 2.0, 3.0];
+        let centering = mean_projection(&means, &matrix, 2);
+        assert_eq!(centering, vec![4.0, 5.0]);
+
+        let mut row = vec![0.0; 2];
+        project(&[0, 2], &[2.0, 5.0], &matrix, 2, &centering, &mut row);
+        assert_eq!(row, vec![3.0, 0.0]);
+    }
+
+    #[test]
+    fn fit_recovers_a_rank_two_matrix() {
+        // centered, the cells are 4 sqrt2 u1 v1 + 2 sqrt2 u2 v2 with
+        // u1 = (1, -1, 1, -1) / 2, u2 = (1, 1, -1, -1) / 2,
+        // v1 = (1, 1, 0, 0) / sqrt2 and v2 = (0, 0, 1, -1) / sqrt2
+        let cells = vec![
+            (vec![0, 1, 2], vec![5.0, 5.0, 3.0]),
+            (vec![0, 1, 2], vec![1.0, 1.0, 3.0]),
+            (vec![0, 1, 2, 3], vec![5.0, 5.0, 1.0, 2.0]),
+            (vec![0, 1, 2, 3], vec![1.0, 1.0, 1.0, 2.0]),
+        ];
+        let input = write_test_cells("pca-input", FileType::EDS, 4, &cells);
+
+        let options = Options { num_components: 2, oversampling: 2, iterations: 2 };
+        let pca = fit(&input, FileType::EDS, 4, 4, None, &options, &mut get_rng(Some(3))).unwrap();
+
+        let sqrt2 = 2.0f64.sqrt();
+        assert_eq!(pca.means, vec![3.0, 3.0, 2.0, 1.0]);
+        for (actual, expected) in pca.singular_values.iter().zip([4.0 * sqrt2, 2.0 * sqrt2].iter()) {
+            assert!((actual - expected).abs() < 1e-6, "expected {}, found {}", expected, actual);
+        }
+
+        // the second component has no largest loading, fix its sign
+        let sign = pca.loadings[2 * 2 + 1].signum();
+        let expected = [[1.0, 0.0], [1.0, 0.0], [0.0, 1.0], [0.0, -1.0]];
+        for (feature_id, row) in expected.iter().enumerate() {
+            for (component, value) in row.iter().enumerate() {
+                let loading = pca.loadings[feature_id * 2 + component];
+                let loading = if component == 1 { sign * loading } else { loading };
+                assert!((loading - value / sqrt2).abs() < 1e-6,
+                        "loading {} of feature {} is {}", component + 1, feature_id + 1, loading);
+            }
+        }
+
+        let path = temp_path("pca-embeddings.npy");
+        pca.write_embeddings(&input, FileType::EDS, 4, &path, &TableFormat::Npy, None).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&input).unwrap();
+
+        assert_eq!(&bytes[..8], b"\x93NUMPY\x01\x00");
+        let header_size = 10 + u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        assert_eq!(header_size % 64, 0);
+        let header = std::str::from_utf8(&bytes[10..header_size]).unwrap();
+        assert!(header.starts_with("{'descr': '<f4', 'fortran_order': False, 'shape': (4, 2), }"));
+        assert!(header.ends_with('\n'));
+
+        // one row of 2 f32 per cell, the cells are u S
+        let values: Vec<f32> = bytes[header_size..]
+            .chunks(4)
+            .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+            .collect();
+        assert_eq!(values.len(), 4 * 2);
+        let expected = [[2.0, 1.0], [-2.0, 1.0], [2.0, -1.0], [-2.0, -1.0]];
+        for (cell_id, row) in expected.iter().enumerate() {
+            assert!((values[cell_id * 2] as f64 - row[0] * sqrt2).abs() < 1e-5);
+            assert!((sign * values[cell_id * 2 + 1] as f64 - row[1] * sqrt2).abs() < 1e-5);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::io;
+
+use rand::seq::index;
+use rand::Rng;
+
+use crate::utils::{for_each_cell, CellWriter, FileType};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SampleSize {
+    Count(usize),
+    Fraction(f64),
+}
+
+// number of cells to draw from every stratum, largest remainder rounding
+fn allocate(strata_sizes: &[usize], size: &SampleSize) -> Vec<usize> {
+    let num_cells: usize = strata_sizes.iter().sum();
+    let target = match size {
+        SampleSize::Count(count) => (*count).min(num_cells),
+        SampleSize::Fraction(fraction) => ((fraction * num_cells as f64).round() as usize).min(num_cells),
+    };
+
+    if num_cells == 0 {
+        return vec![0; strata_sizes.len()];
+    }
+
+    let quotas: Vec<f64> = strata_sizes.iter()
+        .map(|size| target as f64 * *size as f64 / num_cells as f64)
+        .collect();
+    let mut counts: Vec<usize> = quotas.iter().map(|quota| quota.floor() as usize).collect();
+
+    let mut by_remainder: Vec<usize> = (0..quotas.len()).collect();
+    by_remainder.sort_by(|a, b| {
+        let (ra, rb) = (quotas[*a].fract(), quotas[*b].fract());
+        rb.total_cmp(&ra).then(a.cmp(b))
+    });
+
+    let mut left = target - counts.iter().sum::<usize>();
+    for stratum in by_remainder {
+        if left == 0 {
+            break;
+        }
+        if counts[stratum] < strata_sizes[stratum] {
+            counts[stratum] += 1;
+            left -= 1;
+        }
+    }
+
+    counts
+}
+
+// returns the sampled cells in increasing order, every cell without a
+// stratum is put into an extra one
+pub fn sample_cells<R: Rng>(
+    num_cells: usize,
+    size: &SampleSize,
+    strata: Option<&[Option<usize>]>,
+    rng: &mut R,
+) -> Vec<usize> {
+    let mut members: Vec<Vec<usize>> = Vec::new();
+    match strata {
+        Some(strata) => {
+            assert!(strata.len() == num_cells);
+            let num_strata = strata.iter().filter_map(|x| *x).max().map_or(0, |x| x + 1);
+            members = vec![Vec::new(); num_strata + 1];
+            for (cell_id, stratum) in strata.iter().enumerate() {
+                members[stratum.unwrap_or(num_strata)].push(cell_id);
+            }
+        }
+        None => members.push((0..num_cells).collect()),
+    };
+
+    let strata_sizes: Vec<usize> = members.iter().map(|cells| cells.len()).collect();
+    let counts = allocate(&strata_sizes, size);
+
+    let mut sampled: Vec<usize> = Vec::new();
+    for (cells, count) in members.iter().zip(counts) {
+        for position in index::sample(rng, cells.len(), count.min(cells.len())).into_iter() {
+            sampled.push(cells[position]);
+        }
+    }
+    sampled.sort();
+
+    info!("Sampled {} out of {} cells", sampled.len(), num_cells);
+    sampled
+}
+
+// writes the sampled cells and, if asked, the rest of them into a second writer
+pub fn write_sample(
+    input: &str,
+    input_type: FileType,
+    num_cells: usize,
+    num_features: usize,
+    sampled: &[usize],
+    writer: &mut CellWriter,
+    mut rest_writer: Option<&mut CellWriter>,
+) -> Result<(), io::Error> {
+    let mut is_sampled: Vec<bool> = vec![false; num_cells];
+    for cell_id in sampled {
+        is_sampled[*cell_id] = true;
+    }
+
+    for_each_cell(input, input_type, num_cells, num_features, |cell_id, bit_vec, exp| {
+        match (is_sampled[cell_id], rest_writer.as_deref_mut()) {
+            (true, _) => writer.write_cell(bit_vec, exp),
+            (false, Some(rest_writer)) => rest_writer.write_cell(bit_vec, exp),
+            (false, None) => Ok(()),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::utils::get_rng;
+
+    #[test]
+    fn allocate_by_largest_remainder() {
+        // quotas of 1.5, 4.5 and 9, the tie goes to the first stratum
+        let counts = allocate(&[10, 30, 60], &SampleSize::Count(15));
+        assert_eq!(counts, vec![2, 4, 9]);
+
+        // quotas of 2.4, 3.3 and 4.3
+        let counts = allocate(&[8, 11, 14, 0], &SampleSize::Count(10));
+        assert_eq!(counts, vec![3, 3, 4, 0]);
+
+        let counts = allocate(&[8, 11, 14], &SampleSize::Fraction(0.5));
+        assert_eq!(counts.iter().sum::<usize>(), 17);
+        assert_eq!(counts, vec![4, 6, 7]);
+
+        // more than there is takes everything
+        assert_eq!(allocate(&[2, 3], &SampleSize::Count(10)), vec![2, 3]);
+        assert_eq!(allocate(&[], &SampleSize::Count(10)), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn sample_is_proportional_per_label() {
+        // 30 cells of label 0, 60 of label 1 and 10 without a label
+        let strata: Vec<Option<usize>> = (0..100)
+            .map(|cell_id| match cell_id % 10 {
+                0 => None,
+                x if x < 4 => Some(0),
+                _ => Some(1),
+            })
+            .collect();
+
+        let sampled = sample_cells(100, &SampleSize::Count(20), Some(&strata), &mut get_rng(Some(3)));
+        assert_eq!(sampled.len(), 20);
+        assert!(sampled.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let count_of = |label: Option<usize>| sampled.iter().filter(|x| strata[**x] == label).count();
+        assert_eq!((count_of(Some(0)), count_of(Some(1)), count_of(None)), (6, 12, 2));
+
+        let again = sample_cells(100, &SampleSize::Count(20), Some(&strata), &mut get_rng(Some(3)));
+        assert_eq!(sampled, again);
+    }
+}
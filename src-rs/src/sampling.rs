@@ -0,0 +1,128 @@
+use rand::Rng;
+
+pub fn normal<R: Rng>(rng: &mut R) -> f64 {
+    // standard normal through box-muller, 1 - u keeps the logarithm finite
+    let (u, v) = (1.0 - rng.gen::<f64>(), rng.gen::<f64>());
+    (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos()
+}
+
+// marsaglia and tsang, shape at least 1
+pub fn gamma<R: Rng>(shape: f64, rng: &mut R) -> f64 {
+    let d = shape - 1.0 / 3.0;
+    let c = 1.0 / (9.0 * d).sqrt();
+    loop {
+        let x = normal(rng);
+        let v = (1.0 + c * x).powi(3);
+        if v <= 0.0 {
+            continue;
+        }
+
+        let u = 1.0 - rng.gen::<f64>();
+        if u < 1.0 - 0.0331 * x.powi(4) || u.ln() < 0.5 * x * x + d * (1.0 - v + v.ln()) {
+            return d * v;
+        }
+    }
+}
+
+// exact binomial draw: the a-th smallest of n uniforms is beta(a, n + 1 - a)
+// distributed, which splits the trials around it (knuth) until the
+// expected count is small enough for inversion
+pub fn binomial<R: Rng>(n: u64, p: f64, rng: &mut R) -> u64 {
+    if n == 0 || p <= 0.0 {
+        return 0;
+    }
+    if p >= 1.0 {
+        return n;
+    }
+    if p > 0.5 {
+        return n - binomial(n, 1.0 - p, rng);
+    }
+
+    if (n as f64) * p < 10.0 {
+        let ratio = p / (1.0 - p);
+        let mut probability = (1.0 - p).powf(n as f64);
+        let mut u = rng.gen::<f64>();
+        let mut k = 0;
+        while u > probability && k < n {
+            u -= probability;
+            k += 1;
+            probability *= (n - k + 1) as f64 / k as f64 * ratio;
+        }
+        return k;
+    }
+
+    let a = 1 + n / 2;
+    let b = n + 1 - a;
+    let x = gamma(a as f64, rng);
+    let x = x / (x + gamma(b as f64, rng));
+    match x >= p {
+        true => binomial(a - 1, p / x, rng),
+        false => a + binomial(b - 1, (p - x) / (1.0 - x), rng),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    // sample mean and variance
+    fn moments(samples: &[f64]) -> (f64, f64) {
+        let n = samples.len() as f64;
+        let mean = samples.iter().sum::<f64>() / n;
+        let variance = samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0);
+        (mean, variance)
+    }
+
+    #[test]
+    fn normal_moments() {
+        let mut rng = ChaCha8Rng::seed_from_u64(7);
+        let samples: Vec<f64> = (0..100_000).map(|_| normal(&mut rng)).collect();
+        let (mean, variance) = moments(&samples);
+
+        assert!(mean.abs() < 0.015, "mean {}", mean);
+        assert!((variance - 1.0).abs() < 0.02, "variance {}", variance);
+        assert!(samples.iter().all(|x| x.is_finite()));
+    }
+
+    #[test]
+    fn gamma_moments() {
+        let mut rng = ChaCha8Rng::seed_from_u64(7);
+        for shape in [1.0, 3.5, 40.0].iter() {
+            let samples: Vec<f64> = (0..100_000).map(|_| gamma(*shape, &mut rng)).collect();
+            let (mean, variance) = moments(&samples);
+
+            assert!(samples.iter().all(|x| *x > 0.0));
+            assert!((mean / shape - 1.0).abs() < 0.01, "shape {} mean {}", shape, mean);
+            assert!((variance / shape - 1.0).abs() < 0.03, "shape {} variance {}", shape, variance);
+        }
+    }
+
+    #[test]
+    fn binomial_moments() {
+        let mut rng = ChaCha8Rng::seed_from_u64(7);
+        // inversion, splitting and the flipped p > 0.5 case
+        for (n, p) in [(20, 0.2), (1000, 0.3), (50, 0.9), (100_000, 0.5)].iter() {
+            let samples: Vec<u64> = (0..20_000).map(|_| binomial(*n, *p, &mut rng)).collect();
+            assert!(samples.iter().all(|x| x <= n));
+
+            let samples: Vec<f64> = samples.into_iter().map(|x| x as f64).collect();
+            let (mean, variance) = moments(&samples);
+            let expected_variance = *n as f64 * p * (1.0 - p);
+
+            assert!((mean - *n as f64 * p).abs() < 4.0 * (expected_variance / 20_000.0).sqrt(),
+                    "n {} p {} mean {}", n, p, mean);
+            assert!((variance / expected_variance - 1.0).abs() < 0.05,
+                    "n {} p {} variance {}", n, p, variance);
+        }
+    }
+
+    #[test]
+    fn binomial_edges() {
+        let mut rng = ChaCha8Rng::seed_from_u64(7);
+        assert_eq!(binomial(0, 0.5, &mut rng), 0);
+        assert_eq!(binomial(10, 0.0, &mut rng), 0);
+        assert_eq!(binomial(10, 1.0, &mut rng), 10);
+    }
+}
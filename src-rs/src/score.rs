@@ -0,0 +1,410 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+use rand::seq::index;
+use rand::Rng;
+
+use crate::normalize::CellNormalizer;
+use crate::stats::FeatureStats;
+use crate::utils::{for_each_cell, get_positions, FileType};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScoreMethod {
+    Mean,
+    // mean of the set minus the mean of control features drawn from the
+    // same expression bins (Seurat's AddModuleScore)
+    Seurat { num_controls: usize, num_bins: usize },
+    // area under the recovery curve of the set in the top ranked features
+    // of the cell (AUCell), max_rank as a fraction of the features
+    AUCell { max_rank: f64 },
+}
+
+impl ScoreMethod {
+    // no bin or no control would leave the control mean undefined
+    pub fn seurat(num_controls: usize, num_bins: usize) -> Result<ScoreMethod, io::Error> {
+        if num_controls == 0 || num_bins == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("seurat scores need at least one control and one bin, found {} and {}",
+                        num_controls, num_bins),
+            ));
+        }
+
+        Ok(ScoreMethod::Seurat { num_controls, num_bins })
+    }
+}
+
+pub struct Options {
+    pub method: ScoreMethod,
+    // applied to the values before scoring, the ranks of AUCell ignore it
+    pub normalizer: Option<CellNormalizer>,
+}
+
+pub struct GeneSet {
+    pub name: String,
+    pub features: Vec<usize>,
+}
+
+// GMT file: set name, description, then the features, tab separated
+pub fn read_gene_sets(file_path: &str, names: &[String]) -> Result<Vec<GeneSet>, io::Error> {
+    let file = BufReader::new(File::open(file_path)?);
+
+    let mut name_index: HashMap<&str, usize> = HashMap::new();
+    for (index, name) in names.iter().enumerate() {
+        name_index.entry(name.as_str()).or_insert(index);
+    }
+
+    let mut sets: Vec<GeneSet> = Vec::new();
+    for (line_id, line) in file.lines().enumerate() {
+        let record = line?;
+        if record.trim().is_empty() || record.starts_with('#') {
+            continue;
+        }
+
+        let vals: Vec<&str> = record.split('\t').map(|x| x.trim()).collect();
+        if vals.len() < 3 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {} of {} is not name<TAB>description<TAB>features...",
+                        line_id + 1, file_path),
+            ));
+        }
+
+        let mut features: Vec<usize> = Vec::new();
+        let mut num_missing = 0;
+        for feature in vals[2..].iter().filter(|x| !x.is_empty()) {
+            match name_index.get(feature) {
+                Some(index) => features.push(*index),
+                None => num_missing += 1,
+            };
+        }
+        features.sort();
+        features.dedup();
+
+        if num_missing > 0 {
+            warn!("{} features of set {} aren't in the input", num_missing, vals[0]);
+        }
+        if features.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("none of the features of set {} are in the input", vals[0]),
+            ));
+        }
+
+        sets.push(GeneSet { name: vals[0].to_string(), features });
+    }
+
+    info!("Found {} gene sets in {}", sets.len(), file_path);
+    Ok(sets)
+}
+
+// value of one feature, looked up in the bit vector without decoding the
+// other positions. byte_ranks holds the number of set bits before every byte.
+fn value_at(bit_vec: &[u8], exp: &[f32], byte_ranks: &[usize], position: usize) -> f32 {
+    let (byte, offset) = (position / 8, position % 8);
+    match bit_vec[byte] & (128u8 >> offset) {
+        0 => 0.0,
+        _ => {
+            let before = (bit_vec[byte] & !(0xffu8 >> offset)).count_ones() as usize;
+            exp[byte_ranks[byte] + before]
+        }
+    }
+}
+
+fn byte_ranks(bit_vec: &[u8], ranks: &mut Vec<usize>) {
+    ranks.clear();
+    let mut count = 0;
+    for byte in bit_vec {
+        ranks.push(count);
+        count += byte.count_ones() as usize;
+    }
+}
+
+// control features of every set, drawn without replacement from the
+// expression bin of each of its features
+fn control_sets<R: Rng>(
+    sets: &[GeneSet],
+    means: &[f64],
+    num_controls: usize,
+    num_bins: usize,
+    rng: &mut R,
+) -> Vec<Vec<usize>> {
+    let num_features = means.len();
+    let mut order: Vec<usize> = (0..num_features).collect();
+    order.sort_by(|a, b| means[*a].total_cmp(&means[*b]).then(a.cmp(b)));
+
+    // equal sized bins over the ranks of the means
+    let mut bin_of: Vec<usize> = vec![0; num_features];
+    let mut bins: Vec<Vec<usize>> = vec![Vec::new(); num_bins];
+    for (rank, feature_id) in order.iter().enumerate() {
+        let bin = rank * num_bins / num_features;
+        bin_of[*feature_id] = bin;
+        bins[bin].push(*feature_id);
+    }
+
+    sets.iter()
+        .map(|set| {
+            let mut controls: HashSet<usize> = HashSet::new();
+            for feature_id in set.features.iter() {
+                let bin = &bins[bin_of[*feature_id]];
+                for position in index::sample(rng, bin.len(), num_controls.min(bin.len())).into_iter() {
+                    controls.insert(bin[position]);
+                }
+            }
+
+            let mut controls: Vec<usize> = controls.into_iter().collect();
+            controls.sort();
+            controls
+        })
+        .collect()
+}
+
+// sum of (max_rank - rank + 1) over the ranks from first to last, the
+// contribution of the features at those ranks to the recovery curve
+fn recovery_area(first: usize, last: usize, max_rank: usize) -> f64 {
+    let last = last.min(max_rank);
+    if first > last {
+        return 0.0;
+    }
+
+    let (high, low) = ((max_rank - first + 1) as f64, (max_rank - last + 1) as f64);
+    (high + low) * (high - low + 1.0) / 2.0
+}
+
+pub fn score<R: Rng>(
+    input: &str,
+    input_type: FileType,
+    num_cells: usize,
+    num_features: usize,
+    sets: &[GeneSet],
+    options: &Options,
+    rng: &mut R,
+) -> Result<Vec<Vec<f64>>, io::Error> {
+    let (method, normalizer) = (&options.method, options.normalizer.as_ref());
+    let controls: Vec<Vec<usize>> = match method {
+        ScoreMethod::Seurat { num_controls, num_bins } => {
+            let mut stats = FeatureStats::new(num_features);
+            for_each_cell(input, input_type.clone(), num_cells, num_features, |_, bit_vec, mut exp| {
+                if let Some(normalizer) = normalizer {
+                    normalizer.apply(&mut exp);
+                }
+                stats.add_cell(&get_positions(&bit_vec), &exp);
+                Ok(())
+            })?;
+
+            let means: Vec<f64> = (0..num_features).map(|x| stats.mean(x)).collect();
+            control_sets(sets, &means, *num_controls, (*num_bins).min(num_features), rng)
+        }
+        _ => Vec::new(),
+    };
+
+    info!("Scoring {} cells on {} gene sets with {:?}", num_cells, sets.len(), method);
+    let mut scores: Vec<Vec<f64>> = Vec::with_capacity(num_cells);
+    let mut ranks: Vec<usize> = Vec::new();
+    let mut feature_ranks: Vec<usize> = vec![0; num_features];
+    let is_rank_based = matches!(method, ScoreMethod::AUCell { .. });
+
+    for_each_cell(input, input_type, num_cells, num_features, |_, bit_vec, mut exp| {
+        let set_mean = |features: &[usize], exp: &[f32], ranks: &[usize]| {
+            let sum: f64 = features.iter()
+                .map(|x| value_at(&bit_vec, exp, ranks, *x) as f64)
+                .sum();
+            sum / features.len() as f64
+        };
+
+        // the ranks of AUCell don't change with the normalization
+        if let (Some(normalizer), false) = (normalizer, is_rank_based) {
+            normalizer.apply(&mut exp);
+        }
+        byte_ranks(&bit_vec, &mut ranks);
+
+        let cell_scores: Vec<f64> = match method {
+            ScoreMethod::Mean => {
+                sets.iter().map(|set| set_mean(&set.features, &exp, &ranks)).collect()
+            }
+            ScoreMethod::Seurat { .. } => {
+                sets.iter()
+                    .zip(controls.iter())
+                    .map(|(set, controls)| {
+                        set_mean(&set.features, &exp, &ranks) - set_mean(controls, &exp, &ranks)
+                    })
+                    .collect()
+            }
+            ScoreMethod::AUCell { max_rank } => {
+                // ties between expressed features go by position, the
+                // unexpressed ones share the remaining ranks and count with
+                // their expected area
+                let max_rank = ((max_rank * num_features as f64).ceil() as usize).max(1);
+                let mut entries: Vec<(usize, f32)> = get_positions(&bit_vec).into_iter()
+                    .zip(exp)
+                    .filter(|x| x.1 != 0.0)
+                    .collect();
+                entries.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+
+                for (rank, entry) in entries.iter().enumerate() {
+                    feature_ranks[entry.0] = rank + 1;
+                }
+                let num_zeros = num_features - entries.len();
+                let zero_area = match num_zeros {
+                    0 => 0.0,
+                    n => recovery_area(entries.len() + 1, num_features, max_rank) / n as f64,
+                };
+
+                let cell_scores = sets.iter()
+                    .map(|set| {
+                        let area: f64 = set.features.iter()
+                            .map(|feature_id| match feature_ranks[*feature_id] {
+                                0 => zero_area,
+                                rank => recovery_area(rank, rank, max_rank),
+                            })
+                            .sum();
+
+                        // the set taking the top ranks
+                        let k = set.features.len().min(max_rank);
+                        let max_area = (k * (k + 1) / 2 + k * (max_rank - k)) as f64;
+                        area / max_area
+                    })
+                    .collect();
+
+                for entry in entries.iter() {
+                    feature_ranks[entry.0] = 0;
+                }
+                cell_scores
+            }
+        };
+
+        scores.push(cell_scores);
+        Ok(())
+    })?;
+
+    Ok(scores)
+}
+
+pub fn write_table(
+    path: &str,
+    sets: &[GeneSet],
+    scores: &[Vec<f64>],
+    barcodes: Option<&Vec<String>>,
+) -> Result<(), io::Error> {
+    info!("Writing scores into file path: {}", path);
+    let mut file = BufWriter::new(File::create(path)?);
+
+    let set_names: Vec<&str> = sets.iter().map(|set| set.name.as_str()).collect();
+    writeln!(file, "cell\t{}", set_names.join("\t"))?;
+    for (cell_id, cell_scores) in scores.iter().enumerate() {
+        let name = match barcodes {
+            Some(barcodes) => barcodes[cell_id].clone(),
+            None => (cell_id + 1).to_string(),
+        };
+        let values: Vec<String> = cell_scores.iter().map(|x| x.to_string()).collect();
+        writeln!(file, "{}\t{}", name, values.join("\t"))?;
+    }
+
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::utils::{get_rng, positions_to_bit_vec, write_test_cells};
+
+    // 3 cells x 6 features
+    const CELLS: [[f32; 6]; 3] = [
+        [5.0, 4.0, 3.0, 0.0, 0.0, 0.0],
+        [0.0, 0.0, 0.0, 1.0, 2.0, 3.0],
+        [1.0, 0.0, 2.0, 0.0, 3.0, 0.0],
+    ];
+
+    fn score_cells(name: &str, method: &ScoreMethod) -> Vec<Vec<f64>> {
+        let cells: Vec<(Vec<usize>, Vec<f32>)> = CELLS.iter()
+            .map(|cell| {
+                let positions: Vec<usize> = (0..6).filter(|x| cell[*x] != 0.0).collect();
+                let values: Vec<f32> = positions.iter().map(|x| cell[*x]).collect();
+                (positions, values)
+            })
+            .collect();
+        let path = write_test_cells(&format!("score-{}", name), FileType::EDS, 6, &cells);
+
+        let sets = vec![
+            GeneSet { name: "a".to_string(), features: vec![0, 1] },
+            GeneSet { name: "b".to_string(), features: vec![3, 4, 5] },
+        ];
+        let scores = score(&path, FileType::EDS, 3, 6, &sets,
+                           &Options { method: method.clone(), normalizer: None },
+                           &mut get_rng(Some(7)));
+        fs::remove_file(&path).unwrap();
+        scores.unwrap()
+    }
+
+    fn assert_scores(actual: &[Vec<f64>], expected: &[[f64; 2]; 3]) {
+        for (cell, expected) in actual.iter().zip(expected.iter()) {
+            for (actual, expected) in cell.iter().zip(expected.iter()) {
+                assert!((actual - expected).abs() < 1e-12, "expected {:?}, found {:?}", expected, cell);
+            }
+        }
+    }
+
+    #[test]
+    fn recovery_area_sums_the_reversed_ranks() {
+        // ranks 2 to 4 out of 5 add 4 + 3 + 2
+        assert_eq!(recovery_area(2, 4, 5), 9.0);
+        assert_eq!(recovery_area(4, 9, 5), 3.0);
+        assert_eq!(recovery_area(6, 9, 5), 0.0);
+    }
+
+    #[test]
+    fn value_at_finds_the_expressed_values() {
+        let bit_vec = positions_to_bit_vec(&[1, 8, 10], 12);
+        let mut ranks = Vec::new();
+        byte_ranks(&bit_vec, &mut ranks);
+
+        let exp = [1.0, 2.0, 3.0];
+        let values: Vec<f32> = (0..12).map(|x| value_at(&bit_vec, &exp, &ranks, x)).collect();
+        assert_eq!(values, vec![0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 2.0, 0.0, 3.0, 0.0]);
+    }
+
+    #[test]
+    fn mean_scores() {
+        let scores = score_cells("mean", &ScoreMethod::Mean);
+        assert_scores(&scores, &[[4.5, 0.0], [0.0, 2.0], [0.5, 1.0]]);
+    }
+
+    #[test]
+    fn seurat_scores_subtract_the_control_mean() {
+        // a single bin with more controls than features takes every feature
+        let method = ScoreMethod::Seurat { num_controls: 10, num_bins: 1 };
+        let scores = score_cells("seurat", &method);
+        assert_scores(&scores, &[[2.5, -2.0], [-1.0, 1.0], [-0.5, 0.0]]);
+    }
+
+    #[test]
+    fn seurat_needs_a_bin() {
+        let error = ScoreMethod::seurat(10, 0).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(ScoreMethod::seurat(10, 1).unwrap(),
+                   ScoreMethod::Seurat { num_controls: 10, num_bins: 1 });
+    }
+
+    #[test]
+    fn seurat_needs_a_control() {
+        let error = ScoreMethod::seurat(0, 5).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(ScoreMethod::seurat(1, 5).unwrap(),
+                   ScoreMethod::Seurat { num_controls: 1, num_bins: 5 });
+    }
+
+    #[test]
+    fn aucell_scores_within_the_top_ranks() {
+        // the top 3 ranks, unexpressed features fall outside of them
+        let scores = score_cells("aucell-half", &ScoreMethod::AUCell { max_rank: 0.5 });
+        assert_scores(&scores, &[[1.0, 0.0], [0.0, 1.0], [0.2, 0.5]]);
+
+        // all 6 ranks, the 3 unexpressed features of a cell share ranks 4 to 6
+        let scores = score_cells("aucell-all", &ScoreMethod::AUCell { max_rank: 1.0 });
+        assert_scores(&scores, &[[1.0, 0.4], [4.0 / 11.0, 1.0], [6.0 / 11.0, 10.0 / 15.0]]);
+    }
+}
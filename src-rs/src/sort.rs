@@ -0,0 +1,130 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use crate::utils::cell_total;
+
+pub enum SortKey {
+    Counts,
+    Features,
+    Barcode,
+    // barcode -> key table, compared as numbers if all of them parse
+    Table(HashMap<String, String>),
+}
+
+impl SortKey {
+    pub fn name(&self) -> &str {
+        match self {
+            SortKey::Counts => "total counts",
+            SortKey::Features => "detected features",
+            SortKey::Barcode => "barcode",
+            SortKey::Table(_) => "key table",
+        }
+    }
+}
+
+enum Key {
+    Number(f64),
+    Text(String),
+    Missing,
+}
+
+fn compare(a: &Key, b: &Key) -> Ordering {
+    match (a, b) {
+        (Key::Number(a), Key::Number(b)) => a.total_cmp(b),
+        (Key::Text(a), Key::Text(b)) => a.cmp(b),
+        (Key::Missing, Key::Missing) => Ordering::Equal,
+        (Key::Missing, _) => Ordering::Greater,
+        (_, Key::Missing) => Ordering::Less,
+        _ => unreachable!(),
+    }
+}
+
+// returns the new order of the cells, ties keep the input order and cells
+// without a key go last
+pub fn sort_order(
+    alphas: &[Vec<f32>],
+    barcodes: Option<&Vec<String>>,
+    key: &SortKey,
+    descending: bool,
+) -> Vec<usize> {
+    info!("Sorting {} cells by {}", alphas.len(), key.name());
+
+    let keys: Vec<Key> = match key {
+        SortKey::Counts => alphas.iter().map(|exp| Key::Number(cell_total(exp))).collect(),
+        SortKey::Features => alphas.iter().map(|exp| Key::Number(exp.len() as f64)).collect(),
+        SortKey::Barcode => barcodes.expect("sorting by barcode needs the cell barcodes")
+            .iter()
+            .map(|barcode| Key::Text(barcode.clone()))
+            .collect(),
+        SortKey::Table(table) => {
+            let barcodes = barcodes.expect("sorting by a key table needs the cell barcodes");
+            let numeric = table.values().all(|value| value.parse::<f64>().is_ok());
+
+            barcodes.iter()
+                .map(|barcode| match (table.get(barcode), numeric) {
+                    (Some(value), true) => Key::Number(value.parse().unwrap()),
+                    (Some(value), false) => Key::Text(value.clone()),
+                    (None, _) => Key::Missing,
+                })
+                .collect()
+        }
+    };
+
+    let num_missing = keys.iter().filter(|key| matches!(key, Key::Missing)).count();
+    if num_missing > 0 {
+        warn!("{} cells don't have a key, putting them last", num_missing);
+    }
+
+    let mut order: Vec<usize> = (0..alphas.len()).collect();
+    order.sort_by(|a, b| {
+        let ordering = match (descending, &keys[*a], &keys[*b]) {
+            (true, Key::Missing, _) | (true, _, Key::Missing) => compare(&keys[*a], &keys[*b]),
+            (true, key_a, key_b) => compare(key_b, key_a),
+            (false, key_a, key_b) => compare(key_a, key_b),
+        };
+        ordering.then(a.cmp(b))
+    });
+
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_strings(names: &[&str]) -> Vec<String> {
+        names.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn ties_keep_the_input_order() {
+        // totals of 3, 1, 3, 2 and 1
+        let alphas = vec![vec![1.0, 2.0], vec![1.0], vec![3.0], vec![2.0], vec![0.5, 0.5]];
+
+        let order = sort_order(&alphas, None, &SortKey::Counts, false);
+        assert_eq!(order, vec![1, 4, 3, 0, 2]);
+
+        let order = sort_order(&alphas, None, &SortKey::Counts, true);
+        assert_eq!(order, vec![0, 2, 3, 1, 4]);
+
+        let order = sort_order(&alphas, None, &SortKey::Features, true);
+        assert_eq!(order, vec![0, 4, 1, 2, 3]);
+    }
+
+    #[test]
+    fn missing_keys_go_last() {
+        let alphas = vec![vec![]; 4];
+        let barcodes = to_strings(&["a", "b", "c", "d"]);
+        let table: HashMap<String, String> = vec![("a", "10"), ("c", "9"), ("d", "10")].into_iter()
+            .map(|(barcode, key)| (barcode.to_string(), key.to_string()))
+            .collect();
+        let key = SortKey::Table(table);
+
+        // numbers, not text: 9 comes before 10
+        assert_eq!(sort_order(&alphas, Some(&barcodes), &key, false), vec![2, 0, 3, 1]);
+        assert_eq!(sort_order(&alphas, Some(&barcodes), &key, true), vec![0, 3, 2, 1]);
+
+        let barcodes = to_strings(&["b", "a", "c", "a"]);
+        assert_eq!(sort_order(&alphas, Some(&barcodes), &SortKey::Barcode, true), vec![2, 0, 1, 3]);
+    }
+}
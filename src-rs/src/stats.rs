@@ -0,0 +1,236 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+
+use crate::utils::{cell_total, for_each_cell, get_positions, json_number, json_string, FileType};
+
+pub struct CellStats {
+    pub totals: Vec<f64>,
+    pub detected: Vec<usize>,
+}
+
+// per-feature moments, the non-zero values are accumulated with Welford's
+// algorithm and merged with the implicit zeros (Chan et al.) on request
+pub struct FeatureStats {
+    pub num_cells: usize,
+    pub sums: Vec<f64>,
+    pub detected: Vec<usize>,
+    nz_means: Vec<f64>,
+    nz_m2s: Vec<f64>,
+}
+
+impl FeatureStats {
+    pub fn new(num_features: usize) -> FeatureStats {
+        FeatureStats {
+            num_cells: 0,
+            sums: vec![0.0; num_features],
+            detected: vec![0; num_features],
+            nz_means: vec![0.0; num_features],
+            nz_m2s: vec![0.0; num_features],
+        }
+    }
+
+    pub fn add_cell(&mut self, positions: &[usize], values: &[f32]) {
+        for (position, value) in positions.iter().zip(values) {
+            let value = *value as f64;
+            self.sums[*position] += value;
+            self.detected[*position] += 1;
+
+            let delta = value - self.nz_means[*position];
+            self.nz_means[*position] += delta / self.detected[*position] as f64;
+            self.nz_m2s[*position] += delta * (value - self.nz_means[*position]);
+        }
+
+        self.num_cells += 1;
+    }
+
+    pub fn mean(&self, feature_id: usize) -> f64 {
+        match self.num_cells {
+            0 => 0.0,
+            n => self.sums[feature_id] / n as f64,
+        }
+    }
+
+    // sample variance over all cells, zeros included
+    pub fn variance(&self, feature_id: usize) -> f64 {
+        if self.num_cells < 2 {
+            return 0.0;
+        }
+
+        let n = self.num_cells as f64;
+        let n_nz = self.detected[feature_id] as f64;
+        let n_zero = n - n_nz;
+        let nz_mean = self.nz_means[feature_id];
+
+        let m2 = self.nz_m2s[feature_id] + nz_mean * nz_mean * n_nz * n_zero / n;
+        m2 / (n - 1.0)
+    }
+
+    pub fn num_features(&self) -> usize {
+        self.sums.len()
+    }
+}
+
+pub fn collect(
+    input: &str,
+    input_type: FileType,
+    num_cells: usize,
+    num_features: usize,
+) -> Result<(CellStats, FeatureStats), io::Error> {
+    let mut cell_stats = CellStats {
+        totals: Vec::with_capacity(num_cells),
+        detected: Vec::with_capacity(num_cells),
+    };
+    let mut feature_stats = FeatureStats::new(num_features);
+
+    for_each_cell(input, input_type, num_cells, num_features, |_, bit_vec, exp| {
+        let positions = get_positions(&bit_vec);
+        assert!(positions.len() == exp.len(),
+                "#positions doesn't match with #expressed features");
+
+        cell_stats.totals.push(cell_total(&exp));
+        cell_stats.detected.push(exp.len());
+        feature_stats.add_cell(&positions, &exp);
+        Ok(())
+    })?;
+
+    let total_molecules: f64 = cell_stats.totals.iter().sum();
+    let total_exp_values: usize = cell_stats.detected.iter().sum();
+    info!("Found Total {:.2} molecules", total_molecules);
+    info!("Found Total {} expressed entries", total_exp_values);
+
+    Ok((cell_stats, feature_stats))
+}
+
+fn name_of(names: Option<&Vec<String>>, index: usize) -> String {
+    match names {
+        Some(names) => names[index].clone(),
+        None => (index + 1).to_string(),
+    }
+}
+
+pub fn write_tsv(
+    cell_path: &str,
+    feature_path: &str,
+    cell_stats: &CellStats,
+    feature_stats: &FeatureStats,
+    barcodes: Option<&Vec<String>>,
+    features: Option<&Vec<String>>,
+) -> Result<(), io::Error> {
+    info!("Writing cell stats into file path: {}", cell_path);
+    let mut file = BufWriter::new(File::create(cell_path)?);
+    writeln!(file, "cell\ttotal_counts\tdetected_features")?;
+    for (cell_id, total) in cell_stats.totals.iter().enumerate() {
+        writeln!(file, "{}\t{}\t{}",
+                 name_of(barcodes, cell_id), total, cell_stats.detected[cell_id])?;
+    }
+    file.flush()?;
+
+    info!("Writing feature stats into file path: {}", feature_path);
+    let mut file = BufWriter::new(File::create(feature_path)?);
+    writeln!(file, "feature\tsum\tmean\tvariance\tcells_expressing")?;
+    for feature_id in 0..feature_stats.num_features() {
+        writeln!(file, "{}\t{}\t{}\t{}\t{}",
+                 name_of(features, feature_id),
+                 feature_stats.sums[feature_id],
+                 feature_stats.mean(feature_id),
+                 feature_stats.variance(feature_id),
+                 feature_stats.detected[feature_id])?;
+    }
+    file.flush()?;
+
+    Ok(())
+}
+
+pub fn write_json(
+    cell_path: &str,
+    feature_path: &str,
+    cell_stats: &CellStats,
+    feature_stats: &FeatureStats,
+    barcodes: Option<&Vec<String>>,
+    features: Option<&Vec<String>>,
+) -> Result<(), io::Error> {
+    info!("Writing cell stats into file path: {}", cell_path);
+    let mut file = BufWriter::new(File::create(cell_path)?);
+    writeln!(file, "[")?;
+    for (cell_id, total) in cell_stats.totals.iter().enumerate() {
+        let separator = if cell_id + 1 < cell_stats.totals.len() { "," } else { "" };
+        writeln!(file, "  {{\"cell\": {}, \"total_counts\": {}, \"detected_features\": {}}}{}",
+                 json_string(&name_of(barcodes, cell_id)), json_number(*total),
+                 cell_stats.detected[cell_id], separator)?;
+    }
+    writeln!(file, "]")?;
+    file.flush()?;
+
+    info!("Writing feature stats into file path: {}", feature_path);
+    let mut file = BufWriter::new(File::create(feature_path)?);
+    writeln!(file, "[")?;
+    for feature_id in 0..feature_stats.num_features() {
+        let separator = if feature_id + 1 < feature_stats.num_features() { "," } else { "" };
+        writeln!(file, "  {{\"feature\": {}, \"sum\": {}, \"mean\": {}, \"variance\": {}, \"cells_expressing\": {}}}{}",
+                 json_string(&name_of(features, feature_id)),
+                 json_number(feature_stats.sums[feature_id]),
+                 json_number(feature_stats.mean(feature_id)),
+                 json_number(feature_stats.variance(feature_id)),
+                 feature_stats.detected[feature_id],
+                 separator)?;
+    }
+    writeln!(file, "]")?;
+    file.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // two-pass mean and sample variance of the dense values
+    fn dense_moments(values: &[f64]) -> (f64, f64) {
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let variance = values.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0);
+        (mean, variance)
+    }
+
+    #[test]
+    fn moments_include_the_implicit_zeros() {
+        // 5 cells x 3 features, the last feature is never expressed
+        let cells: Vec<[f32; 3]> = vec![
+            [1.0, 0.0, 0.0],
+            [0.0, 1e6, 0.0],
+            [3.0, 1e6 + 2.0, 0.0],
+            [0.0, 0.0, 0.0],
+            [8.0, 1e6 + 1.0, 0.0],
+        ];
+
+        let mut stats = FeatureStats::new(3);
+        for cell in cells.iter() {
+            let positions: Vec<usize> = (0..3).filter(|x| cell[*x] != 0.0).collect();
+            let values: Vec<f32> = positions.iter().map(|x| cell[*x]).collect();
+            stats.add_cell(&positions, &values);
+        }
+
+        assert_eq!(stats.num_cells, 5);
+        assert_eq!(stats.detected, vec![3, 3, 0]);
+        for feature_id in 0..3 {
+            let values: Vec<f64> = cells.iter().map(|x| x[feature_id] as f64).collect();
+            let (mean, variance) = dense_moments(&values);
+            assert!((stats.mean(feature_id) - mean).abs() < 1e-9 * mean.abs().max(1.0));
+            assert!((stats.variance(feature_id) - variance).abs() < 1e-9 * variance.max(1.0),
+                    "feature {}: expected {}, found {}", feature_id, variance, stats.variance(feature_id));
+        }
+    }
+
+    #[test]
+    fn moments_of_few_cells() {
+        let mut stats = FeatureStats::new(1);
+        assert_eq!((stats.mean(0), stats.variance(0)), (0.0, 0.0));
+
+        stats.add_cell(&[0], &[4.0]);
+        assert_eq!((stats.mean(0), stats.variance(0)), (4.0, 0.0));
+
+        stats.add_cell(&[], &[]);
+        assert_eq!((stats.mean(0), stats.variance(0)), (2.0, 8.0));
+    }
+}
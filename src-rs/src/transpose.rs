@@ -0,0 +1,294 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::mtx;
+use crate::utils::{for_each_cell, get_positions, positions_to_bit_vec, CellWriter, FileType};
+
+// size of one spilled (feature, cell, value) entry
+const ENTRY_BYTES: usize = 12;
+
+// spill files open at once, more chunks take more passes over the input
+const MAX_OPEN_SPILLS: usize = 64;
+
+// splits the features into contiguous chunks of at most max_entries entries
+pub fn plan_chunks(feature_counts: &[usize], max_entries: usize) -> Vec<(usize, usize)> {
+    let mut chunks: Vec<(usize, usize)> = Vec::new();
+    let mut start = 0;
+    let mut num_entries = 0;
+
+    for (feature_id, count) in feature_counts.iter().enumerate() {
+        if num_entries + count > max_entries && feature_id > start {
+            chunks.push((start, feature_id));
+            start = feature_id;
+            num_entries = 0;
+        }
+        num_entries += count;
+    }
+    chunks.push((start, feature_counts.len()));
+
+    chunks
+}
+
+// the input to transpose, cell_by_gene tells the orientation of an mtx
+// input. Its (cell, feature, value) entries are streamed, mtx entries come
+// in file order so the rows are sorted when written
+pub struct Source<'a> {
+    pub path: &'a str,
+    pub file_type: FileType,
+    pub num_cells: usize,
+    pub num_features: usize,
+    pub cell_by_gene: bool,
+}
+
+impl<'a> Source<'a> {
+    fn for_each_entry<F>(&self, mut callback: F) -> Result<(), io::Error>
+    where F: FnMut(usize, usize, f32) -> Result<(), io::Error> {
+        match self.file_type {
+            FileType::MTX => mtx::for_each_entry(self.path, self.num_cells, self.num_features,
+                                                 self.cell_by_gene, callback),
+            _ => for_each_cell(self.path, self.file_type.clone(), self.num_cells, self.num_features,
+                               |cell_id, bit_vec, exp| {
+                for (position, value) in get_positions(&bit_vec).into_iter().zip(exp) {
+                    callback(cell_id, position, value)?;
+                }
+                Ok(())
+            }),
+        }
+    }
+}
+
+// removes the spill file when dropped, including on the error paths
+struct Spill {
+    path: String,
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// rows are the (cell, value) entries of the features from start on
+fn write_rows(
+    writer: &mut CellWriter,
+    rows: Vec<Vec<(usize, f32)>>,
+    start: usize,
+    num_cells: usize,
+) -> Result<(), io::Error> {
+    for (offset, mut entries) in rows.into_iter().enumerate() {
+        entries.sort_by_key(|x| x.0);
+        if let Some(pair) = entries.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("found cell {} twice for feature {}", pair[0].0 + 1, start + offset + 1),
+            ));
+        }
+
+        let cells: Vec<usize> = entries.iter().map(|x| x.0).collect();
+        let values: Vec<f32> = entries.into_iter().map(|x| x.1).collect();
+        writer.write_cell(positions_to_bit_vec(&cells, num_cells), values)?;
+    }
+
+    Ok(())
+}
+
+fn read_spill(spill: &Spill, start: usize, counts: &[usize]) -> Result<Vec<Vec<(usize, f32)>>, io::Error> {
+    let mut rows: Vec<Vec<(usize, f32)>> = counts.iter()
+        .map(|count| Vec::with_capacity(*count))
+        .collect();
+
+    let mut file = BufReader::new(File::open(&spill.path)?);
+    let mut entry = [0_u8; ENTRY_BYTES];
+    loop {
+        match file.read_exact(&mut entry) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+
+        let position = LittleEndian::read_u32(&entry[0..4]) as usize;
+        let cell_id = LittleEndian::read_u32(&entry[4..8]) as usize;
+        rows[position - start].push((cell_id, LittleEndian::read_f32(&entry[8..12])));
+    }
+
+    Ok(rows)
+}
+
+pub fn transpose(
+    source: &Source,
+    output: String,
+    output_type: FileType,
+    max_entries: usize,
+) -> Result<(), io::Error> {
+    let (num_cells, num_features) = (source.num_cells, source.num_features);
+    info!("Transposing {} cells x {} features", num_cells, num_features);
+
+    let mut feature_counts: Vec<usize> = vec![0; num_features];
+    source.for_each_entry(|_, position, _| {
+        feature_counts[position] += 1;
+        Ok(())
+    })?;
+
+    let num_entries: usize = feature_counts.iter().sum();
+    let chunks = plan_chunks(&feature_counts, max_entries);
+    info!("Found {} entries, transposing in {} chunk(s)", num_entries, chunks.len());
+
+    // rows of the output are the features of the input
+    let mut writer = CellWriter::new(output.clone(), output_type, num_cells,
+                                     Some((num_features, num_entries)))?;
+
+    if chunks.len() == 1 {
+        let mut rows: Vec<Vec<(usize, f32)>> = feature_counts.iter()
+            .map(|count| Vec::with_capacity(*count))
+            .collect();
+
+        source.for_each_entry(|cell_id, position, value| {
+            rows[position].push((cell_id, value));
+            Ok(())
+        })?;
+
+        write_rows(&mut writer, rows, 0, num_cells)?;
+        return writer.finish();
+    }
+
+    // external memory: spill every entry into the file of its feature chunk,
+    // then build and write the rows one chunk at a time. A pass over the
+    // input spills at most MAX_OPEN_SPILLS chunks.
+    let mut chunk_of: Vec<usize> = vec![0; num_features];
+    for (chunk_id, (start, end)) in chunks.iter().enumerate() {
+        for chunk in chunk_of[*start..*end].iter_mut() {
+            *chunk = chunk_id;
+        }
+    }
+
+    for first in (0..chunks.len()).step_by(MAX_OPEN_SPILLS) {
+        let last = (first + MAX_OPEN_SPILLS).min(chunks.len());
+        let spills: Vec<Spill> = (first..last)
+            .map(|chunk_id| Spill { path: format!("{}.tmp{}", output, chunk_id) })
+            .collect();
+
+        {
+            let mut files: Vec<BufWriter<File>> = Vec::with_capacity(spills.len());
+            for spill in spills.iter() {
+                files.push(BufWriter::new(File::create(&spill.path)?));
+            }
+
+            let mut entry = [0_u8; ENTRY_BYTES];
+            source.for_each_entry(|cell_id, position, value| {
+                let chunk_id = chunk_of[position];
+                if chunk_id < first || chunk_id >= last {
+                    return Ok(());
+                }
+
+                LittleEndian::write_u32(&mut entry[0..4], position as u32);
+                LittleEndian::write_u32(&mut entry[4..8], cell_id as u32);
+                LittleEndian::write_f32(&mut entry[8..12], value);
+                files[chunk_id - first].write_all(&entry)
+            })?;
+
+            for file in files.iter_mut() {
+                file.flush()?;
+            }
+        }
+
+        for (chunk_id, spill) in (first..last).zip(spills) {
+            let (start, end) = chunks[chunk_id];
+            let rows = read_spill(&spill, start, &feature_counts[start..end])?;
+            drop(spill);
+            write_rows(&mut writer, rows, start, num_cells)?;
+        }
+    }
+
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::utils::{read_file, temp_path, write_test_cells};
+
+    // dense rows to their (positions, values) entries
+    fn to_entries(rows: &[Vec<f32>]) -> Vec<(Vec<usize>, Vec<f32>)> {
+        rows.iter()
+            .map(|row| {
+                let positions: Vec<usize> = (0..row.len()).filter(|x| row[*x] != 0.0).collect();
+                let values: Vec<f32> = positions.iter().map(|x| row[*x]).collect();
+                (positions, values)
+            })
+            .collect()
+    }
+
+    fn transpose_file(input: &str, num_cells: usize, num_features: usize,
+                      output: &str, max_entries: usize) {
+        let source = Source {
+            path: input,
+            file_type: FileType::EDS,
+            num_cells,
+            num_features,
+            cell_by_gene: true,
+        };
+        transpose(&source, output.to_string(), FileType::EDS, max_entries).unwrap();
+    }
+
+    #[test]
+    fn plan_chunks_at_max_entries() {
+        let chunks = plan_chunks(&[2, 3, 1, 4, 10, 1], 5);
+        assert_eq!(chunks, vec![(0, 2), (2, 4), (4, 5), (5, 6)]);
+
+        // a feature above max_entries takes a chunk of its own
+        let chunks = plan_chunks(&[10], 5);
+        assert_eq!(chunks, vec![(0, 1)]);
+        let chunks = plan_chunks(&[3, 10, 2], 5);
+        assert_eq!(chunks, vec![(0, 1), (1, 2), (2, 3)]);
+
+        assert_eq!(plan_chunks(&[1, 1, 1], 100), vec![(0, 3)]);
+        assert_eq!(plan_chunks(&[], 5), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn transpose_round_trips_through_spills() {
+        // one chunk per feature, more than MAX_OPEN_SPILLS of them
+        let (num_cells, num_features) = (3, MAX_OPEN_SPILLS + 6);
+        let rows: Vec<Vec<f32>> = (0..num_cells)
+            .map(|cell_id| (0..num_features)
+                 .map(|feature_id| match (cell_id + feature_id) % 3 {
+                     0 => 0.0,
+                     _ => (cell_id * num_features + feature_id) as f32,
+                 })
+                 .collect())
+            .collect();
+
+        let input = write_test_cells("transpose-input", FileType::EDS, num_features, &to_entries(&rows));
+
+        let transposed = temp_path("transpose-transposed.eds.gz");
+        transpose_file(&input, num_cells, num_features, &transposed, 1);
+        let (bit_vecs, alphas) = read_file(&transposed, FileType::EDS, num_features, num_cells).unwrap();
+
+        let columns: Vec<Vec<f32>> = (0..num_features)
+            .map(|feature_id| rows.iter().map(|row| row[feature_id]).collect())
+            .collect();
+        let expected = to_entries(&columns);
+        for (feature_id, (positions, values)) in expected.into_iter().enumerate() {
+            assert_eq!(get_positions(&bit_vecs[feature_id]), positions);
+            assert_eq!(alphas[feature_id], values);
+        }
+
+        // back again with a few features per chunk
+        let restored = temp_path("transpose-restored.eds.gz");
+        transpose_file(&transposed, num_features, num_cells, &restored, 4);
+        let (bit_vecs, alphas) = read_file(&restored, FileType::EDS, num_cells, num_features).unwrap();
+        for (cell_id, (positions, values)) in to_entries(&rows).into_iter().enumerate() {
+            assert_eq!(get_positions(&bit_vecs[cell_id]), positions);
+            assert_eq!(alphas[cell_id], values);
+        }
+
+        for path in [input, transposed, restored].iter() {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
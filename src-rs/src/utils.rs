@@ -3,11 +3,16 @@ use clap::ArgMatches;
 use std::collections::HashMap;
 
 use std;
+use std::fs::File;
 use std::io;
-use rand::thread_rng;
+use std::io::{BufRead, BufReader, BufWriter, Write};
 use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 
-use crate::{h5, mtx, csv, eds};
+use crate::{mtx, csv, eds};
+#[cfg(feature = "h5")]
+use crate::h5;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum FileType {
@@ -18,6 +23,12 @@ pub enum FileType {
     Dummy(String),
 }
 
+// bit vector and expression values of one cell
+pub type Cell = (Vec<u8>, Vec<f32>);
+
+// per cell bit vectors and expression values of a whole matrix
+pub type Cells = (Vec<Vec<u8>>, Vec<Vec<f32>>);
+
 pub fn write_file( file_path: String,
                    file_type: FileType,
                    bit_vecs: Vec<Vec<u8>>,
@@ -30,7 +41,10 @@ pub fn write_file( file_path: String,
     match file_type {
         FileType::MTX => mtx::writer(file_path, alphas, bit_vecs, num_cells, num_features)?,
         FileType::CSV => csv::writer(file_path, alphas, bit_vecs, num_cells, num_features)?,
+        #[cfg(feature = "h5")]
         FileType::H5 => h5::writer(file_path, alphas, bit_vecs, num_cells, num_features)?,
+        #[cfg(not(feature = "h5"))]
+        FileType::H5 => return Err(h5_disabled()),
         FileType::EDS => eds::writer(file_path, alphas, bit_vecs, num_cells, num_features)?,
         _ => unreachable!(),
     };
@@ -38,11 +52,118 @@ pub fn write_file( file_path: String,
     Ok(true)
 }
 
+// error for h5 output when the crate is built without the h5 feature
+#[cfg(not(feature = "h5"))]
+pub fn h5_disabled() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput,
+                   "h5 support was not compiled in, rebuild with the h5 feature")
+}
+
+// writes cells one at a time, formats which need the full matrix
+// (or its size) up front are buffered until finish. The variants keep the
+// FileType names
+#[allow(clippy::upper_case_acronyms)]
+pub enum CellWriter {
+    EDS(eds::Writer),
+    MTX(mtx::Writer),
+    CSV(csv::Writer),
+    #[cfg(feature = "h5")]
+    H5(h5::Writer),
+    Buffered {
+        file_path: String,
+        file_type: FileType,
+        num_features: usize,
+        bit_vecs: Vec<Vec<u8>>,
+        alphas: Vec<Vec<f32>>,
+    },
+}
+
+impl CellWriter {
+    pub fn new(file_path: String,
+               file_type: FileType,
+               num_features: usize,
+               size: Option<(usize, usize)>,
+    ) -> Result<CellWriter, io::Error> {
+        let writer = match (file_type, size) {
+            (FileType::EDS, _) => CellWriter::EDS(eds::Writer::new(&file_path)?),
+            (FileType::CSV, _) => CellWriter::CSV(csv::Writer::new(&file_path, num_features)?),
+            (FileType::MTX, Some((num_cells, num_entries))) => CellWriter::MTX(
+                mtx::Writer::new(&file_path, num_cells, num_features, num_entries)?
+            ),
+            #[cfg(feature = "h5")]
+            (FileType::H5, Some((num_cells, num_entries))) => CellWriter::H5(
+                h5::Writer::new(&file_path, num_cells, num_features, num_entries)?
+            ),
+            #[cfg(not(feature = "h5"))]
+            (FileType::H5, _) => return Err(h5_disabled()),
+            (file_type, _) => CellWriter::Buffered {
+                file_path: file_path.clone(),
+                file_type,
+                num_features,
+                bit_vecs: Vec::new(),
+                alphas: Vec::new(),
+            },
+        };
+
+        match writer {
+            CellWriter::Buffered { .. } => (),
+            _ => info!("Writing Output into file path: {}", file_path),
+        };
+
+        Ok(writer)
+    }
+
+    pub fn write_cell(&mut self, bit_vec: Vec<u8>, exp: Vec<f32>) -> Result<(), io::Error> {
+        match self {
+            CellWriter::EDS(writer) => writer.write_cell(&bit_vec, &exp)?,
+            CellWriter::MTX(writer) => writer.write_cell(&bit_vec, &exp)?,
+            CellWriter::CSV(writer) => writer.write_cell(&bit_vec, &exp)?,
+            #[cfg(feature = "h5")]
+            CellWriter::H5(writer) => writer.write_cell(&bit_vec, &exp)?,
+            CellWriter::Buffered { bit_vecs, alphas, .. } => {
+                bit_vecs.push(bit_vec);
+                alphas.push(exp);
+            },
+        };
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), io::Error> {
+        match self {
+            CellWriter::EDS(writer) => {
+                writer.finish()?;
+            },
+            CellWriter::MTX(writer) => writer.finish()?,
+            CellWriter::CSV(writer) => writer.finish()?,
+            #[cfg(feature = "h5")]
+            CellWriter::H5(writer) => writer.finish()?,
+            CellWriter::Buffered { file_path, file_type, num_features, bit_vecs, alphas } => {
+                let num_cells = bit_vecs.len();
+                write_file(file_path, file_type, bit_vecs, alphas, num_cells, num_features)?;
+            },
+        };
+
+        Ok(())
+    }
+}
+
 pub fn read_file(file_path: &str,
                  file_type: FileType,
                  num_cells: usize,
                  num_features: usize,
-) -> Result<(Vec<Vec<u8>>, Vec<Vec<f32>>), io::Error> {
+) -> Result<Cells, io::Error> {
+    read_file_oriented(file_path, file_type, num_cells, num_features, true)
+}
+
+// cell_by_gene tells which of the first two mtx columns is the cell, the
+// other formats always have the cells as rows
+pub fn read_file_oriented(file_path: &str,
+                          file_type: FileType,
+                          num_cells: usize,
+                          num_features: usize,
+                          cell_by_gene: bool,
+) -> Result<Cells, io::Error> {
     let mut alphas: Vec<Vec<f32>> = Vec::new();
     let mut bit_vecs: Vec<Vec<u8>> = Vec::new();
 
@@ -58,9 +179,27 @@ pub fn read_file(file_path: &str,
             file_path,
             num_cells,
             num_features,
+            cell_by_gene,
             &mut alphas,
             &mut bit_vecs,
         )?,
+        FileType::CSV => csv::reader(
+            file_path,
+            num_cells,
+            num_features,
+            &mut alphas,
+            &mut bit_vecs,
+        )?,
+        #[cfg(feature = "h5")]
+        FileType::H5 => h5::reader(
+            file_path,
+            num_cells,
+            num_features,
+            &mut alphas,
+            &mut bit_vecs,
+        )?,
+        #[cfg(not(feature = "h5"))]
+        FileType::H5 => return Err(h5_disabled()),
         _ => unreachable!(),
     };
 
@@ -68,24 +207,154 @@ pub fn read_file(file_path: &str,
     Ok((bit_vecs, alphas))
 }
 
-pub fn randomize(bit_vecs: Vec<Vec<u8>>,
-                 alphas: Vec<Vec<f32>>,
-) -> Result<(Vec<Vec<u8>>, Vec<Vec<f32>>), io::Error> {
+// pulls cells one at a time, formats without a streaming reader are
+// read into memory first
+#[allow(clippy::upper_case_acronyms)]
+pub enum CellReader {
+    EDS(eds::Reader),
+    CSV(csv::Reader),
+    Memory {
+        bit_vecs: std::vec::IntoIter<Vec<u8>>,
+        alphas: std::vec::IntoIter<Vec<f32>>,
+    },
+}
+
+impl CellReader {
+    pub fn new(file_path: &str,
+               file_type: FileType,
+               num_cells: usize,
+               num_features: usize,
+    ) -> Result<CellReader, io::Error> {
+        let reader = match file_type {
+            FileType::EDS => CellReader::EDS(eds::Reader::new(file_path, num_cells, num_features)?),
+            FileType::CSV => CellReader::CSV(csv::Reader::new(file_path, num_cells, num_features)?),
+            _ => {
+                let (bit_vecs, alphas) = read_file(file_path, file_type,
+                                                   num_cells, num_features)?;
+                CellReader::Memory {
+                    bit_vecs: bit_vecs.into_iter(),
+                    alphas: alphas.into_iter(),
+                }
+            },
+        };
+
+        Ok(reader)
+    }
+
+    pub fn next_cell(&mut self) -> Result<Option<Cell>, io::Error> {
+        match self {
+            CellReader::EDS(reader) => reader.next_cell(),
+            CellReader::CSV(reader) => reader.next_cell(),
+            CellReader::Memory { bit_vecs, alphas } => match (bit_vecs.next(), alphas.next()) {
+                (Some(bit_vec), Some(exp)) => Ok(Some((bit_vec, exp))),
+                _ => Ok(None),
+            },
+        }
+    }
+}
+
+pub fn for_each_cell<F>(file_path: &str,
+                        file_type: FileType,
+                        num_cells: usize,
+                        num_features: usize,
+                        mut callback: F,
+) -> Result<(), io::Error>
+where F: FnMut(usize, Vec<u8>, Vec<f32>) -> Result<(), io::Error> {
+    let mut file = CellReader::new(file_path, file_type, num_cells, num_features)?;
+
+    let mut cell_id = 0;
+    while let Some((bit_vec, exp)) = file.next_cell()? {
+        callback(cell_id, bit_vec, exp)?;
+        cell_id += 1;
+    }
+
+    Ok(())
+}
+
+// total counts of a cell, an empty cell sums to +0.0
+pub fn cell_total(exp: &[f32]) -> f64 {
+    exp.iter().fold(0.0, |total, x| total + *x as f64)
+}
+
+// a fixed generator, StdRng may change its algorithm between releases and
+// the same seed has to give the same output
+pub fn get_rng(seed: Option<u64>) -> ChaCha8Rng {
+    match seed {
+        Some(seed) => ChaCha8Rng::seed_from_u64(seed),
+        None => ChaCha8Rng::from_entropy(),
+    }
+}
+
+pub fn randomize<R: Rng>(num_cells: usize, rng: &mut R) -> Vec<usize> {
     info!("Randomizing order");
+
+    let mut order: Vec<usize> = (0..num_cells).collect();
+    order.shuffle(rng);
+    order
+}
+
+// writes cell order[i] of the input as cell i of the output, moving the
+// vectors out instead of copying them
+pub fn write_permuted(file_path: String,
+                      file_type: FileType,
+                      mut bit_vecs: Vec<Vec<u8>>,
+                      mut alphas: Vec<Vec<f32>>,
+                      order: &[usize],
+                      num_features: usize,
+) -> Result<(), io::Error> {
     assert!( bit_vecs.len() == alphas.len() );
+    assert!( order.len() <= bit_vecs.len() );
+
+    let num_entries: usize = order.iter().map(|index| alphas[*index].len()).sum();
+    let mut writer = CellWriter::new(file_path, file_type, num_features,
+                                     Some((order.len(), num_entries)))?;
+
+    for index in order {
+        let bit_vec = std::mem::take(&mut bit_vecs[*index]);
+        let exp = std::mem::take(&mut alphas[*index]);
+        writer.write_cell(bit_vec, exp)?;
+    }
+
+    writer.finish()
+}
 
-    let num_elem = bit_vecs.len() as u32;
-    let mut order: Vec<u32> = (0..num_elem).collect();
-    order.shuffle(&mut thread_rng());
+pub fn write_permutation(file_path: &str, order: &[usize]) -> Result<(), io::Error> {
+    let names: Vec<String> = order.iter().map(|index| index.to_string()).collect();
+    write_names(file_path, &names)
+}
+
+// reads back a permutation, line i holds the input position of output cell i
+pub fn read_permutation(file_path: &str, num_cells: usize) -> Result<Vec<usize>, io::Error> {
+    let mut order: Vec<usize> = Vec::with_capacity(num_cells);
+    let mut seen: Vec<bool> = vec![false; num_cells];
+
+    for name in read_names(file_path)? {
+        let index: usize = name.parse().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData,
+                           format!("can't parse {} as a cell index", name))
+        })?;
+
+        if index >= num_cells || seen[index] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a permutation of {} cells", file_path, num_cells),
+            ));
+        }
+
+        seen[index] = true;
+        order.push(index);
+    }
+
+    Ok(order)
+}
 
-    let mut shuf_bvecs = vec![Vec::new(); bit_vecs.len()];
-    let mut shuf_alphas = vec![Vec::new(); bit_vecs.len()];
-    for (nindex, oindex) in order.into_iter().enumerate() {
-        shuf_bvecs[nindex] = bit_vecs[oindex as usize].clone();
-        shuf_alphas[nindex] = alphas[oindex as usize].clone();
+pub fn invert_permutation(order: &[usize]) -> Vec<usize> {
+    let mut inverse: Vec<usize> = vec![0; order.len()];
+    for (nindex, oindex) in order.iter().enumerate() {
+        inverse[*oindex] = nindex;
     }
 
-    Ok((shuf_bvecs, shuf_alphas))
+    inverse
 }
 
 pub fn find_output_format(sub_m: &ArgMatches) -> FileType {
@@ -128,6 +397,16 @@ pub fn find_output_format(sub_m: &ArgMatches) -> FileType {
     return out_file_type.expect("can't find output format type");
 }
 
+pub fn find_output_format_or(sub_m: &ArgMatches, default: FileType) -> FileType {
+    let any_present = ["mtx", "csv", "h5", "eds"].iter()
+        .any(|name| sub_m.is_present(name));
+
+    match any_present {
+        true => find_output_format(sub_m),
+        false => default,
+    }
+}
+
 pub fn get_output_path(input_path: &str, otype: FileType) -> (FileType, String) {
     let mut itype: FileType = FileType::EDS;
     let mut opath = input_path.to_string();
@@ -180,17 +459,20 @@ pub fn get_output_path(input_path: &str, otype: FileType) -> (FileType, String)
     info!(" Found {:?} as input file type ", itype);
     info!(" Found {:?} as output file type ", otype);
 
-    match otype {
-        FileType::MTX => opath.replace_range(offset.., ".mtx.gz"),
-        FileType::CSV => opath.replace_range(offset.., ".csv.gz"),
-        FileType::H5 => opath.replace_range(offset.., ".h5"),
-        FileType::EDS => opath.replace_range(offset.., ".eds.gz"),
-        FileType::Dummy(name) => opath.replace_range(offset.., &name),
-    }
-
+    opath.replace_range(offset.., &get_extension(&otype));
     (itype, opath)
 }
 
+pub fn get_extension(file_type: &FileType) -> String {
+    match file_type {
+        FileType::MTX => ".mtx.gz".to_string(),
+        FileType::CSV => ".csv.gz".to_string(),
+        FileType::H5 => ".h5".to_string(),
+        FileType::EDS => ".eds.gz".to_string(),
+        FileType::Dummy(name) => name.clone(),
+    }
+}
+
 pub fn triplets_to_eds(triplets: &Vec<HashMap<u32, f32>>,
                        expr: &mut Vec<Vec<f32>>,
                        bit_vecs: &mut Vec<Vec<u8>>,
@@ -246,3 +528,157 @@ pub fn triplets_to_eds(triplets: &Vec<HashMap<u32, f32>>,
         bit_vecs.push(bit_vec);
     }
 }
+
+pub fn get_positions(bit_vec: &[u8]) -> Vec<usize> {
+    let mut positions: Vec<usize> = Vec::new();
+    for (byte_id, flag) in bit_vec.iter().enumerate() {
+        if *flag == 0 {
+            continue;
+        }
+
+        for offset in 0..8 {
+            if flag & (128u8 >> offset) != 0 {
+                positions.push((8 * byte_id) + offset);
+            }
+        }
+    }
+
+    positions
+}
+
+pub fn positions_to_bit_vec(positions: &[usize], num_features: usize) -> Vec<u8> {
+    let num_bit_vecs: usize = round::ceil(num_features as f64 / 8.0, 0) as usize;
+    let mut bit_vec: Vec<u8> = vec![0; num_bit_vecs];
+
+    for position in positions {
+        assert!(*position < num_features,
+                "{} position > {}", position, num_features);
+        bit_vec[position / 8] |= 128u8 >> (position % 8);
+    }
+
+    bit_vec
+}
+
+pub fn read_names(file_path: &str) -> Result<Vec<String>, io::Error> {
+    let file = BufReader::new(File::open(file_path)?);
+
+    let mut names: Vec<String> = Vec::new();
+    for line in file.lines() {
+        let record = line?;
+        let name = record.trim();
+        if name.is_empty() {
+            continue;
+        }
+
+        names.push(name.to_string());
+    }
+
+    info!("Found {} names in {}", names.len(), file_path);
+    Ok(names)
+}
+
+pub fn write_names(file_path: &str, names: &[String]) -> Result<(), io::Error> {
+    let mut file = BufWriter::new(File::create(file_path)?);
+    for name in names {
+        writeln!(file, "{}", name)?;
+    }
+
+    info!("Wrote {} names in {}", names.len(), file_path);
+    Ok(())
+}
+
+// json has no NaN or infinity, they are written as null
+pub fn json_number<T: Into<f64> + ToString + Copy>(value: T) -> String {
+    match value.into().is_finite() {
+        true => value.to_string(),
+        false => "null".to_string(),
+    }
+}
+
+pub fn json_string(value: &str) -> String {
+    let mut json = "\"".to_string();
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+
+    json
+}
+
+// temporary file of a test, the name keeps the tests running in parallel apart
+#[cfg(test)]
+pub fn temp_path(name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("eds-{}-{}", name, std::process::id()))
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+// writes the (positions, values) cells of a test into a temporary file of
+// the given format and returns its path, the test removes it
+#[cfg(test)]
+pub fn write_test_cells(
+    name: &str,
+    file_type: FileType,
+    num_features: usize,
+    cells: &[(Vec<usize>, Vec<f32>)],
+) -> String {
+    let path = temp_path(name) + &get_extension(&file_type);
+    let mut writer = CellWriter::new(path.clone(), file_type, num_features, None).unwrap();
+    for (positions, values) in cells.iter() {
+        writer.write_cell(positions_to_bit_vec(positions, num_features), values.clone()).unwrap();
+    }
+    writer.finish().unwrap();
+
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn unshuffle_restores_the_order() {
+        let num_cells = 20;
+        let bit_vecs: Vec<Vec<u8>> = (0..num_cells)
+            .map(|cell_id| positions_to_bit_vec(&[cell_id % 9], 9))
+            .collect();
+        let alphas: Vec<Vec<f32>> = (0..num_cells).map(|cell_id| vec![cell_id as f32 + 1.0]).collect();
+
+        // the same seed gives the same order
+        let order = randomize(num_cells, &mut get_rng(Some(11)));
+        assert_eq!(order, randomize(num_cells, &mut get_rng(Some(11))));
+        assert_ne!(order, (0..num_cells).collect::<Vec<usize>>());
+
+        let shuffled = temp_path("utils-shuffled.eds.gz");
+        let record = temp_path("utils-shuffled.perm.txt");
+        write_permuted(shuffled.clone(), FileType::EDS, bit_vecs.clone(), alphas.clone(), &order, 9).unwrap();
+        write_permutation(&record, &order).unwrap();
+
+        let (shuffled_bvecs, shuffled_alphas) = read_file(&shuffled, FileType::EDS, num_cells, 9).unwrap();
+        for (index, cell_id) in order.iter().enumerate() {
+            assert_eq!(shuffled_alphas[index], alphas[*cell_id]);
+        }
+
+        let inverse = invert_permutation(&read_permutation(&record, num_cells).unwrap());
+        let restored = temp_path("utils-restored.eds.gz");
+        write_permuted(restored.clone(), FileType::EDS, shuffled_bvecs, shuffled_alphas, &inverse, 9).unwrap();
+
+        let (restored_bvecs, restored_alphas) = read_file(&restored, FileType::EDS, num_cells, 9).unwrap();
+        assert_eq!(restored_bvecs, bit_vecs);
+        assert_eq!(restored_alphas, alphas);
+
+        for path in [shuffled, record, restored].iter() {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::io;
+
+use crate::eds;
+
+pub struct Report {
+    pub num_cells_read: usize,
+    pub num_errors: usize,
+    pub messages: Vec<String>,
+    max_messages: usize,
+}
+
+impl Report {
+    fn error(&mut self, message: String) {
+        self.num_errors += 1;
+        if self.messages.len() < self.max_messages {
+            self.messages.push(message);
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.num_errors == 0
+    }
+}
+
+fn describe_read_error(error: io::Error, what: &str, cell_id: usize, offset: u64) -> String {
+    match error.kind() {
+        io::ErrorKind::UnexpectedEof => format!(
+            "cell {} (offset {}): stream ends inside the {}",
+            cell_id, offset, what
+        ),
+        _ => format!(
+            "cell {} (offset {}): can't read the {}: {}",
+            cell_id, offset, what, error
+        ),
+    }
+}
+
+pub fn validate(
+    input: &str,
+    num_cells: usize,
+    num_features: usize,
+    max_messages: usize,
+) -> Result<Report, io::Error> {
+    info!("Validating {} with {} cells and {} features", input, num_cells, num_features);
+
+    let mut report = Report {
+        num_cells_read: 0,
+        num_errors: 0,
+        messages: Vec::new(),
+        max_messages,
+    };
+
+    // bits past num_features in the last byte of a bit vector
+    let padding_mask: u8 = match num_features % 8 {
+        0 => 0,
+        used => 0xff_u8 >> used,
+    };
+
+    let mut file = eds::Reader::new(input, num_cells, num_features)?;
+    for cell_id in 0..num_cells {
+        let offset = file.offset();
+        let bit_vec = match file.read_bit_vec() {
+            Ok(bit_vec) => bit_vec,
+            Err(e) => {
+                report.error(describe_read_error(e, "bit vector", cell_id, offset));
+                report.error(format!("expected {} cells, found {}", num_cells, cell_id));
+                return Ok(report);
+            }
+        };
+
+        if let Some(last) = bit_vec.last() {
+            if last & padding_mask != 0 {
+                report.error(format!(
+                    "cell {} (offset {}): bits set past the last of {} features",
+                    cell_id, offset, num_features
+                ));
+            }
+        }
+
+        let num_ones: usize = bit_vec.iter().map(|bits| bits.count_ones() as usize).sum();
+        let offset = file.offset();
+        let values = match file.read_values(num_ones) {
+            Ok(values) => values,
+            Err(e) => {
+                let what = format!("{} values given by the bit vector popcount", num_ones);
+                report.error(describe_read_error(e, &what, cell_id, offset));
+                report.error(format!("expected {} cells, found {}", num_cells, cell_id));
+                return Ok(report);
+            }
+        };
+
+        for (index, value) in values.iter().enumerate() {
+            let problem = match value {
+                x if x.is_nan() => "NaN",
+                x if x.is_infinite() => "infinite",
+                x if *x < 0.0 => "negative",
+                _ => continue,
+            };
+
+            report.error(format!(
+                "cell {} (offset {}): {} value {} at entry {}",
+                cell_id, offset + 4 * index as u64, problem, value, index
+            ));
+        }
+
+        report.num_cells_read += 1;
+    }
+
+    let offset = file.offset();
+    match file.trailing_bytes() {
+        Ok(0) => (),
+        Ok(trailing_bytes) => report.error(format!(
+            "offset {}: found {} trailing bytes after the last of {} cells",
+            offset, trailing_bytes, num_cells
+        )),
+        Err(e) => report.error(format!(
+            "offset {}: can't read past the last cell: {}",
+            offset, e
+        )),
+    };
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::utils::temp_path;
+
+    // writes the cells as given, valid or not, and validates them as
+    // num_cells x 12 features
+    fn validate_cells(name: &str, cells: &[(Vec<u8>, Vec<f32>)], num_cells: usize) -> Report {
+        let path = temp_path(&format!("validate-{}.eds.gz", name));
+
+        let mut writer = eds::Writer::new(&path).unwrap();
+        for (bit_vec, exp) in cells.iter() {
+            writer.write_cell(bit_vec, exp).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let report = validate(&path, num_cells, 12, 10);
+        fs::remove_file(&path).unwrap();
+        report.unwrap()
+    }
+
+    fn assert_error(report: &Report, expected: &str) {
+        assert!(!report.is_valid());
+        assert!(report.messages.iter().any(|x| x.contains(expected)),
+                "no \"{}\" in {:?}", expected, report.messages);
+    }
+
+    #[test]
+    fn valid_cells() {
+        let cells = vec![(vec![0b1000_0001, 0b0001_0000], vec![1.0, 2.0, 3.0]), (vec![0, 0], vec![])];
+        let report = validate_cells("valid", &cells, 2);
+        assert!(report.is_valid(), "{:?}", report.messages);
+        assert_eq!(report.num_cells_read, 2);
+    }
+
+    #[test]
+    fn padding_bits() {
+        // 12 features leave the last 4 bits of the second byte unused
+        let cells = vec![(vec![0, 0b0000_0001], vec![1.0])];
+        let report = validate_cells("padding", &cells, 1);
+        assert_error(&report, "bits set past the last of 12 features");
+        assert_eq!(report.num_cells_read, 1);
+    }
+
+    #[test]
+    fn truncated_bit_vector() {
+        let cells = vec![(vec![0b1000_0000, 0], vec![1.0])];
+        let report = validate_cells("truncated-bits", &cells, 2);
+        assert_error(&report, "cell 1 (offset 6): stream ends inside the bit vector");
+        assert_error(&report, "expected 2 cells, found 1");
+    }
+
+    #[test]
+    fn truncated_values() {
+        let cells = vec![(vec![0b1100_0000, 0], vec![1.0])];
+        let report = validate_cells("truncated-values", &cells, 1);
+        assert_error(&report, "stream ends inside the 2 values given by the bit vector popcount");
+        assert_eq!(report.num_cells_read, 0);
+    }
+
+    #[test]
+    fn bad_values() {
+        let cells = vec![(vec![0b1111_0000, 0], vec![f32::NAN, f32::INFINITY, -1.0, 0.5])];
+        let report = validate_cells("values", &cells, 1);
+        assert_eq!(report.num_errors, 3);
+        assert_error(&report, "NaN value NaN at entry 0");
+        assert_error(&report, "infinite value inf at entry 1");
+        assert_error(&report, "cell 0 (offset 10): negative value -1 at entry 2");
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        let cells = vec![(vec![0b1000_0000, 0], vec![1.0]), (vec![0, 0], vec![])];
+        let report = validate_cells("trailing", &cells, 1);
+        assert_error(&report, "offset 6: found 2 trailing bytes after the last of 1 cells");
+        assert_eq!(report.num_cells_read, 1);
+    }
+}